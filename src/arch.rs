@@ -1,4 +1,9 @@
-use crate::memset::ValidAddress;
+// Register and field names follow the privileged spec
+#![allow(clippy::upper_case_acronyms)]
+// Only built for RV64, where every field fits a usize
+#![allow(clippy::enum_clike_unportable_variant)]
+
+use crate::memset::{TimerCompareValue, ValidAddress};
 use core::arch::asm;
 
 // Control and Status Register (CSR) Addresses
@@ -9,10 +14,20 @@ const MSTATUS: usize = 0x300;
 const MEDELEG: usize = 0x302;
 const MIDELEG: usize = 0x303;
 const MIE: usize = 0x304;
+const MTVEC: usize = 0x305;
 const MCOUNTEREN: usize = 0x306;
 const MENVCFG: usize = 0x30A;
+const MSCRATCH: usize = 0x340;
 const MEPC: usize = 0x341;
 const MCYCLE: usize = 0xB00;
+const MHPMEVENT3: usize = 0x323;
+const MHPMEVENT4: usize = 0x324;
+const MHPMEVENT5: usize = 0x325;
+// Unprivileged counters
+const TIME: usize = 0xC01;
+const HPMCOUNTER3: usize = 0xC03;
+const HPMCOUNTER4: usize = 0xC04;
+const HPMCOUNTER5: usize = 0xC05;
 // Supervisor Level
 const SSTATUS: usize = 0x100;
 const SIE: usize = 0x104;
@@ -28,36 +43,6 @@ const STIMECMP: usize = 0x14d;
 const PMPCFG0: usize = 0x3A0;
 const PMPADDR0: usize = 0x3B0;
 
-// Read some value from a CSR register
-macro_rules! read_csr {
-    ($csr:expr) => {{
-        let value: usize;
-        unsafe {
-            asm!(
-                "csrr {0}, {1}",
-                out(reg) value,
-                const $csr,
-                options(nostack, preserves_flags)
-            );
-        }
-        value
-    }};
-}
-
-// Write some value to a CSR register
-macro_rules! write_csr {
-    ($csr:expr, $val:expr) => {{
-        unsafe {
-            asm!(
-                "csrw {0}, {1}",
-                const $csr,
-                in(reg) $val as usize,
-                options(nostack, preserves_flags)
-            );
-        }
-    }};
-}
-
 //  __  __            _     _                  _                   _
 // |  \/  | __ _  ___| |__ (_)_ __   ___      | |    _____   _____| |
 // | |\/| |/ _` |/ __| '_ \| | '_ \ / _ \_____| |   / _ \ \ / / _ \ |
@@ -97,7 +82,7 @@ pub fn write_threadptr(val: usize) {
 // Machine Status Register (MSTATUS)
 // - Machine Previous Privilege (MPP[1:0]): 2-bit field indicating the previous privilege mode (U/S/M) before a trap
 
-trait MStatusField {
+pub trait MStatusField {
     fn to_usize(self) -> usize;
 }
 
@@ -200,10 +185,18 @@ pub fn write_mstatus<T: MStatusField>(val: T) {
     write_csr!(MSTATUS, val.to_usize());
 }
 
+// Set the privilege mode mret returns to, leaving the rest of mstatus as it is
+pub fn set_mpp(mode: PrivilegeMode) {
+    let mut x = read_mstatus();
+    x &= !MPP_MASK;
+    x |= mode.to_usize();
+    write_csr!(MSTATUS, x);
+}
+
 // Machine Exception Delegation
 // Delegates exceptions from machine mode to supervisor mode
 
-trait MedelegField {
+pub trait MedelegField {
     fn to_usize(self) -> usize;
 }
 #[repr(usize)]
@@ -238,11 +231,16 @@ pub fn write_medeleg<T: MedelegField>(val: T) {
     write_csr!(MEDELEG, val.to_usize());
 }
 
+// Delegate one exception, leaving the others as they are
+pub fn set_medeleg<T: MedelegField>(val: T) {
+    set_csr!(MEDELEG, val.to_usize());
+}
+
 // Machine Interrupt Delegation
 // Delegates interrupts from machine mode to supervisor mode
 //
 
-trait MidelegField {
+pub trait MidelegField {
     fn to_usize(self) -> usize;
 }
 
@@ -268,10 +266,15 @@ pub fn read_mideleg() -> usize {
 pub fn write_mideleg<T: MidelegField>(val: T) {
     write_csr!(MIDELEG, val.to_usize());
 }
+
+// Delegate one interrupt, leaving the others as they are
+pub fn set_mideleg<T: MidelegField>(val: T) {
+    set_csr!(MIDELEG, val.to_usize());
+}
 // Machine Interrupt Enable
 // Controls the enabling/disabling of various interrupts in machine mode
 
-trait MieField {
+pub trait MieField {
     fn to_usize(self) -> usize;
 }
 
@@ -301,10 +304,29 @@ pub fn read_mie() -> usize {
 pub fn write_mie<T: MieField>(val: T) {
     write_csr!(MIE, val.to_usize());
 }
+
+// Enable one interrupt, leaving the others as they are
+pub fn set_mie<T: MieField>(val: T) {
+    set_csr!(MIE, val.to_usize());
+}
+
+// Machine Trap-Vector Base Address
+// Sets base address of trap handler routine for machine mode
+
+pub fn write_mtvec(addr: ValidAddress) {
+    write_csr!(MTVEC, addr.get());
+}
+
+// Machine Scratch
+// Free for machine-mode trap handlers, typically a pointer to per-hart scratch space
+
+pub fn write_mscratch(val: usize) {
+    write_csr!(MSCRATCH, val);
+}
 // Machine-Mode Counter Enable
 // Controls the availability of performance counters (cycle, time, instruction) to lower privilege modes
 
-trait MCounterenField {
+pub trait MCounterenField {
     fn to_usize(self) -> usize;
 }
 
@@ -359,10 +381,15 @@ pub fn write_mcounteren<T: MCounterenField>(val: T) {
     write_csr!(MCOUNTEREN, val.to_usize());
 }
 
+// Let supervisor mode read one more counter
+pub fn set_mcounteren<T: MCounterenField>(val: T) {
+    set_csr!(MCOUNTEREN, val.to_usize());
+}
+
 // Machine Environment Configuration
 // Configures environment settings i.e. memory protection attributes, cacheability
 
-trait MenvcfgField {
+pub trait MenvcfgField {
     fn to_usize(self) -> usize;
 }
 
@@ -388,6 +415,7 @@ pub enum MenvcfgVal {
     PMA13 = 1 << 16, // Physical Memory Attributes 13
     PMA14 = 1 << 17, // Physical Memory Attributes 14
     PMA15 = 1 << 18, // Physical Memory Attributes 15
+    STCE = 1 << 63,  // Supervisor timer (stimecmp) enable
 }
 
 impl MenvcfgField for MenvcfgVal {
@@ -404,6 +432,11 @@ pub fn write_menvcfg<T: MenvcfgField>(val: T) {
     write_csr!(MENVCFG, val.to_usize());
 }
 
+// Turn on one setting, leaving the others as they are
+pub fn set_menvcfg<T: MenvcfgField>(val: T) {
+    set_csr!(MENVCFG, val.to_usize());
+}
+
 // Machine Exception Program Counter
 // Holds the address of an instruction that caused a machine-level exception
 // Address is saved when exception occurs and can be used to resume execution or handle the exception
@@ -423,6 +456,45 @@ pub fn read_mcycle() -> usize {
     read_csr!(MCYCLE)
 }
 
+// Machine Hardware Performance-Monitoring Event Selectors
+// mhpmeventN picks the event that hpmcounterN counts; event numbers are
+// platform specific, and zero counts nothing
+
+pub fn write_mhpmevent(counter: usize, event: usize) {
+    match counter {
+        3 => write_csr!(MHPMEVENT3, event),
+        4 => write_csr!(MHPMEVENT4, event),
+        5 => write_csr!(MHPMEVENT5, event),
+        _ => panic!("write_mhpmevent {}", counter),
+    }
+}
+
+// Return from machine mode to the mode in mstatus.MPP, at mepc
+pub fn mret() -> ! {
+    unsafe { asm!("mret", options(noreturn)) }
+}
+
+// Real-Time Counter
+// Counts ticks of the platform's constant-frequency clock; readable from
+// supervisor mode once mcounteren.TM is set
+
+pub fn read_time() -> usize {
+    read_csr!(TIME)
+}
+
+// Hardware Performance-Monitoring Counters
+// Count the events picked with write_mhpmevent(); readable from supervisor
+// mode once the counter's mcounteren bit is set
+
+pub fn read_hpmcounter(counter: usize) -> usize {
+    match counter {
+        3 => read_csr!(HPMCOUNTER3),
+        4 => read_csr!(HPMCOUNTER4),
+        5 => read_csr!(HPMCOUNTER5),
+        _ => panic!("read_hpmcounter {}", counter),
+    }
+}
+
 //  ____                              _                     _                   _
 // / ___| _   _ _ __   ___ _ ____   _(_)___  ___  _ __     | |    _____   _____| |
 // \___ \| | | | '_ \ / _ \ '__\ \ / / / __|/ _ \| '__|____| |   / _ \ \ / / _ \ |
@@ -431,7 +503,7 @@ pub fn read_mcycle() -> usize {
 //             |_|
 
 // Supervisor Status Register (SSTATUS)
-trait SStatusField {
+pub trait SStatusField {
     fn to_usize(self) -> usize;
 }
 
@@ -480,10 +552,44 @@ pub fn read_sstatus() -> usize {
 pub fn write_sstatus<T: SStatusField>(val: T) {
     write_csr!(SSTATUS, val.to_usize());
 }
+
+pub fn set_sstatus<T: SStatusField>(val: T) {
+    set_csr!(SSTATUS, val.to_usize());
+}
+
+pub fn clear_sstatus<T: SStatusField>(val: T) {
+    clear_csr!(SSTATUS, val.to_usize());
+}
+
+// Put back a value saved earlier with read_sstatus()
+pub fn restore_sstatus(val: usize) {
+    write_csr!(SSTATUS, val);
+}
+
+// Was the trap being handled taken from supervisor mode?
+pub fn from_supervisor(sstatus: usize) -> bool {
+    sstatus & PrivilegeModeSStatus::SPP.to_usize() != 0
+}
+
+// Enable supervisor interrupts on this hart
+pub fn intr_on() {
+    set_csr!(SSTATUS, InterruptEnableSStatus::SIE.to_usize());
+}
+
+// Disable supervisor interrupts on this hart
+pub fn intr_off() {
+    clear_csr!(SSTATUS, InterruptEnableSStatus::SIE.to_usize());
+}
+
+// Are supervisor interrupts enabled on this hart?
+pub fn intr_get() -> bool {
+    read_sstatus() & InterruptEnableSStatus::SIE.to_usize() != 0
+}
+
 // Supervisor Interrupt Enable
 // Controls the enabling/disabling of various interrupts in supervisor mode
 
-trait SieField {
+pub trait SieField {
     fn to_usize(self) -> usize;
 }
 
@@ -508,6 +614,11 @@ pub fn read_sie() -> usize {
 pub fn write_sie<T: SieField>(val: T) {
     write_csr!(SIE, val.to_usize());
 }
+
+// Enable one interrupt, leaving the others as they are
+pub fn set_sie<T: SieField>(val: T) {
+    set_csr!(SIE, val.to_usize());
+}
 // Supervisor Trap-Vector Base Address
 // Sets base address of trap handler routine for supervisor mode
 
@@ -518,6 +629,11 @@ pub fn read_stvec() -> usize {
 pub fn write_stvec(addr: ValidAddress) {
     write_csr!(STVEC, addr.get());
 }
+
+// Point stvec at uservec, by its trampoline virtual address, for traps from user space
+pub fn write_user_stvec(va: usize) {
+    write_csr!(STVEC, va);
+}
 // Supervisor Exception Program Counter
 // Holds the address of an instruction that caused a supervisor-level exception
 // Address is saved when exception occurs prior to trap handler routine. Can be used to resume execution or handle the exception
@@ -530,13 +646,24 @@ pub fn write_sepc(addr: ValidAddress) {
     write_csr!(SEPC, addr.get())
 }
 
+// Set the user program counter that sret returns to
+pub fn write_user_sepc(va: usize) {
+    write_csr!(SEPC, va)
+}
+
 // Supervisor Trap Cause
 // Holds cause of last trap (exception/interrupt) occurence in supervisor mode
 
-trait ScauseField {
+pub trait ScauseField {
     fn to_usize(self) -> usize;
 }
 
+// Set in scause when the trap was caused by an interrupt
+const INTERRUPT: usize = 1 << 63;
+
+// mcause for the only trap taken in machine mode, a CLINT software interrupt
+pub const MACHINE_SOFTWARE_INTERRUPT: usize = INTERRUPT | 3;
+
 #[repr(usize)]
 #[derive(Copy, Clone, Debug)]
 pub enum ScauseVal {
@@ -555,12 +682,12 @@ pub enum ScauseVal {
     LoadPageFault = 13,
     StorePageFault = 15,
     // Interrupt codes (bit 63 set to 1)
-    UserSoftwareInterrupt = 0x8000000000000000 | 0,
-    SupervisorSoftwareInterrupt = 0x8000000000000000 | 1,
-    UserTimerInterrupt = 0x8000000000000000 | 4,
-    SupervisorTimerInterrupt = 0x8000000000000000 | 5,
-    UserExternalInterrupt = 0x8000000000000000 | 8,
-    SupervisorExternalInterrupt = 0x8000000000000000 | 9,
+    UserSoftwareInterrupt = INTERRUPT,
+    SupervisorSoftwareInterrupt = INTERRUPT | 1,
+    UserTimerInterrupt = INTERRUPT | 4,
+    SupervisorTimerInterrupt = INTERRUPT | 5,
+    UserExternalInterrupt = INTERRUPT | 8,
+    SupervisorExternalInterrupt = INTERRUPT | 9,
 }
 
 impl ScauseField for ScauseVal {
@@ -593,7 +720,7 @@ pub fn write_stval(addr: ValidAddress) {
 // Each register bit corresponds to a specific interrupt type
 // If set, interrupt is pending and waiting to be serviced
 
-trait SipField {
+pub trait SipField {
     fn to_usize(self) -> usize;
 }
 
@@ -605,6 +732,12 @@ pub enum SipVal {
     SEIP = 0b01 << 9, // External (Hardware [I/O])
 }
 
+impl SipField for SipVal {
+    fn to_usize(self) -> usize {
+        self as usize
+    }
+}
+
 pub fn read_sip() -> usize {
     read_csr!(SIP)
 }
@@ -613,11 +746,16 @@ pub fn write_sip<T: SipField>(val: T) {
     write_csr!(SIP, val.to_usize());
 }
 
+// Acknowledge a pending interrupt; only SSIP is writable from supervisor mode
+pub fn clear_sip<T: SipField>(val: T) {
+    clear_csr!(SIP, val.to_usize());
+}
+
 // Supervisor Address Translation and Protection
 // Manages address translation/protection, page table configuration and ASIDs
 // Integral component in supervisor mode establishment of virtual memory space
 
-trait SatpField {
+pub trait SatpField {
     fn to_usize(self) -> usize;
 }
 
@@ -636,9 +774,14 @@ impl SatpField for SatpMode {
     }
 }
 
-// Create an SATP value given a page table base address and mode
-pub fn make_satp<T: SatpField>(pagetable: usize, mode: T) -> usize {
-    mode.to_usize() | (pagetable >> 12)
+// Address-space identifier field, bits 59..44
+// Implementations may support fewer ASID bits, down to none at all
+pub const SATP_ASID_SHIFT: usize = 44;
+pub const SATP_ASID_MASK: usize = 0xffff;
+
+// Create an SATP value given a page table base address, mode and address-space identifier
+pub fn make_satp<T: SatpField>(pagetable: usize, mode: T, asid: usize) -> usize {
+    mode.to_usize() | ((asid & SATP_ASID_MASK) << SATP_ASID_SHIFT) | (pagetable >> 12)
 }
 
 pub fn read_satp() -> usize {
@@ -670,18 +813,18 @@ pub fn write_stimecmp(val: TimerCompareValue) {
 // Physical Memory Protection Configuration Register 0
 // Configures regions 0-3 of PMP, controls permission settings (r/w/x) + addressing mode
 
-trait PmpcfgField {
+pub trait PmpcfgField {
     fn to_usize(self) -> usize;
 }
 
 #[repr(usize)]
 #[derive(Copy, Clone)]
 pub enum PmpcfgVal {
-    R = 1 << 0,  // Read permission
-    W = 1 << 1,  // Write permission
-    X = 1 << 2,  // Execute permission
-    A = 1 << 3,  // Address-matching mode
-    L = 1 << 7,  // Lock bit
+    R = 1 << 0, // Read permission
+    W = 1 << 1, // Write permission
+    X = 1 << 2, // Execute permission
+    A = 1 << 3, // Address-matching mode
+    L = 1 << 7, // Lock bit
 }
 
 impl PmpcfgField for PmpcfgVal {
@@ -698,15 +841,21 @@ pub fn write_pmpcfg0<T: PmpcfgField>(val: T) {
     write_csr!(PMPCFG0, val.to_usize());
 }
 
+// Set one bit of region 0's configuration, leaving the others as they are
+pub fn set_pmpcfg0<T: PmpcfgField>(val: T) {
+    set_csr!(PMPCFG0, val.to_usize());
+}
+
 // Physical Memory Protection Address Register 0
 // Specifies the address boundary for PMP region 0
+// Holds bits 55..2 of a physical address, which need not be in RAM
 
 pub fn read_pmpaddr0() -> usize {
     read_csr!(PMPADDR0)
 }
 
-pub fn write_pmpaddr0(val: ValidAddress) {
-    write_csr!(PMPADDR0, addr.get())
+pub fn write_pmpaddr0(val: usize) {
+    write_csr!(PMPADDR0, val)
 }
 
 // Return Address Register
@@ -728,7 +877,7 @@ pub fn write_return_addr(val: ValidAddress) {
     unsafe {
         asm!(
             "mv ra, {0}",
-            in(reg) val,
+            in(reg) val.get(),
             options(nostack, preserves_flags)
         );
     }
@@ -741,3 +890,17 @@ pub fn flush_tlb() {
         asm!("sfence.vma zero, zero", options(nostack, preserves_flags));
     }
 }
+
+// Flush the entries for the page at va in address space asid
+pub fn flush_tlb_page(va: usize, asid: usize) {
+    unsafe {
+        asm!("sfence.vma {0}, {1}", in(reg) va, in(reg) asid, options(nostack, preserves_flags));
+    }
+}
+
+// Flush every non-global entry for address space asid
+pub fn flush_tlb_asid(asid: usize) {
+    unsafe {
+        asm!("sfence.vma zero, {0}", in(reg) asid, options(nostack, preserves_flags));
+    }
+}
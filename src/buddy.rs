@@ -0,0 +1,279 @@
+// Buddy allocator for physically contiguous, naturally aligned blocks of 2^order pages
+// Backs the per-hart page caches in kalloc and serves multi-page requests
+// (virtio queues, large kernel stacks, superpages) directly
+
+use crate::memset::{page_index, ValidAddress, NPAGES, PGSIZE, PHYSICAL_MEMORY_LIMIT};
+use crate::spinlock::Spinlock;
+use core::ptr::{null_mut, write_bytes};
+
+pub const NORDERS: usize = 11; // Orders 0 (4 KiB) through 10 (4 MiB)
+
+// Freed blocks are filled with this to catch dangling references
+const JUNK_FREED: u8 = 1;
+
+// Header written into the first page of every free block
+struct Block {
+    next: *mut Block,
+    prev: *mut Block,
+}
+
+struct Buddy {
+    free: [*mut Block; NORDERS], // Free list per order
+    nfree: [usize; NORDERS],     // Length of each free list
+    // For every page: 0 if it does not start a free block, otherwise order + 1
+    head: [u8; NPAGES],
+    start: usize, // Lowest address under management
+}
+
+unsafe impl Send for Buddy {}
+
+static BUDDY: Spinlock<Buddy> = Spinlock::new(Buddy::new(), "buddy");
+
+// Snapshot of free memory, for judging fragmentation
+#[derive(Copy, Clone, Debug)]
+pub struct BuddyStats {
+    pub free_blocks: [usize; NORDERS], // Free blocks at each order
+    pub free_pages: usize,
+    pub largest_order: Option<usize>, // Order of the largest free block
+}
+
+impl BuddyStats {
+    // Share of free memory, in percent, that is not part of the largest free block
+    // 0 means all free memory is one block; close to 100 means it is scattered
+    pub fn fragmentation(&self) -> usize {
+        match self.largest_order {
+            Some(order) if self.free_pages > 0 => 100 - (100 << order) / self.free_pages,
+            _ => 0,
+        }
+    }
+}
+
+pub const fn order_bytes(order: usize) -> usize {
+    PGSIZE << order
+}
+
+impl Buddy {
+    const fn new() -> Self {
+        Buddy {
+            free: [null_mut(); NORDERS],
+            nfree: [0; NORDERS],
+            head: [0; NPAGES],
+            start: PHYSICAL_MEMORY_LIMIT,
+        }
+    }
+
+    fn push(&mut self, addr: usize, order: usize) {
+        let b = addr as *mut Block;
+        unsafe {
+            (*b).prev = null_mut();
+            (*b).next = self.free[order];
+            if let Some(next) = self.free[order].as_mut() {
+                next.prev = b;
+            }
+        }
+        self.free[order] = b;
+        self.nfree[order] += 1;
+        self.head[page_index(addr)] = order as u8 + 1;
+    }
+
+    fn remove(&mut self, addr: usize, order: usize) {
+        let b = addr as *mut Block;
+        unsafe {
+            if let Some(prev) = (*b).prev.as_mut() {
+                prev.next = (*b).next;
+            } else {
+                self.free[order] = (*b).next;
+            }
+            if let Some(next) = (*b).next.as_mut() {
+                next.prev = (*b).prev;
+            }
+        }
+        self.nfree[order] -= 1;
+        self.head[page_index(addr)] = 0;
+    }
+
+    fn is_free(&self, addr: usize, order: usize) -> bool {
+        self.head[page_index(addr)] == order as u8 + 1
+    }
+
+    fn alloc(&mut self, order: usize) -> Option<usize> {
+        // Find the smallest free block that fits, then split it down
+        let found = (order..NORDERS).find(|&o| !self.free[o].is_null())?;
+        let addr = self.free[found] as usize;
+        self.remove(addr, found);
+        for o in (order..found).rev() {
+            self.push(addr + order_bytes(o), o);
+        }
+        Some(addr)
+    }
+
+    fn free(&mut self, mut addr: usize, mut order: usize) {
+        // Merge with the buddy for as long as it is free too
+        while order + 1 < NORDERS {
+            let buddy = addr ^ order_bytes(order);
+            if buddy < self.start
+                || buddy + order_bytes(order) > PHYSICAL_MEMORY_LIMIT
+                || !self.is_free(buddy, order)
+            {
+                break;
+            }
+            self.remove(buddy, order);
+            addr = addr.min(buddy);
+            order += 1;
+        }
+        self.push(addr, order);
+    }
+
+    // Is any page of the block of 2^order pages at addr already free?
+    // Either a free block starts inside it, or it lies inside a larger free block
+    fn check_free(&self, addr: usize, order: usize) -> Result<(), &'static str> {
+        let inside = (0..1 << order).any(|i| self.head[page_index(addr + i * PGSIZE)] != 0);
+        let around = (order + 1..NORDERS).any(|o| {
+            let block = addr & !(order_bytes(o) - 1);
+            block >= self.start && self.is_free(block, o)
+        });
+        if inside || around {
+            return Err("double free");
+        }
+        Ok(())
+    }
+
+    fn add_range(&mut self, start: usize, stop: usize) {
+        self.start = self.start.min(start);
+        let mut addr = start;
+        while addr + PGSIZE <= stop {
+            let order = (0..NORDERS)
+                .rev()
+                .find(|&o| addr.is_multiple_of(order_bytes(o)) && addr + order_bytes(o) <= stop)
+                .unwrap_or(0);
+            self.free(addr, order);
+            addr += order_bytes(order);
+        }
+    }
+
+    fn stats(&self) -> BuddyStats {
+        BuddyStats {
+            free_blocks: self.nfree,
+            free_pages: (0..NORDERS).map(|o| self.nfree[o] << o).sum(),
+            largest_order: (0..NORDERS).rev().find(|&o| self.nfree[o] > 0),
+        }
+    }
+}
+
+// Hand the pages in [start, stop) to the allocator, carved into the largest aligned blocks that fit
+pub fn add_range(start: usize, stop: usize) {
+    BUDDY.lock().add_range(start, stop);
+}
+
+// Allocate 2^order physically contiguous pages aligned to their size
+// Returns None if no block that large is free
+pub fn alloc_pages(order: usize) -> Option<usize> {
+    if order >= NORDERS {
+        return None;
+    }
+    BUDDY.lock().alloc(order)
+}
+
+// Return a block from alloc_pages() with the same order
+pub fn free_pages(addr: usize, order: usize) {
+    if order >= NORDERS {
+        panic!("free_pages: bad order {}", order);
+    }
+    let pa = match ValidAddress::new(addr) {
+        Ok(pa) => pa.get(),
+        Err(e) => panic!("free_pages: {} {:#x}", e, addr),
+    };
+    if pa % order_bytes(order) != 0 || pa + order_bytes(order) > PHYSICAL_MEMORY_LIMIT {
+        panic!("free_pages: misaligned block {:#x} order {}", pa, order);
+    }
+
+    // Fill with junk to catch dangling refs
+    unsafe { write_bytes(pa as *mut u8, JUNK_FREED, order_bytes(order)) };
+
+    let mut buddy = BUDDY.lock();
+    if pa < buddy.start {
+        panic!("free_pages: {:#x} below managed memory", pa);
+    }
+    if let Err(e) = buddy.check_free(pa, order) {
+        panic!("free_pages: {} {:#x} order {}", e, pa, order);
+    }
+    buddy.free(pa, order);
+}
+
+pub fn stats() -> BuddyStats {
+    BUDDY.lock().stats()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Tests run a private allocator over a 16-page block taken from the real
+    // one, so they see exactly the blocks they make
+    const ORDER: usize = 4;
+    static TEST_BUDDY: Spinlock<Buddy> = Spinlock::new(Buddy::new(), "test buddy");
+
+    fn with_test_buddy(f: impl FnOnce(&mut Buddy, usize)) {
+        let block = alloc_pages(ORDER).unwrap();
+        let mut buddy = TEST_BUDDY.lock();
+        buddy.free.fill(null_mut());
+        buddy.nfree.fill(0);
+        buddy.head.fill(0);
+        buddy.start = PHYSICAL_MEMORY_LIMIT;
+        buddy.add_range(block, block + order_bytes(ORDER));
+        f(&mut buddy, block);
+        drop(buddy);
+        free_pages(block, ORDER);
+    }
+
+    // Allocating one page splits the block, leaving one free buddy at every
+    // order below it
+    #[test_case]
+    fn split() {
+        with_test_buddy(|buddy, block| {
+            assert_eq!(buddy.alloc(0), Some(block));
+            assert_eq!(buddy.nfree[..ORDER], [1; ORDER]);
+            assert_eq!(buddy.nfree[ORDER], 0);
+            assert_eq!(buddy.stats().free_pages, (1 << ORDER) - 1);
+            assert_eq!(buddy.stats().largest_order, Some(ORDER - 1));
+            buddy.free(block, 0);
+        });
+    }
+
+    // Freeing every page, in any order, merges them back into the one block
+    #[test_case]
+    fn coalesce() {
+        with_test_buddy(|buddy, block| {
+            let pages: [usize; 1 << ORDER] = core::array::from_fn(|_| buddy.alloc(0).unwrap());
+            assert_eq!(buddy.stats().free_pages, 0);
+            assert_eq!(buddy.alloc(0), None);
+            for i in [5, 0, 15, 9, 2, 12, 7, 1, 14, 3, 10, 6, 13, 4, 11, 8] {
+                buddy.free(pages[i], 0);
+            }
+            let stats = buddy.stats();
+            assert_eq!(stats.free_blocks[ORDER], 1);
+            assert_eq!(stats.free_pages, 1 << ORDER);
+            assert_eq!(stats.fragmentation(), 0);
+            assert_eq!(buddy.alloc(ORDER), Some(block));
+            buddy.free(block, ORDER);
+        });
+    }
+
+    // A page freed twice is caught, including once it has merged into a
+    // larger block, and so is a block holding a page that is already free
+    #[test_case]
+    fn double_free() {
+        with_test_buddy(|buddy, block| {
+            let a = buddy.alloc(0).unwrap();
+            let b = buddy.alloc(0).unwrap();
+            assert_eq!(buddy.check_free(a, 0), Ok(()));
+            buddy.free(a, 0);
+            assert!(buddy.check_free(a, 0).is_err());
+            buddy.free(b, 0);
+            assert!(buddy.check_free(a, 0).is_err());
+            assert!(buddy.check_free(b, 0).is_err());
+            assert!(buddy.check_free(block, 1).is_err());
+            assert_eq!(buddy.stats().free_blocks[ORDER], 1);
+        });
+    }
+}
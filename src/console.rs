@@ -0,0 +1,185 @@
+// Console output and kernel console commands
+
+use crate::kalloc;
+use crate::proc::{self, sleep, wakeup};
+use crate::programs::program;
+use crate::spinlock::Spinlock;
+use crate::uart::putc_sync;
+use crate::vm;
+use core::fmt::{self, Write};
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+// Serialises output so lines from different harts don't interleave
+static PRINT_LOCK: Spinlock<()> = Spinlock::new((), "pr");
+
+// Set once a hart has panicked. From then on output skips PRINT_LOCK,
+// which the panicking hart may be holding
+static PANICKED: AtomicBool = AtomicBool::new(false);
+
+struct Console;
+
+impl Write for Console {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for c in s.bytes() {
+            putc_sync(c);
+        }
+        Ok(())
+    }
+}
+
+pub fn _print(args: fmt::Arguments) {
+    if PANICKED.load(Ordering::Relaxed) {
+        let _ = Console.write_fmt(args);
+        return;
+    }
+    let _guard = PRINT_LOCK.lock();
+    let _ = Console.write_fmt(args);
+}
+
+// Report a panic on the console
+pub fn panic_print(info: &PanicInfo) {
+    PANICKED.store(true, Ordering::Relaxed);
+    let _ = writeln!(Console, "panic: {}", info.message());
+    if let Some(location) = info.location() {
+        let _ = writeln!(Console, "  at {}:{}", location.file(), location.line());
+    }
+}
+
+#[macro_export]
+macro_rules! print {
+    ($($arg:tt)*) => {
+        $crate::console::_print(format_args!($($arg)*))
+    };
+}
+
+#[macro_export]
+macro_rules! println {
+    () => {
+        $crate::print!("\n")
+    };
+    ($($arg:tt)*) => {
+        $crate::print!("{}\n", format_args!($($arg)*))
+    };
+}
+
+const INPUT_BUF_SIZE: usize = 128;
+const BACKSPACE: u8 = 0x08;
+const DELETE: u8 = 0x7f;
+
+// The line being typed at the console
+struct Input {
+    buf: [u8; INPUT_BUF_SIZE],
+    len: usize,
+    ready: bool, // The line is finished and waiting for console_thread()
+}
+
+static INPUT: Spinlock<Input> = Spinlock::new(
+    Input {
+        buf: [0; INPUT_BUF_SIZE],
+        len: 0,
+        ready: false,
+    },
+    "cons",
+);
+
+fn input_chan() -> usize {
+    &INPUT as *const _ as usize
+}
+
+// Handle one character of console input, from uartintr()
+// Echoes it and does line editing; a finished line wakes console_thread()
+// Input typed while the previous line is still being run is dropped
+pub fn consoleintr(c: u8) {
+    let mut input = INPUT.lock();
+    if input.ready {
+        return;
+    }
+    match c {
+        BACKSPACE | DELETE => {
+            if input.len > 0 {
+                input.len -= 1;
+                for c in [BACKSPACE, b' ', BACKSPACE] {
+                    putc_sync(c);
+                }
+            }
+        }
+        b'\r' | b'\n' => {
+            putc_sync(b'\n');
+            input.ready = true;
+            wakeup(input_chan());
+        }
+        c if input.len < INPUT_BUF_SIZE => {
+            putc_sync(c);
+            let len = input.len;
+            input.buf[len] = c;
+            input.len += 1;
+        }
+        _ => {}
+    }
+}
+
+// Kernel thread that runs each line typed at the console
+pub fn console_thread(_: usize) {
+    loop {
+        let mut input = INPUT.lock();
+        while !input.ready {
+            input = sleep(input_chan(), input);
+        }
+        let (buf, len) = (input.buf, input.len);
+        input.len = 0;
+        input.ready = false;
+        drop(input);
+        run_command(core::str::from_utf8(&buf[..len]).unwrap_or(""));
+    }
+}
+
+// Commands understood by the kernel console, with the handler each runs
+// Handlers receive the rest of the line after the command name
+type Command = (&'static str, fn(&str));
+
+const COMMANDS: &[Command] = &[
+    ("mem", |_| kalloc::print_stats()),
+    ("run", run_program),
+    ("vm", vm_command),
+];
+
+// run <program>: start a built-in user program and wait for it to exit
+fn run_program(name: &str) {
+    let Some(image) = program(name) else {
+        crate::println!("run: no program {}", name);
+        return;
+    };
+    match proc::spawn(name, image).and_then(proc::wait_pid) {
+        Ok(status) => crate::println!("{}: exit status {}", name, status),
+        Err(e) => crate::println!("run: {}", e),
+    }
+}
+
+// vm: print the kernel page table
+// vm <pid>: print that process's address space
+fn vm_command(args: &str) {
+    if args.is_empty() {
+        vm::kvmprint();
+        return;
+    }
+    match args.parse() {
+        Ok(pid) => {
+            if let Err(e) = proc::vmprint(pid) {
+                crate::println!("vm: {}", e);
+            }
+        }
+        Err(_) => crate::println!("usage: vm [pid]"),
+    }
+}
+
+// Run one line typed at the kernel console
+pub fn run_command(line: &str) {
+    let line = line.trim();
+    let (name, args) = line.split_once(' ').unwrap_or((line, ""));
+    match COMMANDS.iter().find(|(cmd, _)| *cmd == name) {
+        Some((_, handler)) => handler(args.trim()),
+        None if name.is_empty() => {}
+        None => crate::println!("unknown command: {}", name),
+    }
+}
@@ -1,35 +1,37 @@
-use crate::start;
-use core::arch::asm;
+use crate::start::{start, STACK0, STACK_SIZE};
+use core::arch::{asm, global_asm};
 
-#[no_mangle]
-pub extern "C" fn _entry() -> ! {
-    unsafe {
-        asm!(
-            // set up a stack frame
-            "la sp, stack0",
-            // 4096-byte stack size
-            "li a0, 1024*4",
-            // read mhartid
-            "csrr a1, mhartid",
-            // increment hartid (zero stack avoidance)
-            "addi a1, a1, 1",
-            // offset = stacksize * mhartid
-            "mul a0, a0, a1",
-            // CPU stack pointer = frame + offset
-            "add sp, sp, a0",
-            // jump to start()
-            "call {start}",
-            start = sym start::start,
-            options(noreturn)
-        );
-    }
-}
+// QEMU's -kernel loads the kernel at 0x80000000 and every hart jumps there in
+// machine mode with paging off. kernel.ld puts .text.entry first, so _entry
+// is at that address. It runs before there is a stack, so it can't be Rust
+// QEMU leaves the hartid in a0 and the device tree's address in a1, which
+// are passed on to start() untouched
+global_asm!(
+    ".pushsection .text.entry, \"ax\"",
+    ".globl _entry",
+    "_entry:",
+    // set up a stack for each hart
+    "la sp, {stack0}",
+    // read mhartid
+    "csrr t0, mhartid",
+    // increment hartid (zero stack avoidance)
+    "addi t0, t0, 1",
+    // offset = stacksize * (mhartid + 1), stacksize is a power of two
+    "slli t1, t0, {stack_shift}",
+    // CPU stack pointer = frame + offset
+    "add sp, sp, t1",
+    // jump to start()
+    "call {start}",
+    "j spin",
+    ".popsection",
+    stack0 = sym STACK0,
+    stack_shift = const STACK_SIZE.trailing_zeros(),
+    start = sym start,
+);
 
 #[no_mangle]
 pub extern "C" fn spin() -> ! {
-    loop {
-        unsafe {
-            asm!("j spin", options(noreturn));
-        }
+    unsafe {
+        asm!("j spin", options(noreturn));
     }
 }
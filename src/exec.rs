@@ -0,0 +1,185 @@
+// Loading ELF programs into new user address spaces
+// There is no file system yet, so the images come from programs.rs
+
+use crate::memset::PGSIZE;
+use crate::vm::{elf_perm, UserAddressSpace, VmError};
+use core::mem::size_of;
+use core::ptr::read_unaligned;
+
+const ELF_MAGIC: [u8; 4] = *b"\x7fELF";
+const ELFCLASS64: u8 = 2;
+const ELFDATA2LSB: u8 = 1;
+
+// ELF e_type values
+const ET_EXEC: u16 = 2; // Loaded at the addresses it was linked for
+const ET_DYN: u16 = 3; // Position independent, loaded wherever the kernel likes
+
+const EM_RISCV: u16 = 243;
+
+// Program header p_type for a segment to load
+const PT_LOAD: u32 = 1;
+
+// File header, at the start of the image
+#[repr(C)]
+#[derive(Copy, Clone)]
+struct ElfHeader {
+    ident: [u8; 16],
+    kind: u16,
+    machine: u16,
+    version: u32,
+    entry: u64,
+    phoff: u64,
+    shoff: u64,
+    flags: u32,
+    ehsize: u16,
+    phentsize: u16,
+    phnum: u16,
+    shentsize: u16,
+    shnum: u16,
+    shstrndx: u16,
+}
+
+// Program section header, one per segment
+#[repr(C)]
+#[derive(Copy, Clone)]
+struct ProgramHeader {
+    kind: u32,
+    flags: u32,
+    off: u64,
+    vaddr: u64,
+    paddr: u64,
+    filesz: u64,
+    memsz: u64,
+    align: u64,
+}
+
+// A new program, ready for usertrapret()
+pub struct Image {
+    pub aspace: UserAddressSpace,
+    pub entry: usize, // Initial user program counter
+    pub sp: usize,    // Initial user stack pointer
+}
+
+// Read a T from image at offset, which need not be aligned
+fn read<T: Copy>(image: &[u8], offset: usize) -> Result<T, &'static str> {
+    match offset.checked_add(size_of::<T>()) {
+        Some(end) if end <= image.len() => {
+            Ok(unsafe { read_unaligned(image[offset..].as_ptr() as *const T) })
+        }
+        _ => Err("exec: truncated image"),
+    }
+}
+
+fn vm_error(e: VmError) -> &'static str {
+    match e {
+        VmError::OutOfMemory => "exec: out of memory",
+        VmError::WriteExec(_) => "exec: segment is writable and executable",
+        _ => "exec: bad segment address",
+    }
+}
+
+// Build an address space holding the ELF program in image, with the user
+// stack set up, whose trapframe page is at physical address trapframe
+// The segments are loaded one after another into user memory, so they must
+// come in address order; the heap starts after the last of them
+pub fn exec(image: &[u8], trapframe: usize) -> Result<Image, &'static str> {
+    let elf: ElfHeader = read(image, 0)?;
+    if elf.ident[..4] != ELF_MAGIC
+        || elf.ident[4] != ELFCLASS64
+        || elf.ident[5] != ELFDATA2LSB
+        || elf.machine != EM_RISCV
+    {
+        return Err("exec: not a RISC-V ELF64 program");
+    }
+    let pie = match elf.kind {
+        ET_EXEC => false,
+        ET_DYN => true,
+        _ => return Err("exec: not an executable"),
+    };
+
+    let mut aspace = UserAddressSpace::new(trapframe).map_err(vm_error)?;
+    aspace.randomize(pie);
+    let base = if pie { aspace.load_base() } else { 0 };
+
+    for i in 0..elf.phnum as usize {
+        let offset = elf.phoff as usize + i * size_of::<ProgramHeader>();
+        let ph: ProgramHeader = read(image, offset)?;
+        if ph.kind != PT_LOAD {
+            continue;
+        }
+        let (off, filesz, memsz) = (ph.off as usize, ph.filesz as usize, ph.memsz as usize);
+        if memsz < filesz || !(ph.vaddr as usize).is_multiple_of(PGSIZE) {
+            return Err("exec: bad segment");
+        }
+        let va = base
+            .checked_add(ph.vaddr as usize)
+            .ok_or("exec: bad segment")?;
+        let end = va.checked_add(memsz).ok_or("exec: bad segment")?;
+        let data = off
+            .checked_add(filesz)
+            .and_then(|data_end| image.get(off..data_end))
+            .ok_or("exec: truncated image")?;
+        aspace
+            .grow_eager(end, elf_perm(ph.flags))
+            .map_err(vm_error)?;
+        aspace.load(va, data).map_err(vm_error)?;
+    }
+    aspace.randomize_brk();
+
+    let sp = aspace.setup_stack().map_err(vm_error)?;
+    Ok(Image {
+        aspace,
+        entry: base + elf.entry as usize,
+        sp,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::exec;
+    use crate::kalloc::{kfree, kzalloc, PagePurpose};
+    use crate::memset::{PGSIZE, PIE_BASE};
+    use crate::proc::{spawn, wait_pid};
+    use crate::programs::program;
+    use crate::vm::{PteFlags, PF_W};
+    use alloc::vec::Vec;
+
+    // A position-independent program is loaded at a random page above PIE_BASE
+    #[test_case]
+    fn load_base() {
+        let tf = kzalloc(PagePurpose::Other).unwrap();
+        let image = exec(program("stack").unwrap(), tf).unwrap();
+        let base = image.aspace.load_base();
+        assert!(base >= PIE_BASE && base.is_multiple_of(PGSIZE));
+        assert!(image.entry > base);
+        drop(image);
+        kfree(tf);
+    }
+
+    // A write-only segment is mapped readable as well, since W without R is
+    // a reserved PTE encoding
+    #[test_case]
+    fn write_only_segment() {
+        // Patch the flags of the one program header, which follows the file header
+        let mut elf: Vec<u8> = program("stack").unwrap().into();
+        elf[64 + 4..64 + 8].copy_from_slice(&PF_W.to_le_bytes());
+        let tf = kzalloc(PagePurpose::Other).unwrap();
+        let image = exec(&elf, tf).unwrap();
+        let base = image.aspace.load_base();
+        let pte = image.aspace.pagetable().lookup(base).unwrap();
+        assert!(pte.flags().contains(PteFlags::R | PteFlags::W));
+        assert!(!pte.flags().contains(PteFlags::X));
+        drop(image);
+        kfree(tf);
+    }
+
+    // A program's stack grows a page at a time as it recurses, and one that
+    // keeps going is killed at its rlimit
+    #[test_case]
+    fn stack_growth() {
+        let pid = spawn("stack", program("stack").unwrap()).unwrap();
+        assert_eq!(wait_pid(pid), Ok(0));
+        let pid = spawn("overflow", program("overflow").unwrap()).unwrap();
+        assert_eq!(wait_pid(pid), Ok(-1));
+    }
+}
@@ -0,0 +1,84 @@
+// Reading the flattened device tree that QEMU passes at boot
+// Only the /chosen node is looked at, for the kernel command line and the
+// random seed QEMU puts there. The tree sits in RAM that kinit() hands out,
+// so it must be read before then
+
+use crate::memset::ValidAddress;
+use core::slice;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+const FDT_MAGIC: u32 = 0xd00d_feed;
+
+// Structure block tokens
+const FDT_BEGIN_NODE: u32 = 1;
+const FDT_END_NODE: u32 = 2;
+const FDT_PROP: u32 = 3;
+const FDT_NOP: u32 = 4;
+const FDT_END: u32 = 9;
+
+// Physical address of the device tree, from a1 at boot
+static DTB: AtomicUsize = AtomicUsize::new(0);
+
+// Remember where the device tree is. Called from start()
+pub fn set_dtb(dtb: usize) {
+    DTB.store(dtb, Ordering::Relaxed);
+}
+
+// Big-endian word at byte offset off in the tree
+fn word(fdt: &[u8], off: usize) -> Option<u32> {
+    let bytes = fdt.get(off..off + 4)?;
+    Some(u32::from_be_bytes(bytes.try_into().ok()?))
+}
+
+// The NUL-terminated string at byte offset off
+fn cstr(fdt: &[u8], off: usize) -> Option<&[u8]> {
+    let rest = fdt.get(off..)?;
+    rest.iter().position(|&c| c == 0).map(|len| &rest[..len])
+}
+
+// The value of property name in the /chosen node, if the tree has one
+pub fn chosen(name: &str) -> Option<&'static [u8]> {
+    let dtb = ValidAddress::new(DTB.load(Ordering::Relaxed)).ok()?.get();
+    let header = unsafe { slice::from_raw_parts(dtb as *const u8, 40) };
+    if word(header, 0)? != FDT_MAGIC {
+        return None;
+    }
+    let size = word(header, 4)? as usize;
+    let fdt = unsafe { slice::from_raw_parts(dtb as *const u8, size) };
+    let structs = word(fdt, 8)? as usize;
+    let strings = word(fdt, 12)? as usize;
+
+    // Walk the structure block, tracking how deep we are and whether
+    // we are directly inside /chosen
+    let mut off = structs;
+    let mut depth = 0;
+    let mut in_chosen = false;
+    loop {
+        let token = word(fdt, off)?;
+        off += 4;
+        match token {
+            FDT_BEGIN_NODE => {
+                let node = cstr(fdt, off)?;
+                off += (node.len() + 1).next_multiple_of(4);
+                depth += 1;
+                in_chosen = depth == 2 && node == b"chosen";
+            }
+            FDT_END_NODE => {
+                depth -= 1;
+                in_chosen = false;
+            }
+            FDT_PROP => {
+                let len = word(fdt, off)? as usize;
+                let nameoff = word(fdt, off + 4)? as usize;
+                let value = fdt.get(off + 8..off + 8 + len)?;
+                off += 8 + len.next_multiple_of(4);
+                if in_chosen && cstr(fdt, strings + nameoff)? == name.as_bytes() {
+                    return Some(value);
+                }
+            }
+            FDT_NOP => {}
+            FDT_END => return None,
+            _ => return None,
+        }
+    }
+}
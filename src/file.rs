@@ -0,0 +1,78 @@
+// Open files
+// There is no file system yet, so every file is a memfd: a fixed-size file that
+// lives only in memory until its last descriptor and mapping go away. Files can
+// be mapped with mmap(); MAP_SHARED changes reach them on msync, munmap and exit
+
+use crate::proc::Proc;
+use crate::sleeplock::SleepLock;
+use crate::vm::MappedFile;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+
+pub const NOFILE: usize = 16; // Open files per process
+pub const MAXFILE: usize = 16 * 1024 * 1024; // Largest memfd, in bytes
+
+pub struct MemFile {
+    data: SleepLock<Vec<u8>>,
+}
+
+impl MemFile {
+    // A zero-filled file of size bytes
+    pub fn new(size: usize) -> Result<Self, &'static str> {
+        if size > MAXFILE {
+            return Err("memfd too large");
+        }
+        let mut data = Vec::new();
+        data.try_reserve_exact(size)
+            .map_err(|_| "memfd: out of memory")?;
+        data.resize(size, 0);
+        Ok(MemFile {
+            data: SleepLock::new(data, "memfd"),
+        })
+    }
+
+    // Copy the file's contents at offset into buf, returning the bytes copied
+    pub fn read_at(&self, offset: usize, buf: &mut [u8]) -> usize {
+        let data = self.data.lock();
+        let start = offset.min(data.len());
+        let n = buf.len().min(data.len() - start);
+        buf[..n].copy_from_slice(&data[start..start + n]);
+        n
+    }
+
+    // Copy buf into the file at offset, returning the bytes copied
+    // The file never grows, so bytes past its end are dropped
+    pub fn write_at(&self, offset: usize, buf: &[u8]) -> usize {
+        let mut data = self.data.lock();
+        let start = offset.min(data.len());
+        let n = buf.len().min(data.len() - start);
+        data[start..start + n].copy_from_slice(&buf[..n]);
+        n
+    }
+}
+
+impl MappedFile for MemFile {
+    fn read_page(&self, offset: usize, buf: &mut [u8]) -> Result<(), &'static str> {
+        let n = self.read_at(offset, buf);
+        buf[n..].fill(0);
+        Ok(())
+    }
+
+    // A page that straddles the end of the file only writes the part inside it
+    fn write_page(&self, offset: usize, buf: &[u8]) -> Result<(), &'static str> {
+        self.write_at(offset, buf);
+        Ok(())
+    }
+}
+
+// Install f in the lowest free slot of p's open file table, returning the descriptor
+pub fn fdalloc(p: &mut Proc, f: Arc<MemFile>) -> Option<usize> {
+    let fd = p.ofile.iter().position(|slot| slot.is_none())?;
+    p.ofile[fd] = Some(f);
+    Some(fd)
+}
+
+// The file open as fd in p
+pub fn fdget(p: &Proc, fd: usize) -> Option<Arc<MemFile>> {
+    p.ofile.get(fd)?.clone()
+}
@@ -0,0 +1,107 @@
+// Inter-processor interrupts
+// Supervisor mode cannot interrupt another hart by itself. A hart writes the
+// target's CLINT msip word, which raises a machine software interrupt there;
+// mswvec turns that into a supervisor software interrupt (SSIP), and the
+// target's trap handler calls ipiintr()
+
+use crate::arch::{
+    clear_sip, read_mhartid, set_mie, set_sie, write_mscratch, write_mtvec, MieVal, SieVal, SipVal,
+    MACHINE_SOFTWARE_INTERRUPT,
+};
+use crate::memset::{clint_msip, ValidAddress};
+use crate::proc::NCPU;
+use crate::vm::shootdown_intr;
+use core::arch::global_asm;
+use core::ptr::{addr_of, addr_of_mut, write_volatile};
+
+// Per-hart scratch space for mswvec: two saved registers and the hart's msip address
+static mut MSCRATCH: [[usize; 3]; NCPU] = [[0; 3]; NCPU];
+
+// Bytes of stack per hart for mtrap(); must be a power of two
+const MSTACK_SIZE: usize = 4096;
+
+#[repr(C, align(16))]
+struct MStack([u8; MSTACK_SIZE * NCPU]);
+
+static mut MSTACK: MStack = MStack([0; MSTACK_SIZE * NCPU]);
+
+global_asm!(
+    ".align 4",
+    ".globl mswvec",
+    "mswvec:",
+    // Machine-mode trap vector; only software interrupts are enabled in machine mode,
+    // everything else is delegated to supervisor mode
+    // mscratch points to this hart's MSCRATCH entry
+    "csrrw a0, mscratch, a0",
+    "sd a1, 0(a0)",
+    "sd a2, 8(a0)",
+    // Anything but a software interrupt is a kernel bug
+    "csrr a1, mcause",
+    "li a2, {msi}",
+    "bne a1, a2, 1f",
+    // Acknowledge the interrupt by clearing this hart's msip word
+    "ld a1, 16(a0)",
+    "sw zero, 0(a1)",
+    // Raise a supervisor software interrupt in its place
+    "li a2, {ssip}",
+    "csrs mip, a2",
+    "ld a2, 8(a0)",
+    "ld a1, 0(a0)",
+    "csrrw a0, mscratch, a0",
+    "mret",
+    // The interrupted sp may be a kernel virtual address, which means nothing
+    // in machine mode, so mtrap() runs on this hart's MSTACK
+    "1:",
+    "csrr tp, mhartid",
+    "addi a0, tp, 1",
+    "slli a0, a0, {mstack_shift}",
+    "la sp, {mstack}",
+    "add sp, sp, a0",
+    "csrr a0, mcause",
+    "csrr a1, mepc",
+    "csrr a2, mtval",
+    "call {mtrap}",
+    msi = const MACHINE_SOFTWARE_INTERRUPT,
+    ssip = const SipVal::SSIP as usize,
+    mstack_shift = const MSTACK_SIZE.trailing_zeros(),
+    mstack = sym MSTACK,
+    mtrap = sym mtrap,
+);
+
+// A trap that should have gone to supervisor mode came to machine mode
+extern "C" fn mtrap(mcause: usize, mepc: usize, mtval: usize) -> ! {
+    panic!(
+        "mtrap: mcause {:#x} mepc={:#x} mtval={:#x}",
+        mcause, mepc, mtval
+    );
+}
+
+extern "C" {
+    static mswvec: u8;
+}
+
+// Set up this hart to receive IPIs
+// Called by start() in machine mode, before it drops to supervisor mode
+pub fn ipiinit() {
+    let hart = read_mhartid();
+    unsafe {
+        let scratch = addr_of_mut!(MSCRATCH[hart]);
+        (*scratch)[2] = clint_msip(hart);
+        write_mscratch(scratch as usize);
+    }
+    let vec = ValidAddress::new(addr_of!(mswvec) as usize).expect("ipiinit: mswvec");
+    write_mtvec(vec);
+    set_mie(MieVal::MSIE);
+    set_sie(SieVal::SSIE);
+}
+
+// Interrupt hart
+pub fn send_ipi(hart: usize) {
+    unsafe { write_volatile(clint_msip(hart) as *mut u32, 1) };
+}
+
+// Supervisor software interrupt: another hart wants something done here
+pub fn ipiintr() {
+    clear_sip(SipVal::SSIP);
+    shootdown_intr();
+}
@@ -0,0 +1,497 @@
+// Physical memory allocator, for user processes, kernel stacks,
+// page-table pages, and pipe buffers. Allocates whole 4096-byte pages
+// from per-hart caches that refill from and drain to the buddy allocator
+
+use crate::arch::{read_return_addr, read_threadptr};
+use crate::buddy;
+use crate::memset::{page_index, pg_round_up, ValidAddress, NPAGES, PGSIZE, PHYSICAL_MEMORY_LIMIT};
+use crate::println;
+use crate::proc::NCPU;
+use crate::spinlock::{pop_off, push_off, Spinlock};
+use crate::swap::swap_stats;
+use core::ptr::{addr_of, null_mut, write_bytes};
+use core::sync::atomic::{AtomicU32, AtomicU8, AtomicUsize, Ordering};
+
+// Freed pages are filled with this to catch dangling references
+const JUNK_FREED: u8 = 1;
+// Fresh pages are filled with this to catch reads of uninitialised memory
+const JUNK_ALLOCATED: u8 = 5;
+// Pages moved at once between a hart's cache and the buddy allocator or another hart
+const BATCH: usize = 32;
+// A hart's cache gives a batch back to the buddy allocator once it holds this many pages,
+// so freed memory can still coalesce into larger blocks
+const CACHE_HIGH: usize = 4 * BATCH;
+
+extern "C" {
+    // First address after kernel, defined by kernel.ld
+    static end: u8;
+}
+
+// A free page holds a pointer to the next free page
+struct Run {
+    next: *mut Run,
+}
+
+struct FreeList {
+    head: *mut Run,
+    len: usize,
+}
+
+unsafe impl Send for FreeList {}
+
+impl FreeList {
+    fn push(&mut self, r: *mut Run) {
+        unsafe { (*r).next = self.head };
+        self.head = r;
+        self.len += 1;
+    }
+
+    fn pop(&mut self) -> Option<*mut Run> {
+        let r = self.head;
+        if r.is_null() {
+            return None;
+        }
+        self.head = unsafe { (*r).next };
+        self.len -= 1;
+        Some(r)
+    }
+}
+
+// Each hart frees into and allocates from its own cache, so harts only
+// contend for a lock when one of them has to refill or steal
+static KMEM: [Spinlock<FreeList>; NCPU] = [const {
+    Spinlock::new(
+        FreeList {
+            head: null_mut(),
+            len: 0,
+        },
+        "kmem",
+    )
+}; NCPU];
+
+// What an allocated page is being used for
+#[repr(u8)]
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum PagePurpose {
+    PageTable = 0,
+    KernelStack = 1,
+    User = 2,
+    Slab = 3,
+    Buffer = 4,
+    Other = 5,
+    Heap = 6, // Kernel heap allocations too large for a slab cache
+}
+
+pub const NPURPOSES: usize = 7;
+
+impl PagePurpose {
+    pub fn from_index(i: usize) -> Option<Self> {
+        match i {
+            0 => Some(PagePurpose::PageTable),
+            1 => Some(PagePurpose::KernelStack),
+            2 => Some(PagePurpose::User),
+            3 => Some(PagePurpose::Slab),
+            4 => Some(PagePurpose::Buffer),
+            5 => Some(PagePurpose::Other),
+            6 => Some(PagePurpose::Heap),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            PagePurpose::PageTable => "page tables",
+            PagePurpose::KernelStack => "kernel stacks",
+            PagePurpose::User => "user pages",
+            PagePurpose::Slab => "slab",
+            PagePurpose::Buffer => "buffers",
+            PagePurpose::Other => "other",
+            PagePurpose::Heap => "heap",
+        }
+    }
+}
+
+// Pages handed to the allocator at boot
+static TOTAL: AtomicUsize = AtomicUsize::new(0);
+// Pages currently allocated, per purpose
+static USED: [AtomicUsize; NPURPOSES] = [const { AtomicUsize::new(0) }; NPURPOSES];
+// For the first page of every allocation: purpose + 1, or 0 if not allocated
+static OWNER: [AtomicU8; NPAGES] = [const { AtomicU8::new(0) }; NPAGES];
+// References to the first page of every allocation, so pages shared between
+// address spaces are only freed when the last mapping goes away
+static REFCOUNT: [AtomicU32; NPAGES] = [const { AtomicU32::new(0) }; NPAGES];
+
+// With kalloc-debug, and always in the kernel tests, every allocation also
+// remembers who made it and when, so allocations outstanding at the end of a
+// test can be traced to their caller
+#[cfg(any(test, feature = "kalloc-debug"))]
+static CALLER: [AtomicUsize; NPAGES] = [const { AtomicUsize::new(0) }; NPAGES];
+#[cfg(any(test, feature = "kalloc-debug"))]
+static SEQ: [AtomicUsize; NPAGES] = [const { AtomicUsize::new(0) }; NPAGES];
+#[cfg(any(test, feature = "kalloc-debug"))]
+static NEXT_SEQ: AtomicUsize = AtomicUsize::new(0);
+
+// Snapshot of the allocator's page counts
+#[derive(Copy, Clone, Debug)]
+pub struct MemStats {
+    pub total: usize,
+    pub free: usize,
+    pub used: [usize; NPURPOSES], // Indexed by PagePurpose
+}
+
+// Record an allocation of 2^order pages at pa
+#[cfg_attr(not(any(test, feature = "kalloc-debug")), allow(unused_variables))]
+fn track_alloc(pa: usize, order: usize, purpose: PagePurpose, caller: usize) {
+    OWNER[page_index(pa)].store(purpose as u8 + 1, Ordering::Relaxed);
+    USED[purpose as usize].fetch_add(1 << order, Ordering::Relaxed);
+    #[cfg(any(test, feature = "kalloc-debug"))]
+    {
+        CALLER[page_index(pa)].store(caller, Ordering::Relaxed);
+        SEQ[page_index(pa)].store(NEXT_SEQ.fetch_add(1, Ordering::Relaxed), Ordering::Relaxed);
+    }
+}
+
+// Forget the allocation of 2^order pages at pa
+fn track_free(pa: usize, order: usize) {
+    let owner = OWNER[page_index(pa)].swap(0, Ordering::Relaxed);
+    if let Some(purpose) = owner.checked_sub(1) {
+        USED[purpose as usize].fetch_sub(1 << order, Ordering::Relaxed);
+    }
+}
+
+// Add a reference to an allocated page
+pub fn page_ref(pa: usize) {
+    let old = REFCOUNT[page_index(pa)].fetch_add(1, Ordering::AcqRel);
+    if old == 0 {
+        panic!("page_ref: page {:#x} is not allocated", pa);
+    }
+}
+
+// Number of references to an allocated page, 0 if it is free
+pub fn page_refcount(pa: usize) -> usize {
+    REFCOUNT[page_index(pa)].load(Ordering::Acquire) as usize
+}
+
+// Drop a reference to an allocated page
+// Returns true if that was the last one and the page should be freed
+fn page_unref(pa: usize) -> bool {
+    match REFCOUNT[page_index(pa)]
+        .fetch_update(Ordering::AcqRel, Ordering::Acquire, |c| c.checked_sub(1))
+    {
+        Ok(old) => old == 1,
+        Err(_) => panic!("kfree: double free or refcount underflow {:#x}", pa),
+    }
+}
+
+pub fn mem_stats() -> MemStats {
+    let cached: usize = KMEM.iter().map(|kmem| kmem.lock().len).sum();
+    MemStats {
+        total: TOTAL.load(Ordering::Relaxed),
+        free: buddy::stats().free_pages + cached,
+        used: core::array::from_fn(|i| USED[i].load(Ordering::Relaxed)),
+    }
+}
+
+// Print page counts on the console
+pub fn print_stats() {
+    let stats = mem_stats();
+    println!("pages: {} total, {} free", stats.total, stats.free);
+    for (i, used) in stats.used.iter().enumerate() {
+        if let Some(purpose) = PagePurpose::from_index(i) {
+            println!("  {}: {}", purpose.name(), used);
+        }
+    }
+    let buddy = buddy::stats();
+    println!(
+        "buddy: free blocks by order {:?}, largest order {:?}, {}% fragmented",
+        buddy.free_blocks,
+        buddy.largest_order,
+        buddy.fragmentation()
+    );
+    let (free, total) = swap_stats();
+    if total > 0 {
+        println!("swap: {} slots, {} free", total, free);
+    }
+}
+
+// Position in the allocation sequence, to pass to report_leaks() later
+#[cfg(any(test, feature = "kalloc-debug"))]
+pub fn leak_mark() -> usize {
+    NEXT_SEQ.load(Ordering::Relaxed)
+}
+
+// Print every allocation made since mark that has not been freed, other than
+// slab cache pages. Returns how many there were
+#[cfg(any(test, feature = "kalloc-debug"))]
+pub fn report_leaks(mark: usize) -> usize {
+    let mut leaks = 0;
+    for i in 0..NPAGES {
+        let owner = OWNER[i].load(Ordering::Relaxed);
+        if owner == 0 || SEQ[i].load(Ordering::Relaxed) < mark {
+            continue;
+        }
+        let purpose = PagePurpose::from_index(owner as usize - 1).unwrap_or(PagePurpose::Other);
+        // A slab cache keeps its pages for later objects once it has them
+        if purpose == PagePurpose::Slab {
+            continue;
+        }
+        println!(
+            "leak: page {:#x} ({}) allocated from {:#x}",
+            crate::memset::KERNEL_BASE_ADDRESS + i * PGSIZE,
+            purpose.name(),
+            CALLER[i].load(Ordering::Relaxed)
+        );
+        leaks += 1;
+    }
+    leaks
+}
+
+// First page the allocator may hand out
+fn kernel_end() -> usize {
+    pg_round_up(addr_of!(end) as usize)
+}
+
+// Hand every page between the end of the kernel image and the top of RAM to the allocator
+pub fn kinit() {
+    buddy::add_range(kernel_end(), PHYSICAL_MEMORY_LIMIT);
+    TOTAL.store(buddy::stats().free_pages, Ordering::Relaxed);
+}
+
+// Drop a reference to the page of physical memory at pa, which should have been
+// returned by a call to kalloc(), and free it once no references remain
+pub fn kfree(pa: usize) {
+    let addr = match ValidAddress::new(pa) {
+        Ok(addr) => addr.get(),
+        Err(e) => panic!("kfree: {} {:#x}", e, pa),
+    };
+    if addr % PGSIZE != 0 || addr < kernel_end() {
+        panic!("kfree: bad page {:#x}", addr);
+    }
+    if !page_unref(addr) {
+        return;
+    }
+
+    track_free(addr, 0);
+
+    // Fill with junk to catch dangling refs
+    unsafe { write_bytes(addr as *mut u8, JUNK_FREED, PGSIZE) };
+
+    push_off();
+    let mut kmem = KMEM[read_threadptr()].lock();
+    kmem.push(addr as *mut Run);
+    if kmem.len >= CACHE_HIGH {
+        for _ in 0..BATCH {
+            if let Some(r) = kmem.pop() {
+                buddy::free_pages(r as usize, 0);
+            }
+        }
+    }
+    drop(kmem);
+    pop_off();
+}
+
+// Allocate one 4096-byte page of physical memory, charged to purpose
+// Returns None if the memory cannot be allocated
+#[inline(never)]
+pub fn kalloc(purpose: PagePurpose) -> Option<usize> {
+    let caller = read_return_addr();
+    push_off();
+    let hart = read_threadptr();
+    let mine = KMEM[hart].lock().pop();
+    let r = mine.or_else(|| refill(hart)).or_else(|| steal(hart));
+    pop_off();
+    let r = r?;
+
+    REFCOUNT[page_index(r as usize)].store(1, Ordering::Release);
+    track_alloc(r as usize, 0, purpose, caller);
+
+    // Fill with junk
+    unsafe { write_bytes(r as *mut u8, JUNK_ALLOCATED, PGSIZE) };
+    Some(r as usize)
+}
+
+// Refill hart's empty cache with a batch of pages from the buddy allocator
+// Returns one of the new pages for the caller
+fn refill(hart: usize) -> Option<*mut Run> {
+    let r = buddy::alloc_pages(0)? as *mut Run;
+    let mut kmem = KMEM[hart].lock();
+    for _ in 1..BATCH {
+        match buddy::alloc_pages(0) {
+            Some(pa) => kmem.push(pa as *mut Run),
+            None => break,
+        }
+    }
+    Some(r)
+}
+
+// Refill hart's empty cache with a batch taken from the first other hart that has pages
+// Returns one of the stolen pages for the caller
+// Never holds two cache locks at once, so two harts stealing from each other can't deadlock
+fn steal(hart: usize) -> Option<*mut Run> {
+    let mut batch = FreeList {
+        head: null_mut(),
+        len: 0,
+    };
+    for victim in (1..NCPU).map(|i| (hart + i) % NCPU) {
+        let mut kmem = KMEM[victim].lock();
+        while batch.len < BATCH {
+            match kmem.pop() {
+                Some(r) => batch.push(r),
+                None => break,
+            }
+        }
+        if batch.len > 0 {
+            break;
+        }
+    }
+
+    let r = batch.pop()?;
+    let mut kmem = KMEM[hart].lock();
+    while let Some(page) = batch.pop() {
+        kmem.push(page);
+    }
+    Some(r)
+}
+
+// Allocate one page of physical memory, zeroed
+pub fn kzalloc(purpose: PagePurpose) -> Option<usize> {
+    let pa = kalloc(purpose)?;
+    unsafe { write_bytes(pa as *mut u8, 0, PGSIZE) };
+    Some(pa)
+}
+
+// Allocate 2^order physically contiguous pages, charged to purpose
+#[inline(never)]
+pub fn kalloc_pages(order: usize, purpose: PagePurpose) -> Option<usize> {
+    let caller = read_return_addr();
+    let pa = buddy::alloc_pages(order)?;
+    REFCOUNT[page_index(pa)].store(1, Ordering::Release);
+    track_alloc(pa, order, purpose, caller);
+    Some(pa)
+}
+
+// Turn a block from kalloc_pages() into 2^order pages that are each
+// referenced and freed on their own with page_ref() and kfree()
+// Every page inherits the block's references and purpose
+pub fn ksplit_pages(pa: usize, order: usize) {
+    let head = page_index(pa);
+    let refs = REFCOUNT[head].load(Ordering::Acquire);
+    if refs == 0 {
+        panic!("ksplit_pages: block {:#x} is not allocated", pa);
+    }
+    let owner = OWNER[head].load(Ordering::Relaxed);
+    for page in head + 1..head + (1 << order) {
+        REFCOUNT[page].store(refs, Ordering::Release);
+        OWNER[page].store(owner, Ordering::Relaxed);
+        #[cfg(any(test, feature = "kalloc-debug"))]
+        {
+            CALLER[page].store(CALLER[head].load(Ordering::Relaxed), Ordering::Relaxed);
+            SEQ[page].store(SEQ[head].load(Ordering::Relaxed), Ordering::Relaxed);
+        }
+    }
+}
+
+// Drop a reference to a block from kalloc_pages() with the same order,
+// freeing it once no references remain
+pub fn kfree_pages(pa: usize, order: usize) {
+    if ValidAddress::new(pa).is_err() {
+        panic!("kfree_pages: bad block {:#x}", pa);
+    }
+    if !page_unref(pa) {
+        return;
+    }
+    track_free(pa, order);
+    buddy::free_pages(pa, order);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::arch::read_time;
+    use crate::proc::{kthread, kthread_wait};
+    use crate::start::harts_online;
+    use crate::vm::{PteFlags, UserAddressSpace};
+    use alloc::vec::Vec;
+
+    // fork()s each worker does, and pages in each parent
+    const ROUNDS: usize = 200;
+    const PAGES: usize = 16;
+    // QEMU's time counter runs at 10 MHz
+    const TIME_PER_MS: usize = 10_000;
+
+    // Build a process image, fork it, and tear both down again, which takes
+    // and frees a page per user page, page-table page and trapframe
+    fn fork_heavy(_: usize) {
+        for _ in 0..ROUNDS {
+            let tf = kalloc(PagePurpose::Other).unwrap();
+            let child_tf = kalloc(PagePurpose::Other).unwrap();
+            let mut parent = UserAddressSpace::new(tf).unwrap();
+            parent
+                .grow_eager(PAGES * PGSIZE, PteFlags::R | PteFlags::W)
+                .unwrap();
+            let child = parent.copy(child_tf).unwrap();
+            drop(child);
+            drop(parent);
+            kfree(child_tf);
+            kfree(tf);
+        }
+    }
+
+    // A heap allocation too large for a slab cache is charged to Heap, not
+    // Slab, so it shows up as a leak until it is freed
+    #[test_case]
+    fn large_heap_leak() {
+        let mark = leak_mark();
+        let before = mem_stats().used[PagePurpose::Heap as usize];
+        let big: Vec<u8> = Vec::with_capacity(3 * PGSIZE);
+        assert_eq!(mem_stats().used[PagePurpose::Heap as usize], before + 4);
+        assert_eq!(report_leaks(mark), 1);
+        drop(big);
+        assert_eq!(report_leaks(mark), 0);
+    }
+
+    // Time for n workers to finish the workload side by side
+    fn run_workers(n: usize) -> usize {
+        let start = read_time();
+        let pids: Vec<usize> = (0..n)
+            .map(|_| kthread("fork_heavy", fork_heavy, 0).unwrap())
+            .collect();
+        for pid in pids {
+            kthread_wait(pid);
+        }
+        read_time() - start
+    }
+
+    // With per-hart caches, workers on different harts rarely touch the same
+    // lock, so n of them get through n times the work in about the same time
+    // as one. Run with -smp 4 (as `cargo test` does) to see the scaling
+    #[test_case]
+    fn scaling() {
+        let harts = harts_online();
+        run_workers(1); // warm the caches up
+        let one = run_workers(1);
+        let all = run_workers(harts);
+        let speedup = 100 * one * harts / all.max(1);
+        println!(
+            "\n  1 worker: {}ms, {} workers: {}ms, throughput x{}.{:02}",
+            one / TIME_PER_MS,
+            harts,
+            all / TIME_PER_MS,
+            speedup / 100,
+            speedup % 100
+        );
+        // Contention on a shared lock would keep the parallel run close to
+        // doing the same work one worker at a time. With four harts or more,
+        // demand at least half again the throughput of one
+        if harts >= 4 {
+            assert!(
+                speedup > 150,
+                "throughput only x{}.{:02}",
+                speedup / 100,
+                speedup % 100
+            );
+        } else {
+            assert!(all < one * harts);
+        }
+    }
+}
@@ -0,0 +1,64 @@
+// Interrupts and exceptions while in supervisor mode come here
+// trapinithart() points stvec at kernelvec whenever the kernel is running
+// The trap is taken on the current kernel stack: push the registers a call to
+// kerneltrap() may clobber, call it, and pop them again
+
+use crate::trap::kerneltrap;
+use core::arch::global_asm;
+
+global_asm!(
+    ".align 4",
+    ".globl kernelvec",
+    "kernelvec:",
+    // Make room to save registers
+    "addi sp, sp, -256",
+    // Save caller-saved registers
+    "sd ra, 0(sp)",
+    // Not sp, kernelvec puts it back by adding 256
+    "sd gp, 16(sp)",
+    "sd tp, 24(sp)",
+    "sd t0, 32(sp)",
+    "sd t1, 40(sp)",
+    "sd t2, 48(sp)",
+    "sd a0, 72(sp)",
+    "sd a1, 80(sp)",
+    "sd a2, 88(sp)",
+    "sd a3, 96(sp)",
+    "sd a4, 104(sp)",
+    "sd a5, 112(sp)",
+    "sd a6, 120(sp)",
+    "sd a7, 128(sp)",
+    "sd t3, 216(sp)",
+    "sd t4, 224(sp)",
+    "sd t5, 232(sp)",
+    "sd t6, 240(sp)",
+    // Call the Rust trap handler
+    "call {kerneltrap}",
+    // Restore registers
+    "ld ra, 0(sp)",
+    "ld gp, 16(sp)",
+    // Not tp (contains hartid), in case we moved harts
+    "ld t0, 32(sp)",
+    "ld t1, 40(sp)",
+    "ld t2, 48(sp)",
+    "ld a0, 72(sp)",
+    "ld a1, 80(sp)",
+    "ld a2, 88(sp)",
+    "ld a3, 96(sp)",
+    "ld a4, 104(sp)",
+    "ld a5, 112(sp)",
+    "ld a6, 120(sp)",
+    "ld a7, 128(sp)",
+    "ld t3, 216(sp)",
+    "ld t4, 224(sp)",
+    "ld t5, 232(sp)",
+    "ld t6, 240(sp)",
+    "addi sp, sp, 256",
+    // Return to whatever we were doing in the kernel
+    "sret",
+    kerneltrap = sym kerneltrap,
+);
+
+extern "C" {
+    pub static kernelvec: u8;
+}
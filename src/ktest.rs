@@ -0,0 +1,65 @@
+// Kernel tests
+// `cargo test` builds a kernel whose boot hart starts a kernel thread that
+// runs every #[test_case] in turn, then powers QEMU off with the result
+// Tests run in process context, so they may sleep and start kernel threads
+// A test fails if it panics, or if it leaves pages allocated that it did not
+// have before it started
+
+use crate::kalloc::{leak_mark, report_leaks};
+use crate::memset::VIRT_TEST;
+use crate::proc::kthread;
+use crate::{print, println};
+use core::hint::spin_loop;
+use core::ptr::write_volatile;
+
+// Values the test finisher understands
+const FINISHER_PASS: u32 = 0x5555;
+const FINISHER_FAIL: u32 = 0x3333;
+
+pub enum QemuExit {
+    Passed,
+    Failed,
+}
+
+pub trait Testable {
+    fn run(&self);
+}
+
+impl<T: Fn()> Testable for T {
+    fn run(&self) {
+        print!("{}... ", core::any::type_name::<T>());
+        let mark = leak_mark();
+        self();
+        let leaks = report_leaks(mark);
+        if leaks > 0 {
+            panic!("{} pages leaked", leaks);
+        }
+        println!("ok");
+    }
+}
+
+pub fn runner(tests: &[&dyn Testable]) {
+    println!("running {} tests", tests.len());
+    for test in tests {
+        test.run();
+    }
+    println!("test result: ok. {} passed", tests.len());
+    exit_qemu(QemuExit::Passed);
+}
+
+// Power QEMU off, with an exit status saying whether the tests passed
+pub fn exit_qemu(exit: QemuExit) -> ! {
+    let code = match exit {
+        QemuExit::Passed => FINISHER_PASS,
+        QemuExit::Failed => 1 << 16 | FINISHER_FAIL,
+    };
+    unsafe { write_volatile(VIRT_TEST as *mut u32, code) };
+    loop {
+        spin_loop();
+    }
+}
+
+// Called by init() on hart 0 once the kernel is set up
+pub fn start() {
+    kthread("ktest", |_| crate::test_main(), 0).expect("ktest: no process");
+}
@@ -1,29 +1,77 @@
 #![no_std]
+#![no_main]
+// Subsystems are written ahead of the code that will call them
+#![allow(dead_code)]
+#![feature(alloc_error_handler)]
+#![cfg_attr(test, feature(custom_test_frameworks))]
+#![cfg_attr(test, test_runner(crate::ktest::runner))]
+#![cfg_attr(test, reexport_test_harness_main = "test_main")]
 
+extern crate alloc;
+
+use core::alloc::Layout;
 use core::panic::PanicInfo;
 
+// The CSR access macros must come before the modules that use them
+#[macro_use]
+mod safety;
+
 mod arch;
+mod buddy;
 mod console;
 mod entry;
+mod exec;
+mod fdt;
+mod file;
+mod ipi;
 mod kalloc;
+mod kernelvec;
+#[cfg(test)]
+mod ktest;
 mod memset;
+mod mutex;
+mod plic;
 mod proc;
-mod safety;
+mod programs;
+mod random;
+mod semaphore;
+mod shm;
+mod slab;
 mod sleeplock;
 mod spinlock;
 mod start;
+mod swap;
 mod syscall;
 mod sysproc;
 mod trampoline;
 mod trap;
 mod uart;
+mod virtio;
+mod virtio_disk;
+mod virtio_rng;
 mod vm;
+mod waitqueue;
 
 #[panic_handler]
-fn panic(_info: &PanicInfo) -> ! {
+fn panic(info: &PanicInfo) -> ! {
+    arch::intr_off();
+    console::panic_print(info);
+    #[cfg(test)]
+    ktest::exit_qemu(ktest::QemuExit::Failed);
+    #[cfg(not(test))]
     loop {}
 }
 
-fn main() {
-    start::init();
+#[alloc_error_handler]
+fn alloc_error(layout: Layout) -> ! {
+    panic!(
+        "out of memory: failed to allocate {} bytes (align {})",
+        layout.size(),
+        layout.align()
+    );
+}
+
+// start() jumps here in supervisor mode on every hart
+extern "C" fn main() -> ! {
+    start::init()
 }
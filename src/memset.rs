@@ -1,12 +1,95 @@
+use crate::vm::MAXVA;
+
 pub const KERNEL_BASE_ADDRESS: usize = 0x80000000;
 pub const PHYSICAL_MEMORY_LIMIT: usize = KERNEL_BASE_ADDRESS + 128 * 1024 * 1024;
 
+pub const PGSIZE: usize = 4096; // Bytes per page
+pub const PGSHIFT: usize = 12; // Bits of offset within a page
+pub const NPAGES: usize = (PHYSICAL_MEMORY_LIMIT - KERNEL_BASE_ADDRESS) / PGSIZE; // Pages of RAM
+
+// Index of the RAM page holding pa, for per-page bookkeeping arrays
+pub const fn page_index(pa: usize) -> usize {
+    (pa - KERNEL_BASE_ADDRESS) >> PGSHIFT
+}
+
+pub const fn pg_round_up(addr: usize) -> usize {
+    (addr + PGSIZE - 1) & !(PGSIZE - 1)
+}
+
+pub const fn pg_round_down(addr: usize) -> usize {
+    addr & !(PGSIZE - 1)
+}
+
+// Physical memory layout of QEMU's virt machine
+// 0x00100000 -- test finisher, to power off
+// 0x02000000 -- CLINT
+// 0x0C000000 -- PLIC
+// 0x10000000 -- uart0
+// 0x10001000 -- virtio disk
+// 0x10002000 -- virtio entropy source
+// 0x80000000 -- kernel text and data, then free pages up to PHYSICAL_MEMORY_LIMIT
+pub const VIRT_TEST: usize = 0x0010_0000; // Writes here stop QEMU
+pub const CLINT: usize = 0x0200_0000; // Core local interruptor
+pub const CLINT_SIZE: usize = 0x1_0000;
+
+// Writing 1 to a hart's msip word raises a machine software interrupt on it
+pub const fn clint_msip(hart: usize) -> usize {
+    CLINT + 4 * hart
+}
+pub const PLIC: usize = 0x0c00_0000; // Platform-level interrupt controller
+pub const PLIC_SIZE: usize = 0x400_0000;
+pub const PLIC_PRIORITY: usize = PLIC;
+pub const PLIC_PENDING: usize = PLIC + 0x1000;
+
+// Per-hart supervisor-mode PLIC registers: the interrupts a hart takes,
+// the priority threshold below which it ignores them, and claim/complete
+pub const fn plic_senable(hart: usize) -> usize {
+    PLIC + 0x2080 + hart * 0x100
+}
+pub const fn plic_spriority(hart: usize) -> usize {
+    PLIC + 0x20_1000 + hart * 0x2000
+}
+pub const fn plic_sclaim(hart: usize) -> usize {
+    PLIC + 0x20_1004 + hart * 0x2000
+}
+pub const UART0: usize = 0x1000_0000;
+pub const VIRTIO0: usize = 0x1000_1000;
+pub const VIRTIO1: usize = 0x1000_2000;
+
+// Virtual memory layout
+// The trampoline page is mapped at the highest virtual address,
+// in both user and kernel space
+pub const TRAMPOLINE: usize = MAXVA - PGSIZE;
+
+// Each process's kernel stack sits below the trampoline,
+// with an unmapped guard page underneath to catch overflow
+pub const fn kstack(p: usize) -> usize {
+    TRAMPOLINE - (p + 1) * 2 * PGSIZE
+}
+
+// User memory layout, from address zero (or a random PIE load base):
+//   text, original data and bss, expandable heap,
+//   ..., mmap regions growing down from MMAP_BASE,
+//   ..., user stack growing down from USTACK_TOP,
+//   TRAPFRAME (p.trapframe, used by the trampoline), TRAMPOLINE
+// ASLR moves the load base, heap start, mmap base and stack top by random page counts
+pub const TRAPFRAME: usize = TRAMPOLINE - PGSIZE;
+
+// Top of the area mmap places regions in, leaving room for the stack gap
+pub const MMAP_BASE: usize = MAXVA / 2;
+
+// Top of the user stack, with an unmapped page between it and the trapframe
+pub const USTACK_TOP: usize = TRAPFRAME - PGSIZE;
+
+// Lowest load base for position-independent programs when ASLR is on
+pub const PIE_BASE: usize = 0x10_0000;
+
 #[derive(Debug, Copy, Clone)]
 pub struct ValidAddress(usize);
 
 impl ValidAddress {
     pub fn new(addr: usize) -> Result<Self, &'static str> {
-        if addr >= KERNEL_BASE_ADDRESS && addr < PHYSICAL_MEMORY_LIMIT {
+        if (KERNEL_BASE_ADDRESS..PHYSICAL_MEMORY_LIMIT).contains(&addr) {
             Ok(ValidAddress(addr))
         } else {
             Err("Invalid memory address")
@@ -22,12 +105,9 @@ impl ValidAddress {
 pub struct TimerCompareValue(usize);
 
 impl TimerCompareValue {
+    // Any value will do: one that is already in the past fires at once
     pub fn new(val: usize) -> Result<Self, &'static str> {
-        if val <= usize::MAX {
-            Ok(TimerCompareValue(val))
-        } else {
-            Err("Invalid timer compare value")
-        }
+        Ok(TimerCompareValue(val))
     }
 
     pub fn get(self) -> usize {
@@ -0,0 +1,187 @@
+use crate::proc::{myproc, sleep, wakeup, Proc};
+use crate::spinlock::Spinlock;
+use core::cell::UnsafeCell;
+use core::ops::{Deref, DerefMut};
+use core::ptr::null_mut;
+
+// Sleeping mutual exclusion lock with priority inheritance
+// A waiter that outranks the holder lends the holder its priority until the
+// lock is released, so a low priority holder can't be starved by middle
+// priority work while a high priority process waits on it
+// Inheritance is one level deep; a holder blocked on a second mutex does not
+// pass the boost along, and releasing drops the holder back to its base priority
+pub struct Mutex<T> {
+    inner: Spinlock<MutexState>,
+    name: &'static str,
+    data: UnsafeCell<T>,
+}
+
+struct MutexState {
+    owner: *mut Proc, // Process holding the lock, or null
+}
+
+unsafe impl<T: Send> Sync for Mutex<T> {}
+unsafe impl<T: Send> Send for Mutex<T> {}
+
+impl<T> Mutex<T> {
+    pub const fn new(data: T, name: &'static str) -> Self {
+        Mutex {
+            inner: Spinlock::new(MutexState { owner: null_mut() }, "mutex"),
+            name,
+            data: UnsafeCell::new(data),
+        }
+    }
+
+    // Acquire the lock, boosting the holder while we wait for it
+    // Must be called from process context
+    pub fn lock(&self) -> MutexGuard<'_, T> {
+        let me = myproc().expect("mutex: no process");
+        let mut state = self.inner.lock();
+        while let Some(owner) = unsafe { state.owner.as_mut() } {
+            if core::ptr::eq(owner, me) {
+                panic!("acquire {}", self.name);
+            }
+            owner.inherit_priority(me.priority());
+            state = sleep(self.chan(), state);
+        }
+        state.owner = me;
+        MutexGuard { lock: self }
+    }
+
+    // Acquire the lock only if nobody holds it
+    pub fn try_lock(&self) -> Option<MutexGuard<'_, T>> {
+        let me = myproc().expect("mutex: no process");
+        let mut state = self.inner.lock();
+        if !state.owner.is_null() {
+            return None;
+        }
+        state.owner = me;
+        Some(MutexGuard { lock: self })
+    }
+
+    // Is the current process holding the lock?
+    pub fn holding(&self) -> bool {
+        let state = self.inner.lock();
+        myproc().is_some_and(|p| core::ptr::eq(state.owner, p))
+    }
+
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    // Waiters sleep on the mutex's own address
+    fn chan(&self) -> usize {
+        self as *const Self as usize
+    }
+
+    fn release(&self) {
+        let mut state = self.inner.lock();
+        if let Some(owner) = unsafe { state.owner.as_mut() } {
+            owner.restore_priority();
+        }
+        state.owner = null_mut();
+        // Wake everyone, the scheduler runs the highest priority waiter first
+        wakeup(self.chan());
+    }
+}
+
+pub struct MutexGuard<'a, T> {
+    lock: &'a Mutex<T>,
+}
+
+impl<T> Deref for MutexGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<T> DerefMut for MutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.lock.data.get() }
+    }
+}
+
+impl<T> Drop for MutexGuard<'_, T> {
+    fn drop(&mut self) {
+        self.lock.release();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::proc::{kthread, kthread_wait, yield_, NCPU};
+    use crate::trap::ticks;
+    use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+    const LOW: usize = 1;
+    const MEDIUM: usize = 5;
+    const HIGH: usize = 9;
+    // Clock ticks the medium priority hogs run for if nothing stops them
+    const HOG_TICKS: usize = 50;
+
+    static SHARED: Mutex<()> = Mutex::new((), "shared");
+    static LOCKED: AtomicBool = AtomicBool::new(false);
+    static DONE: AtomicBool = AtomicBool::new(false);
+    static TIMED_OUT: AtomicBool = AtomicBool::new(false);
+    static BOOSTED: AtomicUsize = AtomicUsize::new(0);
+
+    fn me() -> &'static mut Proc {
+        myproc().unwrap()
+    }
+
+    // Holds the mutex, then needs the hart for a while before releasing it
+    fn low(_: usize) {
+        me().set_priority(LOW);
+        let guard = SHARED.lock();
+        LOCKED.store(true, Ordering::Release);
+        for _ in 0..20 {
+            yield_();
+        }
+        BOOSTED.store(me().priority(), Ordering::Relaxed);
+        drop(guard);
+        assert_eq!(me().priority(), LOW);
+    }
+
+    // Keeps every hart busy until the high priority process gets the mutex
+    fn medium(_: usize) {
+        me().set_priority(MEDIUM);
+        let deadline = ticks() + HOG_TICKS;
+        while !DONE.load(Ordering::Acquire) {
+            if ticks() > deadline {
+                TIMED_OUT.store(true, Ordering::Relaxed);
+                return;
+            }
+            yield_();
+        }
+    }
+
+    fn high(_: usize) {
+        me().set_priority(HIGH);
+        drop(SHARED.lock());
+        DONE.store(true, Ordering::Release);
+    }
+
+    // Without inheritance the low priority holder never runs while the hogs
+    // are runnable, so the high priority waiter only gets the mutex once
+    // they give up
+    #[test_case]
+    fn inversion() {
+        let low = kthread("low", low, 0).unwrap();
+        while !LOCKED.load(Ordering::Acquire) {
+            yield_();
+        }
+        let hogs: [usize; NCPU] = core::array::from_fn(|_| kthread("medium", medium, 0).unwrap());
+        let high = kthread("high", high, 0).unwrap();
+
+        kthread_wait(high);
+        for pid in hogs {
+            kthread_wait(pid);
+        }
+        kthread_wait(low);
+        assert!(!TIMED_OUT.load(Ordering::Relaxed));
+        assert_eq!(BOOSTED.load(Ordering::Relaxed), HIGH);
+    }
+}
@@ -0,0 +1,35 @@
+// The RISC-V Platform Level Interrupt Controller (PLIC)
+// Routes device interrupts to the harts, as supervisor external interrupts
+
+use crate::arch::read_threadptr;
+use crate::memset::{plic_sclaim, plic_senable, plic_spriority, PLIC_PRIORITY};
+use crate::uart::UART0_IRQ;
+use crate::virtio_disk::VIRTIO0_IRQ;
+use core::ptr::{read_volatile, write_volatile};
+
+fn write_reg(addr: usize, val: u32) {
+    unsafe { write_volatile(addr as *mut u32, val) }
+}
+
+// Set desired IRQ priorities non-zero (otherwise disabled)
+pub fn plicinit() {
+    write_reg(PLIC_PRIORITY + UART0_IRQ * 4, 1);
+    write_reg(PLIC_PRIORITY + VIRTIO0_IRQ * 4, 1);
+}
+
+// Set this hart's enable bits and priority threshold for supervisor mode
+pub fn plicinithart() {
+    let hart = read_threadptr();
+    write_reg(plic_senable(hart), 1 << UART0_IRQ | 1 << VIRTIO0_IRQ);
+    write_reg(plic_spriority(hart), 0);
+}
+
+// Ask the PLIC what interrupt we should serve, 0 if none
+pub fn plic_claim() -> usize {
+    unsafe { read_volatile(plic_sclaim(read_threadptr()) as *const u32) as usize }
+}
+
+// Tell the PLIC we've served this IRQ
+pub fn plic_complete(irq: usize) {
+    write_reg(plic_sclaim(read_threadptr()), irq as u32);
+}
@@ -0,0 +1,676 @@
+use crate::arch::{intr_get, intr_on, read_threadptr};
+use crate::exec::exec;
+use crate::file::{MemFile, NOFILE};
+use crate::kalloc::{kalloc, kfree, kzalloc, PagePurpose};
+use crate::memset::{kstack, PGSIZE};
+use crate::println;
+use crate::spinlock::{pop_off, push_off, Spinlock, SpinlockGuard};
+use crate::trap::usertrapret;
+use crate::vm::{PageTable, PteFlags, UserAddressSpace};
+use alloc::sync::Arc;
+use core::arch::global_asm;
+use core::ptr::{addr_of_mut, null_mut};
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+pub const NCPU: usize = 8; // Maximum number of harts
+pub const NPROC: usize = 64; // Maximum number of processes
+pub const DEFAULT_PRIORITY: usize = 10; // Higher values are scheduled first
+
+// Saved registers for kernel context switches
+#[repr(C)]
+#[derive(Copy, Clone)]
+pub struct Context {
+    pub ra: usize,
+    pub sp: usize,
+    // callee-saved
+    pub s: [usize; 12],
+}
+
+impl Context {
+    pub const fn new() -> Self {
+        Context {
+            ra: 0,
+            sp: 0,
+            s: [0; 12],
+        }
+    }
+}
+
+// Per-process data for the trap handling code in trampoline.rs
+// Sits in a page by itself just under the trampoline page in the user page table,
+// not specially mapped in the kernel page table
+// uservec saves user registers here, then initialises registers from
+// kernel_sp, kernel_hartid and kernel_satp and jumps to kernel_trap
+// usertrapret() and userret set up kernel_* and restore user registers
+#[repr(C)]
+#[derive(Copy, Clone, Default)]
+pub struct TrapFrame {
+    pub kernel_satp: usize,   // 0   Kernel page table
+    pub kernel_sp: usize,     // 8   Top of process's kernel stack
+    pub kernel_trap: usize,   // 16  usertrap()
+    pub epc: usize,           // 24  Saved user program counter
+    pub kernel_hartid: usize, // 32  Saved kernel tp
+    pub ra: usize,            // 40
+    pub sp: usize,            // 48
+    pub gp: usize,            // 56
+    pub tp: usize,            // 64
+    pub t0: usize,            // 72
+    pub t1: usize,            // 80
+    pub t2: usize,            // 88
+    pub s0: usize,            // 96
+    pub s1: usize,            // 104
+    pub a0: usize,            // 112
+    pub a1: usize,            // 120
+    pub a2: usize,            // 128
+    pub a3: usize,            // 136
+    pub a4: usize,            // 144
+    pub a5: usize,            // 152
+    pub a6: usize,            // 160
+    pub a7: usize,            // 168
+    pub s2: usize,            // 176
+    pub s3: usize,            // 184
+    pub s4: usize,            // 192
+    pub s5: usize,            // 200
+    pub s6: usize,            // 208
+    pub s7: usize,            // 216
+    pub s8: usize,            // 224
+    pub s9: usize,            // 232
+    pub s10: usize,           // 240
+    pub s11: usize,           // 248
+    pub t3: usize,            // 256
+    pub t4: usize,            // 264
+    pub t5: usize,            // 272
+    pub t6: usize,            // 280
+    pub tlb_flushes: usize,   // 288 Whole-TLB flushes by uservec and userret, for leave()
+}
+
+// Per-hart state
+pub struct Cpu {
+    pub proc: *mut Proc,  // The process running on this hart, or null
+    pub context: Context, // swtch() here to enter scheduler()
+    pub noff: usize,      // Depth of push_off() nesting
+    pub intena: bool,     // Were interrupts enabled before push_off()?
+}
+
+impl Cpu {
+    const fn new() -> Self {
+        Cpu {
+            proc: null_mut(),
+            context: Context::new(),
+            noff: 0,
+            intena: false,
+        }
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum ProcState {
+    Unused,
+    Used,
+    Sleeping,
+    Runnable,
+    Running,
+    Zombie,
+}
+
+// Per-process state
+pub struct Proc {
+    pub lock: Spinlock<()>,
+
+    // p.lock must be held when using these
+    pub state: ProcState,
+    pub chan: usize,             // If non-zero, sleeping on chan
+    pub deadline: Option<usize>, // If set, woken by the clock at this tick
+    pub killed: bool,            // If true, have been killed
+    pub xstate: i32,             // Exit status to be returned to wait_pid()
+    pub pid: usize,
+    pub base_priority: usize, // Priority the process asked for
+    pub priority: usize,      // Effective priority, raised while a waiter inherits through us
+
+    // Private to the process, p.lock need not be held
+    pub kstack: usize,                         // Virtual address of kernel stack
+    pub trapframe: *mut TrapFrame,             // Data page for trampoline.rs
+    pub aspace: Option<UserAddressSpace>,      // User page table and memory
+    pub ofile: [Option<Arc<MemFile>>; NOFILE], // Open files
+    pub context: Context,                      // swtch() here to run process
+    pub name: [u8; 16],
+}
+
+impl Proc {
+    const fn new() -> Self {
+        Proc {
+            lock: Spinlock::new((), "proc"),
+            state: ProcState::Unused,
+            chan: 0,
+            deadline: None,
+            killed: false,
+            xstate: 0,
+            pid: 0,
+            base_priority: DEFAULT_PRIORITY,
+            priority: DEFAULT_PRIORITY,
+            kstack: 0,
+            trapframe: null_mut(),
+            aspace: None,
+            ofile: [const { None }; NOFILE],
+            context: Context::new(),
+            name: [0; 16],
+        }
+    }
+
+    // Process name for diagnostics
+    pub fn name(&self) -> &str {
+        let len = self
+            .name
+            .iter()
+            .position(|&c| c == 0)
+            .unwrap_or(self.name.len());
+        core::str::from_utf8(&self.name[..len]).unwrap_or("???")
+    }
+
+    pub fn is_killed(&self) -> bool {
+        let _guard = self.lock.lock();
+        self.killed
+    }
+
+    pub fn set_killed(&mut self) {
+        let _guard = self.lock.lock();
+        self.killed = true;
+    }
+
+    pub fn priority(&self) -> usize {
+        let _guard = self.lock.lock();
+        self.priority
+    }
+
+    // Change the base priority, keeping any boost currently inherited
+    pub fn set_priority(&mut self, priority: usize) {
+        let _guard = self.lock.lock();
+        let boosted = self.priority > self.base_priority;
+        self.base_priority = priority;
+        if !boosted || priority > self.priority {
+            self.priority = priority;
+        }
+    }
+
+    // Run at least at priority until restore_priority()
+    pub fn inherit_priority(&mut self, priority: usize) {
+        let _guard = self.lock.lock();
+        if priority > self.priority {
+            self.priority = priority;
+        }
+    }
+
+    // Drop any inherited boost
+    pub fn restore_priority(&mut self) {
+        let _guard = self.lock.lock();
+        self.priority = self.base_priority;
+    }
+}
+
+static mut CPUS: [Cpu; NCPU] = [const { Cpu::new() }; NCPU];
+static mut PROCS: [Proc; NPROC] = [const { Proc::new() }; NPROC];
+
+static NEXT_PID: AtomicUsize = AtomicUsize::new(1);
+
+// Slot where the scheduler's next scan starts
+static NEXT_SLOT: AtomicUsize = AtomicUsize::new(0);
+
+// Held while a process exits, so kthread_wait() and wait_pid() can't miss the wakeup
+static WAIT_LOCK: Spinlock<()> = Spinlock::new((), "wait_lock");
+
+// Save current registers in old, load from new
+global_asm!(
+    ".globl swtch",
+    "swtch:",
+    "sd ra, 0(a0)",
+    "sd sp, 8(a0)",
+    "sd s0, 16(a0)",
+    "sd s1, 24(a0)",
+    "sd s2, 32(a0)",
+    "sd s3, 40(a0)",
+    "sd s4, 48(a0)",
+    "sd s5, 56(a0)",
+    "sd s6, 64(a0)",
+    "sd s7, 72(a0)",
+    "sd s8, 80(a0)",
+    "sd s9, 88(a0)",
+    "sd s10, 96(a0)",
+    "sd s11, 104(a0)",
+    "ld ra, 0(a1)",
+    "ld sp, 8(a1)",
+    "ld s0, 16(a1)",
+    "ld s1, 24(a1)",
+    "ld s2, 32(a1)",
+    "ld s3, 40(a1)",
+    "ld s4, 48(a1)",
+    "ld s5, 56(a1)",
+    "ld s6, 64(a1)",
+    "ld s7, 72(a1)",
+    "ld s8, 80(a1)",
+    "ld s9, 88(a1)",
+    "ld s10, 96(a1)",
+    "ld s11, 104(a1)",
+    "ret",
+);
+
+// A new kernel thread's first return from scheduler() lands here,
+// with the function to run and its argument in s0 and s1
+global_asm!(
+    ".globl kthread_start",
+    "kthread_start:",
+    "mv a0, s0",
+    "mv a1, s1",
+    "call {main}",
+    main = sym kthread_main,
+);
+
+extern "C" {
+    fn swtch(old: *mut Context, new: *const Context);
+    fn kthread_start();
+}
+
+// Return this hart's cpu struct
+// Interrupts must be disabled to prevent a race with the process being moved to a different hart
+pub fn mycpu() -> &'static mut Cpu {
+    unsafe { &mut *addr_of_mut!(CPUS[read_threadptr()]) }
+}
+
+// Return the current process, or None if the hart is in the scheduler
+pub fn myproc() -> Option<&'static mut Proc> {
+    push_off();
+    let p = mycpu().proc;
+    pop_off();
+    unsafe { p.as_mut() }
+}
+
+// Iterate over every slot in the process table
+pub fn procs() -> impl Iterator<Item = &'static mut Proc> {
+    (0..NPROC).map(|i| unsafe { &mut *addr_of_mut!(PROCS[i]) })
+}
+
+// Allocate a page for each process's kernel stack
+// Map it high in memory, followed by an invalid guard page
+pub fn proc_mapstacks(kpgtbl: &mut PageTable) {
+    for (i, p) in procs().enumerate() {
+        let pa = kalloc(PagePurpose::KernelStack).expect("proc_mapstacks: out of memory");
+        let va = kstack(i);
+        if let Err(e) = kpgtbl.map_pages(va, PGSIZE, pa, PteFlags::R | PteFlags::W) {
+            panic!("proc_mapstacks {:#x}: {:?}", va, e);
+        }
+        p.kstack = va;
+    }
+}
+
+// Find an unused process slot and make it a runnable process named name
+// setup() fills in where the new context starts, with p.lock held
+// Returns the new pid
+fn allocproc(name: &str, setup: impl FnOnce(&mut Proc)) -> Result<usize, &'static str> {
+    for p in procs() {
+        let pp = p as *mut Proc;
+        let _guard = p.lock.lock();
+        if p.state != ProcState::Unused {
+            continue;
+        }
+        p.pid = NEXT_PID.fetch_add(1, Ordering::Relaxed);
+        p.chan = 0;
+        p.deadline = None;
+        p.killed = false;
+        p.xstate = 0;
+        p.base_priority = DEFAULT_PRIORITY;
+        p.priority = DEFAULT_PRIORITY;
+        p.name = name_bytes(name);
+
+        // The new context runs on the process's kernel stack
+        p.context = Context::new();
+        p.context.sp = p.kstack + PGSIZE;
+        setup(unsafe { &mut *pp });
+
+        p.state = ProcState::Runnable;
+        return Ok(p.pid);
+    }
+    Err("out of processes")
+}
+
+// Start a kernel thread that runs f(arg) in a process of its own
+// until f returns. Returns its pid
+pub fn kthread(name: &str, f: fn(usize), arg: usize) -> Result<usize, &'static str> {
+    allocproc(name, |p| {
+        // Start executing at kthread_start, which calls kthread_main()
+        p.context.ra = kthread_start as unsafe extern "C" fn() as usize;
+        p.context.s[0] = f as usize;
+        p.context.s[1] = arg;
+    })
+}
+
+// Start a user process running the ELF program in image
+// Returns its pid, for wait_pid()
+pub fn spawn(name: &str, image: &[u8]) -> Result<usize, &'static str> {
+    let trapframe = kzalloc(PagePurpose::Other).ok_or("spawn: out of memory")?;
+    let program = match exec(image, trapframe) {
+        Ok(program) => program,
+        Err(e) => {
+            kfree(trapframe);
+            return Err(e);
+        }
+    };
+    let tf = trapframe as *mut TrapFrame;
+    unsafe {
+        (*tf).epc = program.entry;
+        (*tf).sp = program.sp;
+    }
+
+    let aspace = program.aspace;
+    allocproc(name, move |p| {
+        // Start executing at forkret, which returns to user space
+        p.context.ra = forkret as extern "C" fn() -> ! as usize;
+        p.trapframe = tf;
+        p.aspace = Some(aspace);
+    })
+    // The address space went with the closure, so the trapframe is unmapped
+    .inspect_err(|_| kfree(trapframe))
+}
+
+// A process name as stored in p.name, truncated and NUL-padded
+fn name_bytes(name: &str) -> [u8; 16] {
+    let mut bytes = [0; 16];
+    let len = name.len().min(bytes.len() - 1);
+    bytes[..len].copy_from_slice(&name.as_bytes()[..len]);
+    bytes
+}
+
+// A new user process's very first scheduling by scheduler() switches here
+extern "C" fn forkret() -> ! {
+    // Still holding p.lock from scheduler()
+    let p = myproc().expect("forkret: no process");
+    unsafe { p.lock.force_unlock() };
+    usertrapret()
+}
+
+extern "C" fn kthread_main(f: usize, arg: usize) -> ! {
+    // Still holding p.lock from scheduler()
+    let p = myproc().expect("kthread_main: no process");
+    unsafe { p.lock.force_unlock() };
+
+    let f: fn(usize) = unsafe { core::mem::transmute(f) };
+    f(arg);
+    kthread_exit()
+}
+
+// Exit the current kernel thread. Its slot is free as soon as the
+// scheduler has switched away from it
+pub fn kthread_exit() -> ! {
+    let p = myproc().expect("kthread_exit: no process");
+    let wait = WAIT_LOCK.lock();
+    wakeup(&WAIT_LOCK as *const _ as usize);
+
+    let _guard = p.lock.lock();
+    p.state = ProcState::Unused;
+    drop(wait);
+
+    // Jump into the scheduler, never to return
+    sched();
+    panic!("zombie exit");
+}
+
+// Exit the current user process. It stays a zombie, holding on to its slot
+// and status, until wait_pid() collects it
+pub fn exit(status: i32) -> ! {
+    let p = myproc().expect("exit: no process");
+
+    // Free user memory, then the trapframe page it mapped
+    drop(p.aspace.take());
+    kfree(p.trapframe as usize);
+    p.trapframe = null_mut();
+
+    // Close open files, once mappings of them have been written back
+    p.ofile.iter_mut().for_each(|f| drop(f.take()));
+
+    let wait = WAIT_LOCK.lock();
+    wakeup(&WAIT_LOCK as *const _ as usize);
+
+    let _guard = p.lock.lock();
+    p.xstate = status;
+    p.state = ProcState::Zombie;
+    drop(wait);
+
+    // Jump into the scheduler, never to return
+    sched();
+    panic!("zombie exit");
+}
+
+// Wait for the user process pid to exit, free its slot and return its exit status
+pub fn wait_pid(pid: usize) -> Result<i32, &'static str> {
+    let mut guard = WAIT_LOCK.lock();
+    loop {
+        let mut found = false;
+        for p in procs() {
+            let _guard = p.lock.lock();
+            if p.pid != pid || p.state == ProcState::Unused {
+                continue;
+            }
+            if p.state == ProcState::Zombie {
+                p.state = ProcState::Unused;
+                return Ok(p.xstate);
+            }
+            found = true;
+        }
+        if !found {
+            return Err("no such process");
+        }
+        guard = sleep(&WAIT_LOCK as *const _ as usize, guard);
+    }
+}
+
+// Wait for the kernel thread pid to exit
+pub fn kthread_wait(pid: usize) {
+    let mut guard = WAIT_LOCK.lock();
+    while procs().any(|p| {
+        let _guard = p.lock.lock();
+        p.pid == pid && p.state != ProcState::Unused
+    }) {
+        guard = sleep(&WAIT_LOCK as *const _ as usize, guard);
+    }
+}
+
+// Per-hart process scheduler
+// Each hart calls scheduler() after setting itself up and never returns
+// It loops, choosing a runnable process, switching to it, and regaining
+// control when the process calls sched()
+pub fn scheduler() -> ! {
+    let cpu = mycpu();
+    cpu.proc = null_mut();
+    loop {
+        // The most recent process to run may have had interrupts turned off
+        // Enable them to avoid a deadlock if all processes are waiting
+        intr_on();
+
+        // Pick the highest priority runnable process. The scan starts just past
+        // the slot picked last time, so processes of equal priority take turns
+        let first = NEXT_SLOT.load(Ordering::Relaxed);
+        let mut best: Option<(usize, usize)> = None;
+        for i in (0..NPROC).map(|n| (first + n) % NPROC) {
+            let p = unsafe { &mut *addr_of_mut!(PROCS[i]) };
+            let _guard = p.lock.lock();
+            if p.state == ProcState::Runnable && best.is_none_or(|(prio, _)| p.priority > prio) {
+                best = Some((p.priority, i));
+            }
+        }
+
+        if let Some((_, i)) = best {
+            let p = unsafe { &mut *addr_of_mut!(PROCS[i]) };
+            let pp = p as *mut Proc;
+            let _guard = p.lock.lock();
+            // Recheck, it may have been picked by another hart since the scan
+            if p.state == ProcState::Runnable {
+                NEXT_SLOT.store((i + 1) % NPROC, Ordering::Relaxed);
+                // Switch to chosen process. It is the process's job to release
+                // its lock and then reacquire it before jumping back to us
+                p.state = ProcState::Running;
+                cpu.proc = pp;
+                unsafe { swtch(&mut cpu.context, &p.context) };
+                // Process is done running for now
+                cpu.proc = null_mut();
+            }
+        }
+    }
+}
+
+// Switch to scheduler. Must hold only p.lock and have changed p.state
+// Saves and restores intena because intena is a property of this
+// kernel thread, not this hart
+pub fn sched() {
+    let p = myproc().expect("sched: no process");
+    let cpu = mycpu();
+
+    if !p.lock.holding() {
+        panic!("sched p.lock");
+    }
+    if cpu.noff != 1 {
+        panic!("sched locks");
+    }
+    if p.state == ProcState::Running {
+        panic!("sched running");
+    }
+    if intr_get() {
+        panic!("sched interruptible");
+    }
+
+    let intena = cpu.intena;
+    unsafe { swtch(&mut p.context, &mycpu().context) };
+    mycpu().intena = intena;
+}
+
+// Give up the hart for one scheduling round
+pub fn yield_() {
+    let p = myproc().expect("yield: no process");
+    let _guard = p.lock.lock();
+    p.state = ProcState::Runnable;
+    sched();
+}
+
+// Atomically release the lock held by guard and sleep on chan
+// Reacquires the lock when awakened and hands back the new guard
+pub fn sleep<'a, T>(chan: usize, guard: SpinlockGuard<'a, T>) -> SpinlockGuard<'a, T> {
+    sleep_until(chan, guard, None)
+}
+
+// Like sleep(), but the clock also wakes the process once ticks reaches deadline
+// Callers must recheck their condition; waking says nothing about why
+pub fn sleep_until<'a, T>(
+    chan: usize,
+    guard: SpinlockGuard<'a, T>,
+    deadline: Option<usize>,
+) -> SpinlockGuard<'a, T> {
+    let p = myproc().expect("sleep: no process");
+    let lk = guard.spinlock();
+
+    // Must acquire p.lock in order to change p.state and then call sched
+    // Once we hold p.lock, we can be guaranteed that we won't miss any wakeup
+    // (wakeup locks p.lock), so it's okay to release the caller's lock
+    let plock = p.lock.lock();
+    drop(guard);
+
+    p.chan = chan;
+    p.deadline = deadline;
+    p.state = ProcState::Sleeping;
+
+    sched();
+
+    p.chan = 0;
+    p.deadline = None;
+
+    drop(plock);
+    lk.lock()
+}
+
+// Wake up all processes sleeping on chan
+// Must be called without any p.lock
+pub fn wakeup(chan: usize) {
+    let me = myproc().map_or(null_mut(), |p| p as *mut Proc);
+    for p in procs() {
+        if core::ptr::eq(p, me) {
+            continue;
+        }
+        let _guard = p.lock.lock();
+        if p.state == ProcState::Sleeping && p.chan == chan {
+            p.state = ProcState::Runnable;
+        }
+    }
+}
+
+// Wake up the first process sleeping on chan
+// Returns false if nobody was waiting
+pub fn wakeup_one(chan: usize) -> bool {
+    let me = myproc().map_or(null_mut(), |p| p as *mut Proc);
+    for p in procs() {
+        if core::ptr::eq(p, me) {
+            continue;
+        }
+        let _guard = p.lock.lock();
+        if p.state == ProcState::Sleeping && p.chan == chan {
+            p.state = ProcState::Runnable;
+            return true;
+        }
+    }
+    false
+}
+
+// Wake every sleeper whose deadline has passed
+// Called from the clock interrupt
+pub fn wakeup_expired(now: usize) {
+    for p in procs() {
+        let _guard = p.lock.lock();
+        if p.state == ProcState::Sleeping && p.deadline.is_some_and(|d| d <= now) {
+            p.state = ProcState::Runnable;
+        }
+    }
+}
+
+// Is process slot i the one running on this hart?
+pub fn is_myproc(i: usize) -> bool {
+    myproc().is_some_and(|p| core::ptr::eq(p, unsafe { &*addr_of_mut!(PROCS[i]) }))
+}
+
+// Run f on the address space of the process in slot i, if it is waiting to run
+// or sleeping, with p.lock held throughout so it cannot start running meanwhile
+// f must not sleep or wait for other harts (as with a TLB shootdown)
+pub fn with_idle_aspace(i: usize, f: impl FnOnce(&mut UserAddressSpace)) {
+    let p = unsafe { &mut *addr_of_mut!(PROCS[i]) };
+    let _guard = p.lock.lock();
+    if matches!(p.state, ProcState::Runnable | ProcState::Sleeping) {
+        if let Some(aspace) = p.aspace.as_mut() {
+            f(aspace);
+        }
+    }
+}
+
+// Print the address space of process pid on the console
+pub fn vmprint(pid: usize) -> Result<(), &'static str> {
+    for p in procs() {
+        let _guard = p.lock.lock();
+        if p.pid == pid && p.state != ProcState::Unused {
+            let aspace = p.aspace.as_ref().ok_or("no address space")?;
+            println!("pid {} {}", p.pid, p.name());
+            aspace.vmprint();
+            return Ok(());
+        }
+    }
+    Err("no such process")
+}
+
+// Kill the process with the given pid
+// The victim won't exit until it tries to return to user space,
+// but a sleeping victim is woken so its wait can be interrupted
+pub fn kill(pid: usize) -> Result<(), &'static str> {
+    for p in procs() {
+        let _guard = p.lock.lock();
+        if p.pid == pid && p.state != ProcState::Unused {
+            p.killed = true;
+            if p.state == ProcState::Sleeping {
+                p.state = ProcState::Runnable;
+            }
+            return Ok(());
+        }
+    }
+    Err("No such process")
+}
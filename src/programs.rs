@@ -0,0 +1,140 @@
+// User programs built into the kernel, until there is a file system to load them from
+// Each is a minimal position-independent ELF image: the file header, then one
+// read/execute segment holding the whole file, then the code, entered at its start
+
+use crate::syscall::{SYS_EXIT, SYS_SETRLIMIT, SYS_YIELD};
+use crate::sysproc::RLIMIT_STACK;
+use core::arch::global_asm;
+use core::ptr::addr_of;
+use core::slice;
+
+// Bytes of ELF file header and program header before the code
+const CODE_OFFSET: usize = 64 + 56;
+
+global_asm!(
+    // elf_program name: emit the headers for a program labelled name,
+    // whose image ends at name_end
+    ".macro elf_program name",
+    ".balign 8",
+    "\\name:",
+    // File header: ELF64, little-endian, position independent, RISC-V
+    ".byte 0x7f, 'E', 'L', 'F', 2, 1, 1, 0",
+    ".zero 8",
+    ".half 3, 243",
+    ".word 1",
+    // Entry point, program header offset, no section headers
+    ".quad {code}, 64, 0",
+    // Flags: compressed instructions, double-precision float ABI
+    ".word 5",
+    ".half 64, 56, 1, 64, 0, 0",
+    // Program header: one PT_LOAD of the whole file, readable and executable
+    ".word 1, 5",
+    ".quad 0, 0, 0",
+    ".quad \\name\\()_end - \\name, \\name\\()_end - \\name",
+    ".quad 4096",
+    ".endm",
+    ".pushsection .rodata.programs, \"a\"",
+    ".option push",
+    ".option norelax",
+    // stack: recurse 64 frames of a page each, growing the stack on demand,
+    // and check every frame is still there on the way back up
+    // Exits with 0 if the sum of the frames' depths comes out right
+    "elf_program stack_program",
+    "li a0, 64",
+    "jal ra, 1f",
+    "li t0, 64 * 65 / 2",
+    "sub a0, a0, t0",
+    "snez a0, a0",
+    "li a7, {sys_exit}",
+    "ecall",
+    // depth(n) = n + depth(n - 1), in a page-sized frame
+    "1:",
+    "li t0, 4096",
+    "sub sp, sp, t0",
+    "sd ra, 0(sp)",
+    "sd a0, 8(sp)",
+    "beqz a0, 2f",
+    "addi a0, a0, -1",
+    "jal ra, 1b",
+    "ld t1, 8(sp)",
+    "add a0, a0, t1",
+    "2:",
+    "ld ra, 0(sp)",
+    "li t0, 4096",
+    "add sp, sp, t0",
+    "ret",
+    "stack_program_end:",
+    // overflow: lower the stack rlimit to 64 KiB, then push pages until the
+    // stack runs out and the kernel kills the process
+    "elf_program overflow_program",
+    "li a0, {rlimit_stack}",
+    "li a1, 64 * 1024",
+    "li a7, {sys_setrlimit}",
+    "ecall",
+    "1:",
+    "li t0, 4096",
+    "sub sp, sp, t0",
+    "sd zero, 0(sp)",
+    "j 1b",
+    "overflow_program_end:",
+    // switch: 100 times over, write to 16 pages of stack and yield, so that
+    // harts switch address spaces often and the TLB has something to keep
+    "elf_program switch_program",
+    "li t0, 16 * 4096",
+    "sub sp, sp, t0",
+    "li s0, 100",
+    "1:",
+    "li t1, 16",
+    "mv t2, sp",
+    "2:",
+    "sd t1, 0(t2)",
+    "li t0, 4096",
+    "add t2, t2, t0",
+    "addi t1, t1, -1",
+    "bnez t1, 2b",
+    "li a7, {sys_yield}",
+    "ecall",
+    "addi s0, s0, -1",
+    "bnez s0, 1b",
+    "li a0, 0",
+    "li a7, {sys_exit}",
+    "ecall",
+    "switch_program_end:",
+    ".option pop",
+    ".popsection",
+    code = const CODE_OFFSET,
+    sys_exit = const SYS_EXIT,
+    sys_setrlimit = const SYS_SETRLIMIT,
+    sys_yield = const SYS_YIELD,
+    rlimit_stack = const RLIMIT_STACK,
+);
+
+extern "C" {
+    static stack_program: u8;
+    static stack_program_end: u8;
+    static overflow_program: u8;
+    static overflow_program_end: u8;
+    static switch_program: u8;
+    static switch_program_end: u8;
+}
+
+// The image between two labels
+fn image(start: *const u8, end: *const u8) -> &'static [u8] {
+    unsafe { slice::from_raw_parts(start, end as usize - start as usize) }
+}
+
+// The ELF image of the built-in program called name
+pub fn program(name: &str) -> Option<&'static [u8]> {
+    match name {
+        "stack" => Some(image(addr_of!(stack_program), addr_of!(stack_program_end))),
+        "overflow" => Some(image(
+            addr_of!(overflow_program),
+            addr_of!(overflow_program_end),
+        )),
+        "switch" => Some(image(
+            addr_of!(switch_program),
+            addr_of!(switch_program_end),
+        )),
+        _ => None,
+    }
+}
@@ -0,0 +1,97 @@
+// Kernel entropy pool
+// Timing jitter in mcycle, the device tree's rng-seed and bytes from the
+// virtio entropy device are mixed into a small pool, and random numbers are
+// drawn from a xoshiro256** generator running on the pool state.
+// Good enough to make address space layouts unpredictable, not for cryptography
+
+use crate::arch::read_mcycle;
+use crate::spinlock::Spinlock;
+use core::hint::spin_loop;
+
+// Cycle-counter samples taken at boot
+const JITTER_SAMPLES: usize = 256;
+
+struct Pool {
+    state: [u64; 4],
+    mixed: usize, // Values mixed in so far
+}
+
+static POOL: Spinlock<Pool> = Spinlock::new(
+    Pool {
+        // Arbitrary nonzero start, so the generator works even before seeding
+        state: [
+            0x243f_6a88_85a3_08d3,
+            0x1319_8a2e_0370_7344,
+            0xa409_3822_299f_31d0,
+            0x082e_fa98_ec4e_6c89,
+        ],
+        mixed: 0,
+    },
+    "entropy",
+);
+
+// SplitMix64 step, to spread the bits of each input over a whole word
+fn splitmix64(x: u64) -> u64 {
+    let mut z = x.wrapping_add(0x9e37_79b9_7f4a_7c15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
+}
+
+// Mix value into the pool
+pub fn add_entropy(value: u64) {
+    let mut pool = POOL.lock();
+    let i = pool.mixed % pool.state.len();
+    pool.state[i] = splitmix64(pool.state[i] ^ value);
+    pool.mixed += 1;
+}
+
+// Mix bytes from a hardware source into the pool, such as the rng-seed
+// that QEMU passes in the device tree or the output of virtio-rng
+pub fn add_entropy_bytes(bytes: &[u8]) {
+    for chunk in bytes.chunks(8) {
+        let mut word = [0u8; 8];
+        word[..chunk.len()].copy_from_slice(chunk);
+        add_entropy(u64::from_le_bytes(word));
+    }
+}
+
+// Seed the pool from jitter in the cycle counter: how long a short busy loop
+// takes varies with caches, the memory system and interrupts
+// Called from start() in machine mode on every hart, where mcycle is readable
+pub fn entropy_init() {
+    let mut spins = 0;
+    for _ in 0..JITTER_SAMPLES {
+        let t0 = read_mcycle();
+        for _ in 0..spins {
+            spin_loop();
+        }
+        let t1 = read_mcycle();
+        let delta = t1.wrapping_sub(t0);
+        add_entropy(((delta as u64) << 32) ^ t1 as u64);
+        spins = delta & 0xff;
+    }
+}
+
+// A random 64-bit number
+pub fn random() -> u64 {
+    let mut pool = POOL.lock();
+    let s = &mut pool.state;
+    let result = s[1].wrapping_mul(5).rotate_left(7).wrapping_mul(9);
+    let t = s[1] << 17;
+    s[2] ^= s[0];
+    s[3] ^= s[1];
+    s[1] ^= s[2];
+    s[0] ^= s[3];
+    s[2] ^= t;
+    s[3] = s[3].rotate_left(45);
+    result
+}
+
+// A random number below 2^bits
+pub fn random_bits(bits: usize) -> usize {
+    if bits == 0 {
+        return 0;
+    }
+    (random() >> (64 - bits.min(64))) as usize
+}
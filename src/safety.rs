@@ -34,3 +34,31 @@ macro_rules! write_csr {
         }
     }};
 }
+
+// Set bits in a CSR register, leaving the others untouched
+macro_rules! set_csr {
+    ($csr:expr, $val:expr) => {{
+        unsafe {
+            asm!(
+                "csrs {0}, {1}",
+                const $csr,
+                in(reg) $val as usize,
+                options(nostack, preserves_flags)
+            );
+        }
+    }};
+}
+
+// Clear bits in a CSR register, leaving the others untouched
+macro_rules! clear_csr {
+    ($csr:expr, $val:expr) => {{
+        unsafe {
+            asm!(
+                "csrc {0}, {1}",
+                const $csr,
+                in(reg) $val as usize,
+                options(nostack, preserves_flags)
+            );
+        }
+    }};
+}
@@ -0,0 +1,135 @@
+use crate::spinlock::Spinlock;
+use crate::waitqueue::{WaitError, WaitQueue};
+
+// Counting semaphore for bounded resources (descriptors, buffer slots, ...)
+// down() sleeps while the count is zero, up() releases one unit
+pub struct Semaphore {
+    count: Spinlock<usize>,
+    waiters: WaitQueue,
+}
+
+impl Semaphore {
+    pub const fn new(count: usize, name: &'static str) -> Self {
+        Semaphore {
+            count: Spinlock::new(count, name),
+            waiters: WaitQueue::new(),
+        }
+    }
+
+    // Take one unit, sleeping until one is available
+    // Fails only if the process is killed while waiting
+    pub fn down(&self) -> Result<(), WaitError> {
+        let mut count = self.waiters.wait_event(self.count.lock(), |c| *c > 0)?;
+        *count -= 1;
+        Ok(())
+    }
+
+    // As down(), but gives up after timeout clock ticks
+    pub fn down_timeout(&self, timeout: usize) -> Result<(), WaitError> {
+        let mut count = self
+            .waiters
+            .wait_event_timeout(self.count.lock(), timeout, |c| *c > 0)?;
+        *count -= 1;
+        Ok(())
+    }
+
+    // Take one unit if one is available right now
+    pub fn try_down(&self) -> bool {
+        let mut count = self.count.lock();
+        if *count == 0 {
+            return false;
+        }
+        *count -= 1;
+        true
+    }
+
+    // Return one unit and wake a waiter
+    pub fn up(&self) {
+        let mut count = self.count.lock();
+        *count += 1;
+        self.waiters.wake_one();
+    }
+
+    pub fn count(&self) -> usize {
+        *self.count.lock()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::proc::{kthread, kthread_wait, yield_};
+    use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+    const UNITS: usize = 2;
+
+    static GATE: Semaphore = Semaphore::new(0, "gate");
+    static PASSED: AtomicBool = AtomicBool::new(false);
+    static POOL: Semaphore = Semaphore::new(UNITS, "pool");
+    static HOLDERS: AtomicUsize = AtomicUsize::new(0);
+    static MOST_HOLDERS: AtomicUsize = AtomicUsize::new(0);
+
+    // Sleep for n clock ticks
+    fn pause(n: usize) {
+        let never = Semaphore::new(0, "never");
+        assert_eq!(never.down_timeout(n), Err(WaitError::TimedOut));
+    }
+
+    fn pass_gate(_: usize) {
+        GATE.down().unwrap();
+        PASSED.store(true, Ordering::Release);
+    }
+
+    // Take a unit of the pool n times, holding it across a yield
+    fn use_pool(n: usize) {
+        for _ in 0..n {
+            POOL.down().unwrap();
+            let holders = HOLDERS.fetch_add(1, Ordering::AcqRel) + 1;
+            MOST_HOLDERS.fetch_max(holders, Ordering::Relaxed);
+            yield_();
+            HOLDERS.fetch_sub(1, Ordering::AcqRel);
+            POOL.up();
+        }
+    }
+
+    // down() on an empty semaphore only returns after an up()
+    #[test_case]
+    fn down_waits_for_up() {
+        let pid = kthread("gate", pass_gate, 0).unwrap();
+        pause(3);
+        assert!(!PASSED.load(Ordering::Acquire));
+        GATE.up();
+        kthread_wait(pid);
+        assert!(PASSED.load(Ordering::Acquire));
+        assert_eq!(GATE.count(), 0);
+    }
+
+    // However many processes want them, no more units are handed out than
+    // there are, and all of them come back
+    #[test_case]
+    fn count_bounded() {
+        let pids: [usize; 4 * UNITS] =
+            core::array::from_fn(|_| kthread("pool", use_pool, 50).unwrap());
+        for pid in pids {
+            kthread_wait(pid);
+        }
+        assert!(MOST_HOLDERS.load(Ordering::Relaxed) <= UNITS);
+        assert_eq!(POOL.count(), UNITS);
+
+        let empty = Semaphore::new(0, "empty");
+        assert!(!empty.try_down());
+        assert_eq!(empty.count(), 0);
+    }
+
+    // down_timeout() gives up once the ticks have passed without taking
+    // anything, and succeeds at once while a unit is free
+    #[test_case]
+    fn timeout() {
+        let sem = Semaphore::new(0, "timeout");
+        assert_eq!(sem.down_timeout(3), Err(WaitError::TimedOut));
+        assert_eq!(sem.count(), 0);
+        sem.up();
+        assert_eq!(sem.down_timeout(3), Ok(()));
+        assert_eq!(sem.count(), 0);
+    }
+}
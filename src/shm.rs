@@ -0,0 +1,316 @@
+// System V shared memory segments
+// A segment is a SharedObject that processes attach with shmat() as a shared
+// mmap region, so its pages are refcounted like those of any MAP_SHARED mapping
+// Until it is first attached, the table keeps a segment alive; from then on the
+// table only holds a weak reference, and the segment and its pages are freed as
+// soon as the last attachment goes away, whether by shmdt(), munmap(), or exit
+
+use crate::memset::{pg_round_up, PGSIZE};
+use crate::spinlock::Spinlock;
+use crate::vm::{PteFlags, SharedObject, UserAddressSpace, VmError, VmaKind};
+use alloc::collections::BTreeMap;
+use alloc::sync::{Arc, Weak};
+use alloc::vec::Vec;
+
+// shmget() key that always creates a new segment
+pub const IPC_PRIVATE: usize = 0;
+
+// shmget() flags, besides the low nine permission bits, which are ignored
+pub const IPC_CREAT: usize = 0o1000; // Create the segment if the key has none
+pub const IPC_EXCL: usize = 0o2000; // With IPC_CREAT, fail if the key already has one
+
+// shmat() flags
+pub const SHM_RDONLY: usize = 0o10000; // Attach read-only
+pub const SHM_RND: usize = 0o20000; // Round the attach address down to SHMLBA
+
+// Attach addresses must be multiples of this
+pub const SHMLBA: usize = PGSIZE;
+
+const SHMMAX: usize = 32 * 1024 * 1024; // Largest segment, in bytes
+const SHMMNI: usize = 128; // Most segments at once
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum ShmError {
+    NotFound,    // No segment has that key or id
+    Exists,      // IPC_CREAT | IPC_EXCL, but the key already has a segment
+    Invalid,     // Bad size or address
+    NoSpace,     // Too many segments
+    Vm(VmError), // Attaching or detaching the mapping failed
+}
+
+impl From<VmError> for ShmError {
+    fn from(e: VmError) -> Self {
+        ShmError::Vm(e)
+    }
+}
+
+enum Hold {
+    Created(Arc<SharedObject>),   // Never attached, kept alive by the table
+    Attached(Weak<SharedObject>), // Alive while some process has it attached
+}
+
+struct Segment {
+    key: usize,
+    size: usize, // Bytes, a whole number of pages
+    hold: Hold,
+}
+
+impl Segment {
+    fn object(&self) -> Option<Arc<SharedObject>> {
+        match &self.hold {
+            Hold::Created(object) => Some(object.clone()),
+            Hold::Attached(weak) => weak.upgrade(),
+        }
+    }
+}
+
+struct ShmTable {
+    segments: BTreeMap<usize, Segment>, // By id
+    next_id: usize,
+}
+
+impl ShmTable {
+    // Forget segments whose last attachment has gone away
+    fn purge(&mut self) {
+        self.segments.retain(|_, segment| match &segment.hold {
+            Hold::Created(_) => true,
+            Hold::Attached(weak) => weak.strong_count() > 0,
+        });
+    }
+}
+
+static SHM: Spinlock<ShmTable> = Spinlock::new(
+    ShmTable {
+        segments: BTreeMap::new(),
+        next_id: 1,
+    },
+    "shm",
+);
+
+// Return the id of the segment with key, creating one of size bytes if there
+// is none and flags has IPC_CREAT. IPC_PRIVATE always creates a new segment
+// The pages are zero-filled on first touch, as for anonymous shared mmap
+pub fn shmget(key: usize, size: usize, flags: usize) -> Result<usize, ShmError> {
+    let mut table = SHM.lock();
+    table.purge();
+
+    if key != IPC_PRIVATE {
+        if let Some((&id, segment)) = table.segments.iter().find(|(_, s)| s.key == key) {
+            if flags & (IPC_CREAT | IPC_EXCL) == IPC_CREAT | IPC_EXCL {
+                return Err(ShmError::Exists);
+            }
+            if size > segment.size {
+                return Err(ShmError::Invalid);
+            }
+            return Ok(id);
+        }
+        if flags & IPC_CREAT == 0 {
+            return Err(ShmError::NotFound);
+        }
+    }
+
+    if size == 0 || size > SHMMAX {
+        return Err(ShmError::Invalid);
+    }
+    if table.segments.len() >= SHMMNI {
+        return Err(ShmError::NoSpace);
+    }
+    let id = table.next_id;
+    table.next_id += 1;
+    table.segments.insert(
+        id,
+        Segment {
+            key,
+            size: pg_round_up(size),
+            hold: Hold::Created(Arc::new(SharedObject::new(None))),
+        },
+    );
+    Ok(id)
+}
+
+// Attach segment id to aspace, at addr if it is not zero, otherwise wherever
+// mmap would place it. Returns the address it was attached at
+pub fn shmat(
+    aspace: &mut UserAddressSpace,
+    id: usize,
+    addr: usize,
+    flags: usize,
+) -> Result<usize, ShmError> {
+    let (object, size) = {
+        let table = SHM.lock();
+        let segment = table.segments.get(&id).ok_or(ShmError::NotFound)?;
+        (segment.object().ok_or(ShmError::NotFound)?, segment.size)
+    };
+    let fixed = match addr {
+        0 => None,
+        addr if flags & SHM_RND != 0 => Some(addr - addr % SHMLBA),
+        addr if addr % SHMLBA != 0 => return Err(ShmError::Invalid),
+        addr => Some(addr),
+    };
+    let prot = if flags & SHM_RDONLY != 0 {
+        PteFlags::R
+    } else {
+        PteFlags::R | PteFlags::W
+    };
+
+    let weak = Arc::downgrade(&object);
+    let kind = VmaKind::Shared { object, pgoff: 0 };
+    let start = aspace.mmap(fixed, size, prot, kind, false)?;
+
+    // From now on the attachment keeps the segment alive
+    if let Some(segment) = SHM.lock().segments.get_mut(&id) {
+        segment.hold = Hold::Attached(weak);
+    }
+    Ok(start)
+}
+
+// Detach the segment attached at addr from aspace
+// The segment is freed if that was its last attachment
+pub fn shmdt(aspace: &mut UserAddressSpace, addr: usize) -> Result<(), ShmError> {
+    let object = aspace
+        .vmas()
+        .iter()
+        .find(|vma| vma.start == addr)
+        .and_then(|vma| match &vma.kind {
+            VmaKind::Shared { object, pgoff: 0 } if is_segment(object) => Some(object.clone()),
+            _ => None,
+        })
+        .ok_or(ShmError::Invalid)?;
+    let size = segment_size(&object).ok_or(ShmError::Invalid)?;
+
+    // The attachment may have been split up by mprotect() or partial munmap()
+    let pieces: Vec<(usize, usize)> = aspace
+        .vmas()
+        .iter()
+        .filter(|vma| addr <= vma.start && vma.end <= addr + size)
+        .filter(
+            |vma| matches!(&vma.kind, VmaKind::Shared { object: o, .. } if Arc::ptr_eq(o, &object)),
+        )
+        .map(|vma| (vma.start, vma.end))
+        .collect();
+    drop(object);
+    for (start, end) in pieces {
+        aspace.munmap(start, end - start)?;
+    }
+    SHM.lock().purge();
+    Ok(())
+}
+
+// Is object a shared memory segment?
+fn is_segment(object: &Arc<SharedObject>) -> bool {
+    segment_size(object).is_some()
+}
+
+// Size of the segment whose pages object holds
+fn segment_size(object: &Arc<SharedObject>) -> Option<usize> {
+    let ptr = Arc::as_ptr(object);
+    SHM.lock()
+        .segments
+        .values()
+        .find(|segment| match &segment.hold {
+            Hold::Created(o) => Arc::as_ptr(o) == ptr,
+            Hold::Attached(weak) => weak.as_ptr() == ptr,
+        })
+        .map(|segment| segment.size)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::kalloc::{kalloc, kfree, mem_stats, PagePurpose};
+    use crate::vm::{copyin, copyout};
+
+    fn user_pages() -> usize {
+        mem_stats().used[PagePurpose::User as usize]
+    }
+
+    // IPC_CREAT | IPC_EXCL refuses a key that already has a segment, which
+    // plain IPC_CREAT and a lookup without flags return instead
+    #[test_case]
+    fn create_exclusive() {
+        const KEY: usize = 0x5107;
+        let tf = kalloc(PagePurpose::Other).unwrap();
+        let mut aspace = UserAddressSpace::new(tf).unwrap();
+        let id = shmget(KEY, PGSIZE, IPC_CREAT | IPC_EXCL).unwrap();
+        assert_eq!(
+            shmget(KEY, PGSIZE, IPC_CREAT | IPC_EXCL),
+            Err(ShmError::Exists)
+        );
+        assert_eq!(shmget(KEY, PGSIZE, IPC_CREAT), Ok(id));
+        assert_eq!(shmget(KEY, PGSIZE, 0), Ok(id));
+        assert_eq!(shmget(KEY, 2 * PGSIZE, 0), Err(ShmError::Invalid));
+
+        // Attach and detach it, so that it goes away
+        let va = shmat(&mut aspace, id, 0, 0).unwrap();
+        shmdt(&mut aspace, va).unwrap();
+        assert_eq!(shmget(KEY, PGSIZE, 0), Err(ShmError::NotFound));
+        drop(aspace);
+        kfree(tf);
+    }
+
+    // Address spaces with the same segment attached see each other's writes,
+    // even at different addresses
+    #[test_case]
+    fn shared_writes() {
+        let tfs = [
+            kalloc(PagePurpose::Other).unwrap(),
+            kalloc(PagePurpose::Other).unwrap(),
+        ];
+        let mut a = UserAddressSpace::new(tfs[0]).unwrap();
+        let mut b = UserAddressSpace::new(tfs[1]).unwrap();
+        let id = shmget(IPC_PRIVATE, 2 * PGSIZE, 0).unwrap();
+        let va_a = shmat(&mut a, id, 0, 0).unwrap();
+        b.mmap(
+            None,
+            PGSIZE,
+            PteFlags::R,
+            VmaKind::Private { file: None },
+            false,
+        )
+        .unwrap();
+        let va_b = shmat(&mut b, id, 0, 0).unwrap();
+        assert_ne!(va_a, va_b);
+
+        let mut buf = [0u8; 5];
+        copyout(&mut a, va_a + PGSIZE, b"acorn").unwrap();
+        copyin(&mut b, &mut buf, va_b + PGSIZE).unwrap();
+        assert_eq!(&buf, b"acorn");
+        copyout(&mut b, va_b, b"oak").unwrap();
+        copyin(&mut a, &mut buf[..3], va_a).unwrap();
+        assert_eq!(&buf[..3], b"oak");
+
+        drop(a);
+        drop(b);
+        tfs.into_iter().for_each(kfree);
+    }
+
+    // A segment outlives its first detach, but it and its pages are freed
+    // once the last attachment goes away, here with the exit of its process
+    #[test_case]
+    fn freed_after_last_detach() {
+        const KEY: usize = 0x5108;
+        let tfs = [
+            kalloc(PagePurpose::Other).unwrap(),
+            kalloc(PagePurpose::Other).unwrap(),
+        ];
+        let mut a = UserAddressSpace::new(tfs[0]).unwrap();
+        let mut b = UserAddressSpace::new(tfs[1]).unwrap();
+        let before = user_pages();
+        let id = shmget(KEY, 2 * PGSIZE, IPC_CREAT).unwrap();
+        let va_a = shmat(&mut a, id, 0, 0).unwrap();
+        let va_b = shmat(&mut b, id, 0, 0).unwrap();
+        copyout(&mut a, va_a, b"one").unwrap();
+        copyout(&mut b, va_b + PGSIZE, b"two").unwrap();
+        assert_eq!(user_pages(), before + 2);
+
+        shmdt(&mut a, va_a).unwrap();
+        assert_eq!(shmget(KEY, 0, 0), Ok(id));
+        assert_eq!(user_pages(), before + 2);
+
+        drop(b);
+        assert_eq!(shmget(KEY, 0, 0), Err(ShmError::NotFound));
+        assert_eq!(user_pages(), before);
+        drop(a);
+        tfs.into_iter().for_each(kfree);
+    }
+}
@@ -0,0 +1,104 @@
+// Kernel heap: power-of-two size-class slab caches carved out of kalloc pages,
+// with anything larger than a page's worth of small objects served straight
+// from the buddy allocator. Registered as the global allocator so the kernel
+// can use Box, Vec, BTreeMap and Arc from the alloc crate
+
+use crate::buddy::{order_bytes, NORDERS};
+use crate::kalloc::{kalloc, kalloc_pages, kfree_pages, PagePurpose};
+use crate::memset::PGSIZE;
+use crate::spinlock::Spinlock;
+use core::alloc::{GlobalAlloc, Layout};
+use core::ptr::null_mut;
+
+// Object sizes served by slab caches, smallest first
+const SIZE_CLASSES: [usize; 8] = [16, 32, 64, 128, 256, 512, 1024, 2048];
+const NCLASSES: usize = SIZE_CLASSES.len();
+
+// A free object holds a pointer to the next free object in its cache
+struct Object {
+    next: *mut Object,
+}
+
+struct SlabCache {
+    free: *mut Object,
+    pages: usize, // Pages this cache has taken from kalloc
+}
+
+unsafe impl Send for SlabCache {}
+
+impl SlabCache {
+    // Carve a fresh page into objects of the given size
+    fn grow(&mut self, size: usize) -> bool {
+        let Some(page) = kalloc(PagePurpose::Slab) else {
+            return false;
+        };
+        for offset in (0..PGSIZE).step_by(size).rev() {
+            let obj = (page + offset) as *mut Object;
+            unsafe { (*obj).next = self.free };
+            self.free = obj;
+        }
+        self.pages += 1;
+        true
+    }
+}
+
+static CACHES: [Spinlock<SlabCache>; NCLASSES] = [const {
+    Spinlock::new(
+        SlabCache {
+            free: null_mut(),
+            pages: 0,
+        },
+        "slab",
+    )
+}; NCLASSES];
+
+// Objects are aligned to their size class, so a class fits a layout
+// if it is at least as large as both its size and its alignment
+fn size_class(layout: &Layout) -> Option<usize> {
+    let need = layout.size().max(layout.align());
+    SIZE_CLASSES.iter().position(|&size| size >= need)
+}
+
+// Smallest buddy order whose blocks can hold the layout
+// Buddy blocks are aligned to their own size, which covers the alignment
+fn page_order(layout: &Layout) -> Option<usize> {
+    let need = layout.size().max(layout.align());
+    (0..NORDERS).find(|&order| order_bytes(order) >= need)
+}
+
+pub struct KernelHeap;
+
+unsafe impl GlobalAlloc for KernelHeap {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        if let Some(class) = size_class(&layout) {
+            let mut cache = CACHES[class].lock();
+            if cache.free.is_null() && !cache.grow(SIZE_CLASSES[class]) {
+                return null_mut();
+            }
+            let obj = cache.free;
+            cache.free = (*obj).next;
+            return obj as *mut u8;
+        }
+        match page_order(&layout).and_then(|order| kalloc_pages(order, PagePurpose::Heap)) {
+            Some(pa) => pa as *mut u8,
+            None => null_mut(),
+        }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        if let Some(class) = size_class(&layout) {
+            let obj = ptr as *mut Object;
+            let mut cache = CACHES[class].lock();
+            (*obj).next = cache.free;
+            cache.free = obj;
+            return;
+        }
+        match page_order(&layout) {
+            Some(order) => kfree_pages(ptr as usize, order),
+            None => panic!("dealloc: impossible layout {:?}", layout),
+        }
+    }
+}
+
+#[global_allocator]
+static HEAP: KernelHeap = KernelHeap;
@@ -0,0 +1,131 @@
+use crate::proc::{myproc, sleep, wakeup};
+use crate::spinlock::Spinlock;
+use core::cell::UnsafeCell;
+use core::ops::{Deref, DerefMut};
+
+// Long-term lock for processes
+// Waiters sleep instead of spinning, so the guard may be held across calls that block (disk I/O etc.)
+pub struct SleepLock<T> {
+    inner: Spinlock<SleepState>, // Spinlock protecting this sleep lock
+    name: &'static str,
+    data: UnsafeCell<T>,
+}
+
+struct SleepState {
+    locked: bool, // Is the lock held?
+    pid: usize,   // Process holding lock
+}
+
+unsafe impl<T: Send> Sync for SleepLock<T> {}
+unsafe impl<T: Send> Send for SleepLock<T> {}
+
+impl<T> SleepLock<T> {
+    pub const fn new(data: T, name: &'static str) -> Self {
+        SleepLock {
+            inner: Spinlock::new(
+                SleepState {
+                    locked: false,
+                    pid: 0,
+                },
+                "sleep lock",
+            ),
+            name,
+            data: UnsafeCell::new(data),
+        }
+    }
+
+    // Acquire the lock, sleeping until it is free
+    // Must be called from process context
+    pub fn lock(&self) -> SleepLockGuard<'_, T> {
+        let mut state = self.inner.lock();
+        while state.locked {
+            state = sleep(self.chan(), state);
+        }
+        state.locked = true;
+        state.pid = myproc().expect("sleeplock: no process").pid;
+        SleepLockGuard { lock: self }
+    }
+
+    // Is the current process holding the lock?
+    pub fn holding(&self) -> bool {
+        let state = self.inner.lock();
+        state.locked && myproc().is_some_and(|p| p.pid == state.pid)
+    }
+
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    // Sleepers wait on the lock's own address
+    fn chan(&self) -> usize {
+        self as *const Self as usize
+    }
+
+    fn release(&self) {
+        let mut state = self.inner.lock();
+        state.locked = false;
+        state.pid = 0;
+        wakeup(self.chan());
+    }
+}
+
+pub struct SleepLockGuard<'a, T> {
+    lock: &'a SleepLock<T>,
+}
+
+impl<T> Deref for SleepLockGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<T> DerefMut for SleepLockGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.lock.data.get() }
+    }
+}
+
+impl<T> Drop for SleepLockGuard<'_, T> {
+    fn drop(&mut self) {
+        self.lock.release();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::proc::{kthread, kthread_wait, yield_};
+
+    static COUNTER: SleepLock<usize> = SleepLock::new(0, "counter");
+
+    // Increment COUNTER n times, giving up the hart while holding the lock
+    fn add(n: usize) {
+        for _ in 0..n {
+            let mut count = COUNTER.lock();
+            let seen = *count;
+            yield_();
+            *count = seen + 1;
+        }
+    }
+
+    #[test_case]
+    fn holding() {
+        let lock = SleepLock::new((), "holding");
+        assert!(!lock.holding());
+        let guard = lock.lock();
+        assert!(lock.holding());
+        drop(guard);
+        assert!(!lock.holding());
+    }
+
+    #[test_case]
+    fn held_across_sleep() {
+        let pids: [usize; 4] = core::array::from_fn(|_| kthread("add", add, 50).unwrap());
+        for pid in pids {
+            kthread_wait(pid);
+        }
+        assert_eq!(*COUNTER.lock(), 200);
+    }
+}
@@ -0,0 +1,143 @@
+use crate::arch::{intr_get, intr_off, intr_on, read_threadptr};
+use crate::proc::mycpu;
+use crate::vm::shootdown_intr;
+use core::cell::UnsafeCell;
+use core::ops::{Deref, DerefMut};
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+// No hart holds the lock
+const NO_HOLDER: usize = usize::MAX;
+
+// Mutual exclusion spin lock
+// Interrupts stay disabled on the holding hart until the guard is dropped,
+// so an interrupt handler can never deadlock against the code it interrupted
+pub struct Spinlock<T> {
+    locked: AtomicBool,
+    name: &'static str,
+    cpu: AtomicUsize, // Hart holding the lock
+    data: UnsafeCell<T>,
+}
+
+unsafe impl<T: Send> Sync for Spinlock<T> {}
+unsafe impl<T: Send> Send for Spinlock<T> {}
+
+impl<T> Spinlock<T> {
+    pub const fn new(data: T, name: &'static str) -> Self {
+        Spinlock {
+            locked: AtomicBool::new(false),
+            name,
+            cpu: AtomicUsize::new(NO_HOLDER),
+            data: UnsafeCell::new(data),
+        }
+    }
+
+    // Acquire the lock, spinning until it is free
+    // Interrupts are off while spinning, so TLB shootdowns are served here;
+    // the holder may be waiting for this hart to flush
+    pub fn lock(&self) -> SpinlockGuard<'_, T> {
+        push_off(); // disable interrupts to avoid deadlock
+        if self.holding() {
+            panic!("acquire {}", self.name);
+        }
+        while self
+            .locked
+            .compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            shootdown_intr();
+            core::hint::spin_loop();
+        }
+        self.cpu.store(read_threadptr(), Ordering::Relaxed);
+        SpinlockGuard { lock: self }
+    }
+
+    // Is this hart holding the lock?
+    // Interrupts must be off
+    pub fn holding(&self) -> bool {
+        self.locked.load(Ordering::Relaxed) && self.cpu.load(Ordering::Relaxed) == read_threadptr()
+    }
+
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    // Access the protected data without taking the lock
+    // Caller must guarantee exclusion by other means (e.g. single hart during boot)
+    #[allow(clippy::mut_from_ref)]
+    pub unsafe fn get_mut_unchecked(&self) -> &mut T {
+        &mut *self.data.get()
+    }
+
+    // Release a lock whose guard belongs to another kernel thread
+    // A new process uses this to drop the p.lock that scheduler() took for it
+    pub unsafe fn force_unlock(&self) {
+        self.release();
+    }
+
+    fn release(&self) {
+        if !self.holding() {
+            panic!("release {}", self.name);
+        }
+        self.cpu.store(NO_HOLDER, Ordering::Relaxed);
+        self.locked.store(false, Ordering::Release);
+        pop_off();
+    }
+}
+
+pub struct SpinlockGuard<'a, T> {
+    lock: &'a Spinlock<T>,
+}
+
+impl<'a, T> SpinlockGuard<'a, T> {
+    // The lock this guard was taken from, so sleep() can reacquire it after waking
+    pub fn spinlock(&self) -> &'a Spinlock<T> {
+        self.lock
+    }
+}
+
+impl<T> Deref for SpinlockGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<T> DerefMut for SpinlockGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.lock.data.get() }
+    }
+}
+
+impl<T> Drop for SpinlockGuard<'_, T> {
+    fn drop(&mut self) {
+        self.lock.release();
+    }
+}
+
+// push_off/pop_off are like intr_off()/intr_on() except that they are matched:
+// it takes two pop_off()s to undo two push_off()s
+// If interrupts are initially off, then push_off, pop_off leaves them off
+pub fn push_off() {
+    let old = intr_get();
+    intr_off();
+    let cpu = mycpu();
+    if cpu.noff == 0 {
+        cpu.intena = old;
+    }
+    cpu.noff += 1;
+}
+
+pub fn pop_off() {
+    let cpu = mycpu();
+    if intr_get() {
+        panic!("pop_off - interruptible");
+    }
+    if cpu.noff < 1 {
+        panic!("pop_off");
+    }
+    cpu.noff -= 1;
+    if cpu.noff == 0 && cpu.intena {
+        intr_on();
+    }
+}
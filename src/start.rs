@@ -0,0 +1,198 @@
+// Machine-mode setup on every hart, then the supervisor-mode boot sequence
+
+use crate::arch::{
+    mret, read_mhartid, read_threadptr, read_time, set_mcounteren, set_medeleg, set_menvcfg,
+    set_mideleg, set_mie, set_mpp, set_pmpcfg0, set_sie, write_mepc, write_mhpmevent,
+    write_pmpaddr0, write_satp, write_stimecmp, write_threadptr, MCounterenVal, MedelegVal,
+    MenvcfgVal, MidelegVal, MieVal, PmpcfgVal, PrivilegeMode, SieVal,
+};
+use crate::console::console_thread;
+use crate::fdt::{chosen, set_dtb};
+use crate::ipi::ipiinit;
+use crate::kalloc::kinit;
+use crate::memset::{TimerCompareValue, ValidAddress};
+use crate::plic::{plicinit, plicinithart};
+use crate::println;
+use crate::proc::{kthread, scheduler, NCPU};
+use crate::random::{add_entropy_bytes, entropy_init};
+use crate::trap::{trapinithart, TIMER_INTERVAL};
+use crate::uart::uartinit;
+use crate::virtio_disk::virtio_disk_init;
+use crate::virtio_rng::virtio_rng_init;
+use crate::vm::{aslr_bootargs, kvminit, kvminithart, TLB_MISS_COUNTERS};
+use core::hint::spin_loop;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+// Bytes of boot stack per hart, used by start() and later by scheduler()
+// Must be a power of two
+pub const STACK_SIZE: usize = 4 * 4096;
+
+#[repr(C, align(16))]
+pub struct Stack0([u8; STACK_SIZE * NCPU]);
+
+// entry.rs needs one stack per hart so that Rust code can run
+pub static mut STACK0: Stack0 = Stack0([0; STACK_SIZE * NCPU]);
+
+// Exceptions handed to supervisor mode
+const DELEGATED: [MedelegVal; 13] = [
+    MedelegVal::InstructionAddressMisaligned,
+    MedelegVal::InstructionAccessFault,
+    MedelegVal::IllegalInstruction,
+    MedelegVal::Breakpoint,
+    MedelegVal::LoadAddressMisaligned,
+    MedelegVal::LoadAccessFault,
+    MedelegVal::StoreAddressMisaligned,
+    MedelegVal::StoreAccessFault,
+    MedelegVal::EnvironmentCallFromUMode,
+    MedelegVal::EnvironmentCallFromSMode,
+    MedelegVal::InstructionPageFault,
+    MedelegVal::LoadPageFault,
+    MedelegVal::StorePageFault,
+];
+
+// QEMU's performance events for TLB misses, one per counter in TLB_MISS_COUNTERS
+// Other platforms number their events differently, and the counters may stay at zero
+const TLB_MISS_EVENTS: [usize; 3] = [
+    0x10019, // Data TLB read miss
+    0x1001b, // Data TLB write miss
+    0x10021, // Instruction TLB miss
+];
+
+// Set once hart 0 has initialised the shared kernel state
+static STARTED: AtomicBool = AtomicBool::new(false);
+// Harts that have finished booting
+static ONLINE: AtomicUsize = AtomicUsize::new(0);
+
+// entry.rs jumps here in machine mode on stack0, with the device tree at dtb
+pub extern "C" fn start(_hartid: usize, dtb: usize) -> ! {
+    // Keep each hart's hartid in its tp register, for mycpu()
+    write_threadptr(read_mhartid());
+
+    if read_mhartid() == 0 {
+        set_dtb(dtb);
+    }
+
+    // Seed the entropy pool while mcycle is readable
+    entropy_init();
+
+    // Set M Previous Privilege mode to Supervisor, for mret
+    set_mpp(PrivilegeMode::SMV);
+
+    // Set M Exception Program Counter to main, for mret
+    write_mepc(
+        ValidAddress::new(crate::main as extern "C" fn() -> ! as usize).expect("start: main"),
+    );
+
+    // Disable paging for now
+    write_satp(0);
+
+    // Delegate all exceptions and interrupts to supervisor mode
+    for exception in DELEGATED {
+        set_medeleg(exception);
+    }
+    set_mideleg(MidelegVal::SSIE);
+    set_mideleg(MidelegVal::STIE);
+    set_mideleg(MidelegVal::SEIE);
+    set_sie(SieVal::SEIE);
+    set_sie(SieVal::STIE);
+
+    // Configure Physical Memory Protection to give supervisor mode
+    // access to all of physical memory
+    write_pmpaddr0(0x3f_ffff_ffff_ffff);
+    set_pmpcfg0(PmpcfgVal::R);
+    set_pmpcfg0(PmpcfgVal::W);
+    set_pmpcfg0(PmpcfgVal::X);
+    set_pmpcfg0(PmpcfgVal::A);
+
+    // Ask for clock interrupts
+    timerinit();
+
+    // Count TLB misses
+    perfinit();
+
+    // Turn CLINT software interrupts into supervisor ones, for IPIs
+    ipiinit();
+
+    // Switch to supervisor mode and jump to main()
+    mret()
+}
+
+// Ask each hart to generate timer interrupts
+fn timerinit() {
+    // Enable supervisor-mode timer interrupts
+    set_mie(MieVal::STIE);
+    // Enable the sstc extension (i.e. stimecmp)
+    set_menvcfg(MenvcfgVal::STCE);
+    // Allow supervisor mode to use stimecmp and time
+    set_mcounteren(MCounterenVal::TM);
+    // Ask for the very first timer interrupt
+    let first = TimerCompareValue::new(read_time() + TIMER_INTERVAL).expect("timerinit");
+    write_stimecmp(first);
+}
+
+// Have the performance counters count TLB misses, for the TLB statistics
+// in vm.rs, and let supervisor mode read them
+fn perfinit() {
+    for (counter, event) in TLB_MISS_COUNTERS.into_iter().zip(TLB_MISS_EVENTS) {
+        write_mhpmevent(counter, event);
+    }
+    set_mcounteren(MCounterenVal::HPM3);
+    set_mcounteren(MCounterenVal::HPM4);
+    set_mcounteren(MCounterenVal::HPM5);
+}
+
+// main() calls this in supervisor mode on every hart
+// Hart 0 sets up the kernel; the others wait for it, then turn on paging
+// and traps for themselves. All of them then run processes
+pub fn init() -> ! {
+    if read_threadptr() == 0 {
+        uartinit();
+        println!();
+        println!("acorn kernel is booting");
+        println!();
+        bootargs(); // before kinit() reuses the device tree's memory
+        kinit(); // physical page allocator
+        kvminit(); // create kernel page table
+        kvminithart(); // turn on paging
+        trapinithart(); // install kernel trap vector
+        plicinit(); // set up interrupt controller
+        plicinithart(); // ask PLIC for device interrupts
+        virtio_disk_init(); // swap disk
+        virtio_rng_init(); // seed the entropy pool
+        kthread("console", console_thread, 0).expect("init: console");
+        #[cfg(test)]
+        crate::ktest::start();
+        STARTED.store(true, Ordering::Release);
+    } else {
+        while !STARTED.load(Ordering::Acquire) {
+            spin_loop();
+        }
+        println!("hart {} starting", read_threadptr());
+        kvminithart(); // turn on paging
+        trapinithart(); // install kernel trap vector
+        plicinithart(); // ask PLIC for device interrupts
+    }
+    ONLINE.fetch_add(1, Ordering::Relaxed);
+    scheduler()
+}
+
+// Take the kernel command line and the random seed from the device tree
+// QEMU sets the command line with -append
+fn bootargs() {
+    if let Some(seed) = chosen("rng-seed") {
+        add_entropy_bytes(seed);
+    }
+    if let Some(args) = chosen("bootargs") {
+        let args = core::str::from_utf8(args).unwrap_or("");
+        let args = args.trim_end_matches('\0');
+        if !args.is_empty() {
+            println!("command line: {}", args);
+        }
+        aslr_bootargs(args);
+    }
+}
+
+// Number of harts running processes
+pub fn harts_online() -> usize {
+    ONLINE.load(Ordering::Relaxed)
+}
@@ -0,0 +1,204 @@
+// Swap space
+// Anonymous user pages evicted by the page reclaimer are written to page-sized
+// slots in a swap area on a block device. The PTE left behind is not valid and
+// holds the slot number (see Pte::swapped()), so the next touch faults the page back in
+// A slot is referenced by every swap PTE naming it, as fork shares them, and
+// by a write in flight, and is free again once the last reference is dropped
+// Until its write completes, a slot's contents live on in its old page, so a
+// fault that comes in meanwhile reads from there rather than the device
+
+use crate::kalloc::kfree;
+use crate::memset::PGSIZE;
+use crate::spinlock::Spinlock;
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::ptr::copy_nonoverlapping;
+
+pub const SECTOR_SIZE: usize = 512;
+const SECTORS_PER_SLOT: usize = PGSIZE / SECTOR_SIZE;
+
+// Sector-level access to a disk, implemented by the virtio block driver
+// Both calls may sleep, so they are made without any spinlock held
+pub trait BlockDevice: Send + Sync {
+    // Read buf.len() bytes starting at sector into buf
+    fn read(&self, sector: usize, buf: &mut [u8]) -> Result<(), &'static str>;
+    // Write buf to the disk starting at sector
+    fn write(&self, sector: usize, buf: &[u8]) -> Result<(), &'static str>;
+}
+
+struct SwapArea {
+    dev: Arc<dyn BlockDevice>,
+    start: usize,                  // First sector of slot 0
+    refs: Vec<u16>,                // References to each slot, 0 if free
+    free: usize,                   // Number of free slots
+    hint: usize,                   // Where to start looking for a free slot
+    pages: BTreeMap<usize, usize>, // Slot to the page still holding its contents
+}
+
+static SWAP: Spinlock<Option<SwapArea>> = Spinlock::new(None, "swap");
+
+// Use nsectors of dev starting at sector start as the swap area
+// Called once by the block driver that owns the swap partition
+pub fn swapon(
+    dev: Arc<dyn BlockDevice>,
+    start: usize,
+    nsectors: usize,
+) -> Result<(), &'static str> {
+    let nslots = nsectors / SECTORS_PER_SLOT;
+    if nslots == 0 {
+        return Err("swap area too small");
+    }
+    let mut swap = SWAP.lock();
+    if swap.is_some() {
+        return Err("swap already on");
+    }
+    *swap = Some(SwapArea {
+        dev,
+        start,
+        refs: vec![0; nslots],
+        free: nslots,
+        hint: 0,
+        pages: BTreeMap::new(),
+    });
+    Ok(())
+}
+
+// Is there a swap area to evict pages to?
+pub fn swap_enabled() -> bool {
+    SWAP.lock().is_some()
+}
+
+// Free and total slots, for statistics
+pub fn swap_stats() -> (usize, usize) {
+    SWAP.lock()
+        .as_ref()
+        .map_or((0, 0), |swap| (swap.free, swap.refs.len()))
+}
+
+// Start evicting the page at pa: give it a slot whose contents it holds until
+// swap_write() has put them on disk. The slot comes with one reference for the
+// caller's swap PTE and one for the write
+// Returns None if there is no swap area or it is full
+pub fn swap_alloc(pa: usize) -> Option<usize> {
+    let mut guard = SWAP.lock();
+    let swap = guard.as_mut()?;
+    if swap.free == 0 {
+        return None;
+    }
+    let nslots = swap.refs.len();
+    let slot = (0..nslots)
+        .map(|i| (swap.hint + i) % nslots)
+        .find(|&slot| swap.refs[slot] == 0)?;
+    swap.refs[slot] = 2;
+    swap.free -= 1;
+    swap.hint = (slot + 1) % nslots;
+    swap.pages.insert(slot, pa);
+    Some(slot)
+}
+
+// Write a slot from swap_alloc() out and drop the write's reference
+// The page is freed once its contents are safe on disk; if the write fails
+// it is kept for as long as the slot is in use, so nothing is lost
+pub fn swap_write(slot: usize) {
+    let (dev, sector, pa) = {
+        let guard = SWAP.lock();
+        let swap = guard.as_ref().expect("swap_write: no swap");
+        (
+            swap.dev.clone(),
+            swap.start + slot * SECTORS_PER_SLOT,
+            swap.pages[&slot],
+        )
+    };
+    let buf = unsafe { core::slice::from_raw_parts(pa as *const u8, PGSIZE) };
+    let written = dev.write(sector, buf).is_ok();
+
+    let mut guard = SWAP.lock();
+    let swap = guard.as_mut().expect("swap_write: no swap");
+    if written {
+        swap.pages.remove(&slot);
+        kfree(pa);
+    }
+    unref(swap, slot);
+}
+
+// Fill the page at pa with the contents of slot
+pub fn swap_read(slot: usize, pa: usize) -> Result<(), &'static str> {
+    let (dev, sector) = {
+        let guard = SWAP.lock();
+        let swap = guard.as_ref().expect("swap_read: no swap");
+        if let Some(&page) = swap.pages.get(&slot) {
+            unsafe { copy_nonoverlapping(page as *const u8, pa as *mut u8, PGSIZE) };
+            return Ok(());
+        }
+        (swap.dev.clone(), swap.start + slot * SECTORS_PER_SLOT)
+    };
+    // The caller's reference keeps the slot from being reused while we sleep
+    let buf = unsafe { core::slice::from_raw_parts_mut(pa as *mut u8, PGSIZE) };
+    dev.read(sector, buf)
+}
+
+// Add a reference to slot, for a swap PTE copied by fork
+pub fn swap_dup(slot: usize) {
+    let mut guard = SWAP.lock();
+    let swap = guard.as_mut().expect("swap_dup: no swap");
+    if swap.refs[slot] == 0 {
+        panic!("swap_dup: slot {} is free", slot);
+    }
+    swap.refs[slot] += 1;
+}
+
+// Drop a reference to slot, for a swap PTE that has been removed or faulted back in
+pub fn swap_free(slot: usize) {
+    let mut guard = SWAP.lock();
+    let swap = guard.as_mut().expect("swap_free: no swap");
+    unref(swap, slot);
+}
+
+fn unref(swap: &mut SwapArea, slot: usize) {
+    match swap.refs[slot] {
+        0 => panic!("swap_free: slot {} is free", slot),
+        1 => {
+            swap.free += 1;
+            // A page kept after a failed write is no longer needed
+            if let Some(pa) = swap.pages.remove(&slot) {
+                kfree(pa);
+            }
+        }
+        _ => {}
+    }
+    swap.refs[slot] -= 1;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::kalloc::{kalloc, PagePurpose};
+
+    // A page written out to its slot reads back the same from the disk
+    #[test_case]
+    fn write_read() {
+        if !swap_enabled() {
+            return;
+        }
+        let pa = kalloc(PagePurpose::User).unwrap();
+        let page = unsafe { core::slice::from_raw_parts_mut(pa as *mut u8, PGSIZE) };
+        for (i, byte) in page.iter_mut().enumerate() {
+            *byte = (i % 251) as u8;
+        }
+        let slot = swap_alloc(pa).unwrap();
+        swap_write(slot); // Frees pa
+        assert!(!SWAP.lock().as_ref().unwrap().pages.contains_key(&slot));
+
+        let back = kalloc(PagePurpose::User).unwrap();
+        swap_read(slot, back).unwrap();
+        let page = unsafe { core::slice::from_raw_parts(back as *const u8, PGSIZE) };
+        assert!(page
+            .iter()
+            .enumerate()
+            .all(|(i, &byte)| byte == (i % 251) as u8));
+        swap_free(slot);
+        kfree(back);
+    }
+}
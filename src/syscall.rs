@@ -0,0 +1,120 @@
+// System call dispatch
+// User code puts the call number in a7 and arguments in a0..a5; the result goes back in a0
+
+use crate::println;
+use crate::proc::{myproc, TrapFrame};
+use crate::shm::ShmError;
+use crate::sysproc::*;
+use crate::vm::VmError;
+
+// System call numbers
+pub const SYS_MEMSTAT: usize = 1;
+pub const SYS_SBRK: usize = 2;
+pub const SYS_MMAP: usize = 3;
+pub const SYS_MUNMAP: usize = 4;
+pub const SYS_MSYNC: usize = 5;
+pub const SYS_MPROTECT: usize = 6;
+pub const SYS_VMPRINT: usize = 7;
+pub const SYS_SHMGET: usize = 8;
+pub const SYS_SHMAT: usize = 9;
+pub const SYS_SHMDT: usize = 10;
+pub const SYS_GETRLIMIT: usize = 11;
+pub const SYS_SETRLIMIT: usize = 12;
+pub const SYS_EXIT: usize = 13;
+pub const SYS_YIELD: usize = 14;
+pub const SYS_MEMFD_CREATE: usize = 15;
+pub const SYS_CLOSE: usize = 16;
+
+// Error numbers, returned to user space negated in a0
+// Named as in POSIX
+#[allow(clippy::upper_case_acronyms)]
+#[repr(isize)]
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Errno {
+    ENOENT = 2,  // No such file or directory
+    EIO = 5,     // I/O error
+    EBADF = 9,   // Bad file descriptor
+    ENOMEM = 12, // Out of memory
+    EACCES = 13, // Permission denied
+    EFAULT = 14, // Bad address
+    EEXIST = 17, // File exists
+    EINVAL = 22, // Invalid argument
+    EMFILE = 24, // Too many open files
+    ENOSPC = 28, // No space left on device
+    ENOSYS = 38, // Unknown system call
+}
+
+impl From<VmError> for Errno {
+    fn from(e: VmError) -> Self {
+        match e {
+            VmError::OutOfMemory => Errno::ENOMEM,
+            VmError::Io => Errno::EIO,
+            VmError::WriteExec(_) => Errno::EACCES,
+            VmError::Misaligned(_) => Errno::EINVAL,
+            _ => Errno::EFAULT,
+        }
+    }
+}
+
+impl From<ShmError> for Errno {
+    fn from(e: ShmError) -> Self {
+        match e {
+            ShmError::NotFound => Errno::ENOENT,
+            ShmError::Exists => Errno::EEXIST,
+            ShmError::Invalid => Errno::EINVAL,
+            ShmError::NoSpace => Errno::ENOSPC,
+            ShmError::Vm(e) => e.into(),
+        }
+    }
+}
+
+pub type SysResult = Result<usize, Errno>;
+
+// The raw value of the nth system call argument
+pub fn argraw(n: usize) -> usize {
+    let p = myproc().expect("argraw: no process");
+    let tf: &TrapFrame = unsafe { &*p.trapframe };
+    match n {
+        0 => tf.a0,
+        1 => tf.a1,
+        2 => tf.a2,
+        3 => tf.a3,
+        4 => tf.a4,
+        5 => tf.a5,
+        _ => panic!("argraw {}", n),
+    }
+}
+
+pub fn syscall() {
+    let p = myproc().expect("syscall: no process");
+    let tf = unsafe { &mut *p.trapframe };
+    let num = tf.a7;
+
+    let result = match num {
+        SYS_MEMSTAT => sys_memstat(),
+        SYS_SBRK => sys_sbrk(),
+        SYS_MMAP => sys_mmap(),
+        SYS_MUNMAP => sys_munmap(),
+        SYS_MSYNC => sys_msync(),
+        SYS_MPROTECT => sys_mprotect(),
+        SYS_VMPRINT => sys_vmprint(),
+        SYS_SHMGET => sys_shmget(),
+        SYS_SHMAT => sys_shmat(),
+        SYS_SHMDT => sys_shmdt(),
+        SYS_GETRLIMIT => sys_getrlimit(),
+        SYS_SETRLIMIT => sys_setrlimit(),
+        SYS_EXIT => sys_exit(),
+        SYS_YIELD => sys_yield(),
+        SYS_MEMFD_CREATE => sys_memfd_create(),
+        SYS_CLOSE => sys_close(),
+        _ => {
+            println!("{} {}: unknown sys call {}", p.pid, p.name(), num);
+            Err(Errno::ENOSYS)
+        }
+    };
+
+    tf.a0 = match result {
+        Ok(val) => val,
+        Err(errno) => (-(errno as isize)) as usize,
+    };
+}
@@ -0,0 +1,271 @@
+use crate::buddy;
+use crate::file::{fdalloc, fdget, MemFile, MAXFILE};
+use crate::kalloc::{mem_stats, PagePurpose};
+use crate::memset::PGSIZE;
+use crate::proc::{exit, myproc, yield_};
+use crate::shm::{shmat, shmdt, shmget};
+use crate::syscall::{argraw, Errno, SysResult};
+use crate::vm::{MappedFile, PteFlags, SharedObject, VmaKind, STACK_RLIMIT_MAX};
+use alloc::sync::Arc;
+
+// mmap protection bits
+pub const PROT_READ: usize = 0x1;
+pub const PROT_WRITE: usize = 0x2;
+pub const PROT_EXEC: usize = 0x4;
+
+// mmap flags
+pub const MAP_SHARED: usize = 0x01;
+pub const MAP_PRIVATE: usize = 0x02;
+pub const MAP_FIXED: usize = 0x10;
+pub const MAP_ANONYMOUS: usize = 0x20;
+pub const MAP_JIT: usize = 0x800; // Allow the region to be writable and executable at once
+pub const MAP_HUGETLB: usize = 0x40000; // 2 MiB superpages, anonymous and private only
+
+// Resource limits
+pub const RLIMIT_STACK: usize = 3; // Bytes the user stack may grow to
+
+// sys_memstat() figures about free memory in the buddy allocator start here:
+// MEMSTAT_BUDDY = fragmentation in percent (see BuddyStats::fragmentation()),
+// + 1 = order of the largest free block, + 2 + order = free blocks of order
+pub const MEMSTAT_BUDDY: usize = 0x100;
+
+// Page counts from the allocator, one figure per call
+// 0 = total, 1 = free, 2.. = pages in use for each PagePurpose,
+// MEMSTAT_BUDDY.. = free block counts
+pub fn sys_memstat() -> SysResult {
+    let which = argraw(0);
+    if which >= MEMSTAT_BUDDY {
+        let buddy = buddy::stats();
+        return match which - MEMSTAT_BUDDY {
+            0 => Ok(buddy.fragmentation()),
+            1 => buddy.largest_order.ok_or(Errno::ENOMEM),
+            n => buddy.free_blocks.get(n - 2).copied().ok_or(Errno::EINVAL),
+        };
+    }
+    let stats = mem_stats();
+    match which {
+        0 => Ok(stats.total),
+        1 => Ok(stats.free),
+        _ => PagePurpose::from_index(which - 2)
+            .map(|purpose| stats.used[purpose as usize])
+            .ok_or(Errno::EINVAL),
+    }
+}
+
+// Grow or shrink user memory by n bytes, returning the old break
+// Growing only moves the break; pages are allocated when first touched
+pub fn sys_sbrk() -> SysResult {
+    let n = argraw(0) as isize;
+    let p = myproc().expect("sbrk: no process");
+    let aspace = p.aspace.as_mut().ok_or(Errno::EINVAL)?;
+    let old = aspace.size();
+    let new = old.checked_add_signed(n).ok_or(Errno::EINVAL)?;
+    if new < aspace.load_base() {
+        return Err(Errno::EINVAL);
+    }
+    if new > old {
+        aspace.grow(new)?;
+    } else {
+        aspace.shrink(new);
+    }
+    Ok(old)
+}
+
+// Page permissions for PROT_* bits
+// W without R is a reserved PTE encoding, so PROT_WRITE implies PROT_READ
+fn prot_flags(prot: usize) -> PteFlags {
+    let mut perm = PteFlags::empty();
+    if prot & (PROT_READ | PROT_WRITE) != 0 {
+        perm |= PteFlags::R;
+    }
+    if prot & PROT_WRITE != 0 {
+        perm |= PteFlags::W;
+    }
+    if prot & PROT_EXEC != 0 {
+        perm |= PteFlags::X;
+    }
+    perm
+}
+
+// The file open as fd, for mapping
+fn mapped_file(fd: usize) -> Result<Arc<dyn MappedFile>, Errno> {
+    let p = myproc().expect("mmap: no process");
+    let file = fdget(p, fd).ok_or(Errno::EBADF)?;
+    Ok(file)
+}
+
+// mmap(addr, len, prot, flags, fd, offset)
+// Returns the start of the new mapping; pages are faulted in on first touch
+// Without MAP_ANONYMOUS they are read from the file open as fd, starting at offset
+pub fn sys_mmap() -> SysResult {
+    let (addr, len, prot, flags) = (argraw(0), argraw(1), argraw(2), argraw(3));
+    let (fd, offset) = (argraw(4), argraw(5));
+
+    let perm = prot_flags(prot);
+
+    let shared = match flags & (MAP_SHARED | MAP_PRIVATE) {
+        MAP_SHARED => true,
+        MAP_PRIVATE => false,
+        _ => return Err(Errno::EINVAL),
+    };
+    let fixed = (flags & MAP_FIXED != 0).then_some(addr);
+
+    if flags & MAP_HUGETLB != 0 {
+        if shared || flags & (MAP_ANONYMOUS | MAP_JIT) != MAP_ANONYMOUS {
+            return Err(Errno::EINVAL);
+        }
+        let p = myproc().expect("mmap: no process");
+        let aspace = p.aspace.as_mut().ok_or(Errno::EINVAL)?;
+        return Ok(aspace.mmap_huge(fixed, len, perm)?);
+    }
+
+    let file = if flags & MAP_ANONYMOUS != 0 {
+        None
+    } else {
+        if !offset.is_multiple_of(PGSIZE) {
+            return Err(Errno::EINVAL);
+        }
+        Some((mapped_file(fd)?, offset))
+    };
+    let kind = if shared {
+        VmaKind::Shared {
+            object: Arc::new(SharedObject::new(file)),
+            pgoff: 0,
+        }
+    } else {
+        VmaKind::Private { file }
+    };
+
+    let p = myproc().expect("mmap: no process");
+    let aspace = p.aspace.as_mut().ok_or(Errno::EINVAL)?;
+    Ok(aspace.mmap(fixed, len, perm, kind, flags & MAP_JIT != 0)?)
+}
+
+// munmap(addr, len)
+// MAP_SHARED file pages are written back before they go away
+pub fn sys_munmap() -> SysResult {
+    let (addr, len) = (argraw(0), argraw(1));
+    let p = myproc().expect("munmap: no process");
+    let aspace = p.aspace.as_mut().ok_or(Errno::EINVAL)?;
+    aspace.munmap(addr, len)?;
+    Ok(0)
+}
+
+// msync(addr, len)
+// Write MAP_SHARED file pages in the range back to their files
+pub fn sys_msync() -> SysResult {
+    let (addr, len) = (argraw(0), argraw(1));
+    let p = myproc().expect("msync: no process");
+    let aspace = p.aspace.as_mut().ok_or(Errno::EINVAL)?;
+    aspace.msync(addr, len)?;
+    Ok(0)
+}
+
+// mprotect(addr, len, prot)
+// Writable and executable at once is refused outside MAP_JIT regions
+pub fn sys_mprotect() -> SysResult {
+    let (addr, len, prot) = (argraw(0), argraw(1), argraw(2));
+    let p = myproc().expect("mprotect: no process");
+    let aspace = p.aspace.as_mut().ok_or(Errno::EINVAL)?;
+    aspace.mprotect(addr, len, prot_flags(prot))?;
+    Ok(0)
+}
+
+// Print the calling process's address space on the console
+pub fn sys_vmprint() -> SysResult {
+    let p = myproc().expect("vmprint: no process");
+    let aspace = p.aspace.as_ref().ok_or(Errno::EINVAL)?;
+    aspace.vmprint();
+    Ok(0)
+}
+
+// shmget(key, size, flags)
+// Returns the id of the shared memory segment with key, creating it with IPC_CREAT
+pub fn sys_shmget() -> SysResult {
+    let (key, size, flags) = (argraw(0), argraw(1), argraw(2));
+    Ok(shmget(key, size, flags)?)
+}
+
+// shmat(id, addr, flags)
+// Returns the address the segment was attached at
+pub fn sys_shmat() -> SysResult {
+    let (id, addr, flags) = (argraw(0), argraw(1), argraw(2));
+    let p = myproc().expect("shmat: no process");
+    let aspace = p.aspace.as_mut().ok_or(Errno::EINVAL)?;
+    Ok(shmat(aspace, id, addr, flags)?)
+}
+
+// shmdt(addr)
+// Detach the segment attached at addr; it goes away after its last detach
+pub fn sys_shmdt() -> SysResult {
+    let addr = argraw(0);
+    let p = myproc().expect("shmdt: no process");
+    let aspace = p.aspace.as_mut().ok_or(Errno::EINVAL)?;
+    shmdt(aspace, addr)?;
+    Ok(0)
+}
+
+// memfd_create(size)
+// Returns a descriptor for a new zero-filled file of size bytes, held in memory
+pub fn sys_memfd_create() -> SysResult {
+    let size = argraw(0);
+    if size > MAXFILE {
+        return Err(Errno::EINVAL);
+    }
+    let file = MemFile::new(size).map_err(|_| Errno::ENOMEM)?;
+    let p = myproc().expect("memfd_create: no process");
+    fdalloc(p, Arc::new(file)).ok_or(Errno::EMFILE)
+}
+
+// close(fd)
+// The file itself lives on while a mapping of it remains
+pub fn sys_close() -> SysResult {
+    let fd = argraw(0);
+    let p = myproc().expect("close: no process");
+    p.ofile
+        .get_mut(fd)
+        .and_then(Option::take)
+        .ok_or(Errno::EBADF)?;
+    Ok(0)
+}
+
+// getrlimit(resource)
+// Returns the current limit
+pub fn sys_getrlimit() -> SysResult {
+    let resource = argraw(0);
+    let p = myproc().expect("getrlimit: no process");
+    let aspace = p.aspace.as_ref().ok_or(Errno::EINVAL)?;
+    match resource {
+        RLIMIT_STACK => Ok(aspace.stack_rlimit()),
+        _ => Err(Errno::EINVAL),
+    }
+}
+
+// setrlimit(resource, limit)
+// A stack limit below the current stack size only stops it growing further
+// One that would let the stack grow into the heap or a mapping is refused
+pub fn sys_setrlimit() -> SysResult {
+    let (resource, limit) = (argraw(0), argraw(1));
+    let p = myproc().expect("setrlimit: no process");
+    let aspace = p.aspace.as_mut().ok_or(Errno::EINVAL)?;
+    match resource {
+        RLIMIT_STACK if limit <= STACK_RLIMIT_MAX => {
+            aspace.set_stack_rlimit(limit).map_err(|_| Errno::EINVAL)?
+        }
+        _ => return Err(Errno::EINVAL),
+    }
+    Ok(0)
+}
+
+// exit(status)
+// Does not return; status goes to whoever waits for the process
+pub fn sys_exit() -> SysResult {
+    exit(argraw(0) as i32)
+}
+
+// yield()
+// Give up the hart to another runnable process
+pub fn sys_yield() -> SysResult {
+    yield_();
+    Ok(0)
+}
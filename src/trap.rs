@@ -0,0 +1,261 @@
+use crate::arch::{
+    clear_sstatus, from_supervisor, intr_get, intr_off, intr_on, read_scause, read_sepc,
+    read_sstatus, read_stval, read_threadptr, read_time, restore_sstatus, set_sstatus, write_sepc,
+    write_stimecmp, write_stvec, write_user_sepc, write_user_stvec, PreviousInterruptEnableSStatus,
+    PrivilegeModeSStatus, ScauseVal,
+};
+use crate::ipi::ipiintr;
+use crate::kernelvec::kernelvec;
+use crate::memset::{TimerCompareValue, ValidAddress, PGSIZE, TRAMPOLINE};
+use crate::plic::{plic_claim, plic_complete};
+use crate::println;
+use crate::proc::{exit, myproc, wakeup, wakeup_expired, yield_, Proc};
+use crate::spinlock::Spinlock;
+use crate::syscall::syscall;
+use crate::trampoline::{trampoline, userret, uservec};
+use crate::uart::{uartintr, UART0_IRQ};
+use crate::virtio_disk::{virtio_disk_intr, VIRTIO0_IRQ};
+use crate::vm::{kernel_satp, PteFlags, VmError};
+use core::ptr::addr_of;
+
+// Trap causes handled from user space
+const ENVIRONMENT_CALL_FROM_U_MODE: usize = ScauseVal::EnvironmentCallFromUMode as usize;
+const INSTRUCTION_PAGE_FAULT: usize = ScauseVal::InstructionPageFault as usize;
+const LOAD_PAGE_FAULT: usize = ScauseVal::LoadPageFault as usize;
+const STORE_PAGE_FAULT: usize = ScauseVal::StorePageFault as usize;
+const SUPERVISOR_SOFTWARE_INTERRUPT: usize = ScauseVal::SupervisorSoftwareInterrupt as usize;
+const SUPERVISOR_TIMER_INTERRUPT: usize = ScauseVal::SupervisorTimerInterrupt as usize;
+const SUPERVISOR_EXTERNAL_INTERRUPT: usize = ScauseVal::SupervisorExternalInterrupt as usize;
+
+// Cycles of the time counter between timer interrupts, about 1/10th second in QEMU
+pub const TIMER_INTERVAL: usize = 1_000_000;
+
+// Timer ticks since boot
+pub static TICKS: Spinlock<usize> = Spinlock::new(0, "time");
+
+pub fn ticks() -> usize {
+    *TICKS.lock()
+}
+
+// Set up to take exceptions and traps while in the kernel
+pub fn trapinithart() {
+    let vec = ValidAddress::new(addr_of!(kernelvec) as usize).expect("trapinithart: kernelvec");
+    write_stvec(vec);
+}
+
+// Handle an interrupt, exception, or system call from user space
+// Called from trampoline.rs through p.trapframe.kernel_trap
+pub extern "C" fn usertrap() -> ! {
+    if from_supervisor(read_sstatus()) {
+        panic!("usertrap: not from user mode");
+    }
+
+    // Send interrupts and exceptions to kerneltrap(), since we're now in the kernel
+    trapinithart();
+
+    let p = myproc().expect("usertrap: no process");
+    let tf = unsafe { &mut *p.trapframe };
+
+    // uservec has switched to the kernel page table
+    if let Some(aspace) = p.aspace.as_mut() {
+        aspace.leave(core::mem::take(&mut tf.tlb_flushes));
+    }
+
+    // Save user program counter
+    tf.epc = read_sepc();
+
+    let scause = read_scause();
+    match scause {
+        ENVIRONMENT_CALL_FROM_U_MODE => {
+            // sepc points to the ecall instruction, but we want to return to the next instruction
+            tf.epc += 4;
+            // An interrupt will change sepc, scause, and sstatus, so enable only now that we're done with those registers
+            intr_on();
+            syscall();
+        }
+        INSTRUCTION_PAGE_FAULT | LOAD_PAGE_FAULT | STORE_PAGE_FAULT => {
+            let stval = read_stval();
+            if let Err(e) = page_fault(p, scause, stval) {
+                fault_kill(p, scause, stval, e);
+            }
+        }
+        SUPERVISOR_SOFTWARE_INTERRUPT => ipiintr(),
+        SUPERVISOR_EXTERNAL_INTERRUPT => devintr(),
+        SUPERVISOR_TIMER_INTERRUPT => {
+            clockintr();
+            // Give up the CPU
+            yield_();
+        }
+        _ => {
+            println!(
+                "usertrap(): unexpected scause {:#x} pid={}\n            sepc={:#x} stval={:#x}",
+                scause,
+                p.pid,
+                read_sepc(),
+                read_stval()
+            );
+            p.set_killed();
+        }
+    }
+
+    if p.is_killed() {
+        exit(-1);
+    }
+    usertrapret()
+}
+
+// Return to user space, to the program counter and registers in p.trapframe
+pub fn usertrapret() -> ! {
+    let p = myproc().expect("usertrapret: no process");
+
+    // We're about to switch the destination of traps from kerneltrap() to usertrap(),
+    // so turn off interrupts until we're back in user space, where usertrap() is correct
+    intr_off();
+
+    // Send syscalls, interrupts, and exceptions to uservec in trampoline.rs
+    let tramp = addr_of!(trampoline) as usize;
+    write_user_stvec(TRAMPOLINE + (addr_of!(uservec) as usize - tramp));
+
+    // Set up trapframe values that uservec will need when the process next traps into the kernel
+    let tf = unsafe { &mut *p.trapframe };
+    tf.kernel_satp = kernel_satp();
+    tf.kernel_sp = p.kstack + PGSIZE;
+    tf.kernel_trap = usertrap as extern "C" fn() -> ! as usize;
+    tf.kernel_hartid = read_threadptr();
+
+    // Set up the registers that trampoline.rs's sret will use to get to user space:
+    // previous privilege mode user, and interrupts enabled once there
+    clear_sstatus(PrivilegeModeSStatus::SPP);
+    set_sstatus(PreviousInterruptEnableSStatus::SPIE);
+    write_user_sepc(tf.epc);
+
+    // Tell trampoline.rs the user page table to switch to
+    let satp = p
+        .aspace
+        .as_mut()
+        .expect("usertrapret: no address space")
+        .satp();
+
+    // Jump to userret in trampoline.rs at the top of memory, mapped in both
+    // the kernel and user page tables, which switches to the user page table,
+    // restores user registers, and switches to user mode with sret
+    let va = TRAMPOLINE + (addr_of!(userret) as usize - tramp);
+    let trampoline_userret: extern "C" fn(usize) -> ! = unsafe { core::mem::transmute(va) };
+    trampoline_userret(satp)
+}
+
+// Resolve a page fault at va, or fail if the access was not legitimate
+// The first touch of a demand-zero or mmap page maps it; a store to a read-only page
+// is only allowed for copy-on-write pages. A fault just below the stack grows it
+fn page_fault(p: &mut Proc, scause: usize, va: usize) -> Result<(), VmError> {
+    let aspace = p.aspace.as_mut().ok_or(VmError::NotMapped(va))?;
+    if aspace.below_stack(va) {
+        aspace.grow_stack(va)?;
+    }
+    if aspace.is_lazy(va) {
+        let access = match scause {
+            INSTRUCTION_PAGE_FAULT => PteFlags::X,
+            STORE_PAGE_FAULT => PteFlags::W,
+            _ => PteFlags::R,
+        };
+        return aspace.lazy_fault(va, access);
+    }
+    if scause == STORE_PAGE_FAULT {
+        return aspace.cow_fault(va);
+    }
+    Err(VmError::Protection(va))
+}
+
+// Report a page fault the kernel could not resolve and kill the process
+fn fault_kill(p: &mut Proc, scause: usize, stval: usize, e: VmError) {
+    match e {
+        VmError::StackOverflow(_) => println!(
+            "usertrap(): {} {}: stack overflow\n            sepc={:#x} stval={:#x}",
+            p.pid,
+            p.name(),
+            read_sepc(),
+            stval
+        ),
+        _ => println!(
+            "usertrap(): {} {}: page fault scause {:#x} {:?}\n            sepc={:#x} stval={:#x}",
+            p.pid,
+            p.name(),
+            scause,
+            e,
+            read_sepc(),
+            stval
+        ),
+    }
+    p.set_killed();
+}
+
+// Interrupts and exceptions from kernel code go here via kernelvec,
+// on whatever the current kernel stack is
+pub extern "C" fn kerneltrap() {
+    let sepc = read_sepc();
+    let sstatus = read_sstatus();
+    let scause = read_scause();
+
+    if !from_supervisor(sstatus) {
+        panic!("kerneltrap: not from supervisor mode");
+    }
+    if intr_get() {
+        panic!("kerneltrap: interrupts enabled");
+    }
+
+    match scause {
+        SUPERVISOR_SOFTWARE_INTERRUPT => ipiintr(),
+        SUPERVISOR_EXTERNAL_INTERRUPT => devintr(),
+        SUPERVISOR_TIMER_INTERRUPT => {
+            clockintr();
+            // Give up the CPU if this is a process's kernel thread
+            if myproc().is_some() {
+                yield_();
+            }
+        }
+        _ => panic!(
+            "kerneltrap: scause {:#x} sepc={:#x} stval={:#x}",
+            scause,
+            sepc,
+            read_stval()
+        ),
+    }
+
+    // yield_() may have caused some traps to occur,
+    // so restore trap registers for use by kernelvec's sret
+    write_sepc(ValidAddress::new(sepc).expect("kerneltrap: sepc"));
+    restore_sstatus(sstatus);
+}
+
+// Device interrupt, routed through the PLIC
+fn devintr() {
+    let irq = plic_claim();
+    match irq {
+        0 => {}
+        UART0_IRQ => uartintr(),
+        VIRTIO0_IRQ => virtio_disk_intr(),
+        _ => println!("unexpected interrupt irq={}", irq),
+    }
+    // The PLIC allows each device to raise at most one
+    // interrupt at a time; tell it this one is done
+    if irq != 0 {
+        plic_complete(irq);
+    }
+}
+
+// Timer interrupt
+// Hart 0 keeps time; every hart gets a chance to reschedule
+pub fn clockintr() {
+    if read_threadptr() == 0 {
+        let mut ticks = TICKS.lock();
+        *ticks += 1;
+        let now = *ticks;
+        drop(ticks);
+        wakeup(&TICKS as *const _ as usize);
+        wakeup_expired(now);
+    }
+
+    // Ask for the next timer interrupt. This also clears the interrupt request
+    let next = TimerCompareValue::new(read_time() + TIMER_INTERVAL).expect("clockintr");
+    write_stimecmp(next);
+}
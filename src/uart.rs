@@ -0,0 +1,70 @@
+// Low-level driver routines for the 16550a UART on QEMU's virt machine
+
+use crate::console::consoleintr;
+use crate::memset::UART0;
+use core::ptr::{read_volatile, write_volatile};
+
+pub const UART0_IRQ: usize = 10;
+
+// UART control registers, offsets from UART0
+const RHR: usize = 0; // Receive holding register (for input bytes)
+const THR: usize = 0; // Transmit holding register (for output bytes)
+const IER: usize = 1; // Interrupt enable register
+const FCR: usize = 2; // FIFO control register
+const LCR: usize = 3; // Line control register
+const LSR: usize = 5; // Line status register
+
+const IER_RX_ENABLE: u8 = 1;
+const FCR_FIFO_ENABLE: u8 = 1;
+const FCR_FIFO_CLEAR: u8 = 3 << 1; // Clear the content of the two FIFOs
+const LCR_EIGHT_BITS: u8 = 3;
+const LCR_BAUD_LATCH: u8 = 1 << 7; // Special mode to set baud rate
+const LSR_RX_READY: u8 = 1; // Input is waiting to be read from RHR
+const LSR_TX_IDLE: u8 = 1 << 5; // THR can accept another character to send
+
+fn read_reg(reg: usize) -> u8 {
+    unsafe { read_volatile((UART0 + reg) as *const u8) }
+}
+
+fn write_reg(reg: usize, val: u8) {
+    unsafe { write_volatile((UART0 + reg) as *mut u8, val) }
+}
+
+pub fn uartinit() {
+    // Disable interrupts
+    write_reg(IER, 0x00);
+    // Special mode to set baud rate, LSB then MSB for 38.4K
+    write_reg(LCR, LCR_BAUD_LATCH);
+    write_reg(0, 0x03);
+    write_reg(1, 0x00);
+    // Leave set-baud mode, and set word length to 8 bits, no parity
+    write_reg(LCR, LCR_EIGHT_BITS);
+    // Reset and enable FIFOs
+    write_reg(FCR, FCR_FIFO_ENABLE | FCR_FIFO_CLEAR);
+    // Interrupt when input arrives
+    write_reg(IER, IER_RX_ENABLE);
+}
+
+// Write one character, spinning until the UART is ready
+// Usable from interrupt context and from panic
+pub fn putc_sync(c: u8) {
+    while read_reg(LSR) & LSR_TX_IDLE == 0 {}
+    write_reg(THR, c);
+}
+
+// Read one input character, or None if none is waiting
+pub fn getc() -> Option<u8> {
+    if read_reg(LSR) & LSR_RX_READY != 0 {
+        Some(read_reg(RHR))
+    } else {
+        None
+    }
+}
+
+// Handle a UART interrupt, raised because input has arrived
+// Called from devintr()
+pub fn uartintr() {
+    while let Some(c) = getc() {
+        consoleintr(c);
+    }
+}
@@ -0,0 +1,94 @@
+// Definitions shared by the virtio drivers, from the virtio 1.1 spec's MMIO
+// transport and split virtqueues
+// QEMU's virt machine has eight MMIO slots, a page apart from VIRTIO0, that
+// -device ...,bus=virtio-mmio-bus.N fills in order
+
+use core::ptr::{read_volatile, write_volatile};
+
+// virtio MMIO control registers, offsets from the device's slot
+pub const MMIO_MAGIC_VALUE: usize = 0x000; // 0x74726976
+pub const MMIO_VERSION: usize = 0x004; // Version, 2 for modern devices
+pub const MMIO_DEVICE_ID: usize = 0x008; // Device type; 0 is an empty slot, 2 is disk, 4 is rng
+pub const MMIO_VENDOR_ID: usize = 0x00c; // 0x554d4551
+pub const MMIO_DEVICE_FEATURES: usize = 0x010;
+pub const MMIO_DRIVER_FEATURES: usize = 0x020;
+pub const MMIO_QUEUE_SEL: usize = 0x030; // Select queue, write-only
+pub const MMIO_QUEUE_NUM_MAX: usize = 0x034; // Max size of current queue, read-only
+pub const MMIO_QUEUE_NUM: usize = 0x038; // Size of current queue, write-only
+pub const MMIO_QUEUE_READY: usize = 0x044; // Ready bit
+pub const MMIO_QUEUE_NOTIFY: usize = 0x050; // Write-only
+pub const MMIO_INTERRUPT_STATUS: usize = 0x060; // Read-only
+pub const MMIO_INTERRUPT_ACK: usize = 0x064; // Write-only
+pub const MMIO_STATUS: usize = 0x070; // Read/write
+pub const MMIO_QUEUE_DESC_LOW: usize = 0x080; // Physical address of the descriptor table
+pub const MMIO_QUEUE_DESC_HIGH: usize = 0x084;
+pub const MMIO_DRIVER_DESC_LOW: usize = 0x090; // Physical address of the available ring
+pub const MMIO_DRIVER_DESC_HIGH: usize = 0x094;
+pub const MMIO_DEVICE_DESC_LOW: usize = 0x0a0; // Physical address of the used ring
+pub const MMIO_DEVICE_DESC_HIGH: usize = 0x0a4;
+pub const MMIO_CONFIG: usize = 0x100; // Device configuration, for a disk its capacity in sectors
+
+pub const VIRTIO_MAGIC: u32 = 0x7472_6976;
+pub const VIRTIO_VENDOR_QEMU: u32 = 0x554d_4551;
+
+// Status register bits
+pub const VIRTIO_CONFIG_S_ACKNOWLEDGE: u32 = 1;
+pub const VIRTIO_CONFIG_S_DRIVER: u32 = 2;
+pub const VIRTIO_CONFIG_S_DRIVER_OK: u32 = 4;
+pub const VIRTIO_CONFIG_S_FEATURES_OK: u32 = 8;
+
+// Feature bits of any device that the drivers turn down
+pub const VIRTIO_F_ANY_LAYOUT: u32 = 27;
+pub const VIRTIO_RING_F_INDIRECT_DESC: u32 = 28;
+pub const VIRTIO_RING_F_EVENT_IDX: u32 = 29;
+
+// A descriptor: one buffer of a request
+#[repr(C)]
+#[derive(Copy, Clone)]
+pub struct VirtqDesc {
+    pub addr: u64,
+    pub len: u32,
+    pub flags: u16,
+    pub next: u16,
+}
+pub const VRING_DESC_F_NEXT: u16 = 1; // Chained with another descriptor
+pub const VRING_DESC_F_WRITE: u16 = 2; // Device writes (vs read)
+
+// The available ring of a queue of N descriptors: chains the driver wants
+// the device to process
+#[repr(C)]
+pub struct VirtqAvail<const N: usize> {
+    pub flags: u16,
+    pub idx: u16,       // Where the driver will put the next entry, mod N
+    pub ring: [u16; N], // Descriptor numbers of chain heads
+    pub unused: u16,
+}
+
+// One entry in the used ring, with which the device tells the driver
+// about a completed request
+#[repr(C)]
+pub struct VirtqUsedElem {
+    pub id: u32, // Index of start of completed descriptor chain
+    pub len: u32,
+}
+
+#[repr(C)]
+pub struct VirtqUsed<const N: usize> {
+    pub flags: u16,
+    pub idx: u16, // Where the device will put the next entry, mod N
+    pub ring: [VirtqUsedElem; N],
+}
+
+pub fn read_reg(base: usize, reg: usize) -> u32 {
+    unsafe { read_volatile((base + reg) as *const u32) }
+}
+
+pub fn write_reg(base: usize, reg: usize, val: u32) {
+    unsafe { write_volatile((base + reg) as *mut u32, val) }
+}
+
+// Give the device the physical address of a queue part, in two halves
+pub fn write_addr(base: usize, low: usize, high: usize, addr: usize) {
+    write_reg(base, low, addr as u32);
+    write_reg(base, high, (addr >> 32) as u32);
+}
@@ -0,0 +1,367 @@
+// Driver for QEMU's virtio block device, which holds the swap area
+// QEMU attaches a disk image with
+//   -drive file=swap.img,if=none,format=raw,id=x0
+//   -device virtio-blk-device,drive=x0,bus=virtio-mmio-bus.0
+// Requests go through a single split virtqueue of NUM descriptors (see virtio.rs)
+
+use crate::kalloc::{kzalloc, PagePurpose};
+use crate::memset::{ValidAddress, VIRTIO0};
+use crate::println;
+use crate::proc::{sleep, wakeup};
+use crate::spinlock::Spinlock;
+use crate::swap::{swapon, BlockDevice, SECTOR_SIZE};
+use crate::virtio::{self, *};
+use alloc::sync::Arc;
+use core::mem::size_of;
+use core::ptr::{addr_of, addr_of_mut, null_mut, read_volatile, write_volatile};
+use core::sync::atomic::{fence, Ordering};
+
+pub const VIRTIO0_IRQ: usize = 1;
+
+const VIRTIO_DEVICE_BLOCK: u32 = 2;
+
+// Device feature bits the driver turns down
+const VIRTIO_BLK_F_RO: u32 = 5; // Disk is read-only
+const VIRTIO_BLK_F_SCSI: u32 = 7; // Supports scsi command passthru
+const VIRTIO_BLK_F_CONFIG_WCE: u32 = 11; // Writeback mode available in config
+const VIRTIO_BLK_F_MQ: u32 = 12; // Supports more than one vq
+
+// Descriptors in the queue, a power of two
+// Each request takes three
+const NUM: usize = 8;
+
+type VirtqAvail = virtio::VirtqAvail<NUM>;
+type VirtqUsed = virtio::VirtqUsed<NUM>;
+
+// Block request types
+const VIRTIO_BLK_T_IN: u32 = 0; // Read the disk
+const VIRTIO_BLK_T_OUT: u32 = 1; // Write the disk
+
+// The first descriptor of a request points here
+#[repr(C)]
+#[derive(Copy, Clone)]
+struct BlkReq {
+    kind: u32,
+    reserved: u32,
+    sector: u64,
+}
+
+// Bookkeeping for a request in flight, by its first descriptor
+#[derive(Copy, Clone)]
+struct Info {
+    status: u8, // Written by the device, 0 for success
+    done: bool, // Set by virtio_disk_intr()
+}
+
+struct Disk {
+    // The three parts of the queue, each in a page of its own
+    desc: *mut VirtqDesc,
+    avail: *mut VirtqAvail,
+    used: *mut VirtqUsed,
+
+    free: [bool; NUM], // Is each descriptor free?
+    used_idx: u16,     // How far we have looked in used.ring
+    info: [Info; NUM],
+    ops: [BlkReq; NUM], // Request headers, kept here so the device can read them
+}
+
+// The queue is only touched with DISK held
+unsafe impl Send for Disk {}
+
+static DISK: Spinlock<Disk> = Spinlock::new(
+    Disk {
+        desc: null_mut(),
+        avail: null_mut(),
+        used: null_mut(),
+        free: [false; NUM],
+        used_idx: 0,
+        info: [Info {
+            status: 0,
+            done: false,
+        }; NUM],
+        ops: [BlkReq {
+            kind: 0,
+            reserved: 0,
+            sector: 0,
+        }; NUM],
+    },
+    "virtio_disk",
+);
+
+fn read_reg(reg: usize) -> u32 {
+    virtio::read_reg(VIRTIO0, reg)
+}
+
+fn write_reg(reg: usize, val: u32) {
+    virtio::write_reg(VIRTIO0, reg, val)
+}
+
+fn write_addr(low: usize, high: usize, addr: usize) {
+    virtio::write_addr(VIRTIO0, low, high, addr)
+}
+
+// Set up the disk, if QEMU has one attached, and make all of it the swap area
+pub fn virtio_disk_init() {
+    if read_reg(MMIO_MAGIC_VALUE) != VIRTIO_MAGIC
+        || read_reg(MMIO_VERSION) != 2
+        || read_reg(MMIO_DEVICE_ID) != VIRTIO_DEVICE_BLOCK
+        || read_reg(MMIO_VENDOR_ID) != VIRTIO_VENDOR_QEMU
+    {
+        println!("no virtio disk, so no swap");
+        return;
+    }
+
+    // Reset the device
+    let mut status = 0;
+    write_reg(MMIO_STATUS, status);
+
+    // Set ACKNOWLEDGE and DRIVER status bits
+    status |= VIRTIO_CONFIG_S_ACKNOWLEDGE;
+    write_reg(MMIO_STATUS, status);
+    status |= VIRTIO_CONFIG_S_DRIVER;
+    write_reg(MMIO_STATUS, status);
+
+    // Negotiate features
+    let features = read_reg(MMIO_DEVICE_FEATURES)
+        & !(1 << VIRTIO_BLK_F_RO)
+        & !(1 << VIRTIO_BLK_F_SCSI)
+        & !(1 << VIRTIO_BLK_F_CONFIG_WCE)
+        & !(1 << VIRTIO_BLK_F_MQ)
+        & !(1 << VIRTIO_F_ANY_LAYOUT)
+        & !(1 << VIRTIO_RING_F_EVENT_IDX)
+        & !(1 << VIRTIO_RING_F_INDIRECT_DESC);
+    write_reg(MMIO_DRIVER_FEATURES, features);
+
+    // Tell device that feature negotiation is complete
+    status |= VIRTIO_CONFIG_S_FEATURES_OK;
+    write_reg(MMIO_STATUS, status);
+
+    // Re-read status to ensure FEATURES_OK is set
+    if read_reg(MMIO_STATUS) & VIRTIO_CONFIG_S_FEATURES_OK == 0 {
+        panic!("virtio disk FEATURES_OK unset");
+    }
+
+    // Initialise queue 0
+    write_reg(MMIO_QUEUE_SEL, 0);
+    if read_reg(MMIO_QUEUE_READY) != 0 {
+        panic!("virtio disk should not be ready");
+    }
+    let max = read_reg(MMIO_QUEUE_NUM_MAX) as usize;
+    if max == 0 {
+        panic!("virtio disk has no queue 0");
+    }
+    if max < NUM {
+        panic!("virtio disk max queue too short");
+    }
+
+    // Allocate and zero queue memory
+    let desc = kzalloc(PagePurpose::Buffer).expect("virtio disk: out of memory");
+    let avail = kzalloc(PagePurpose::Buffer).expect("virtio disk: out of memory");
+    let used = kzalloc(PagePurpose::Buffer).expect("virtio disk: out of memory");
+
+    // Set queue size and write physical addresses
+    write_reg(MMIO_QUEUE_NUM, NUM as u32);
+    write_addr(MMIO_QUEUE_DESC_LOW, MMIO_QUEUE_DESC_HIGH, desc);
+    write_addr(MMIO_DRIVER_DESC_LOW, MMIO_DRIVER_DESC_HIGH, avail);
+    write_addr(MMIO_DEVICE_DESC_LOW, MMIO_DEVICE_DESC_HIGH, used);
+
+    // Queue is ready
+    write_reg(MMIO_QUEUE_READY, 1);
+
+    let mut disk = DISK.lock();
+    disk.desc = desc as *mut VirtqDesc;
+    disk.avail = avail as *mut VirtqAvail;
+    disk.used = used as *mut VirtqUsed;
+    disk.free = [true; NUM];
+    drop(disk);
+
+    // Tell device we're completely ready
+    status |= VIRTIO_CONFIG_S_DRIVER_OK;
+    write_reg(MMIO_STATUS, status);
+
+    let capacity = read_reg(MMIO_CONFIG) as usize | (read_reg(MMIO_CONFIG + 4) as usize) << 32;
+    match swapon(Arc::new(VirtioDisk), 0, capacity) {
+        Ok(()) => println!("swap: {} KiB on virtio disk", capacity * SECTOR_SIZE / 1024),
+        Err(e) => println!("virtio disk: {}", e),
+    }
+}
+
+impl Disk {
+    // Find a free descriptor and mark it taken
+    fn alloc_desc(&mut self) -> Option<usize> {
+        let i = self.free.iter().position(|&free| free)?;
+        self.free[i] = false;
+        Some(i)
+    }
+
+    fn free_desc(&mut self, i: usize) {
+        if self.free[i] {
+            panic!("virtio disk: free_desc {}", i);
+        }
+        unsafe {
+            *self.desc.add(i) = VirtqDesc {
+                addr: 0,
+                len: 0,
+                flags: 0,
+                next: 0,
+            }
+        };
+        self.free[i] = true;
+        wakeup(addr_of!(self.free) as usize);
+    }
+
+    // Free the chain of descriptors starting at i
+    fn free_chain(&mut self, mut i: usize) {
+        loop {
+            let desc = unsafe { *self.desc.add(i) };
+            self.free_desc(i);
+            if desc.flags & VRING_DESC_F_NEXT == 0 {
+                break;
+            }
+            i = desc.next as usize;
+        }
+    }
+
+    // Take three descriptors, which need not be contiguous
+    fn alloc3_desc(&mut self) -> Option<[usize; 3]> {
+        let mut idx = [0; 3];
+        for n in 0..3 {
+            match self.alloc_desc() {
+                Some(i) => idx[n] = i,
+                None => {
+                    for &i in &idx[..n] {
+                        self.free_desc(i);
+                    }
+                    return None;
+                }
+            }
+        }
+        Some(idx)
+    }
+}
+
+// Read or write the sectors starting at sector from or to the buffer at
+// physical address buf, and sleep until the device is done
+fn disk_rw(sector: usize, buf: usize, len: usize, write: bool) -> Result<(), &'static str> {
+    if len == 0 || !len.is_multiple_of(SECTOR_SIZE) {
+        return Err("virtio disk: partial sector");
+    }
+    if ValidAddress::new(buf).is_err() || ValidAddress::new(buf + len - 1).is_err() {
+        return Err("virtio disk: buffer not in the direct map");
+    }
+
+    let mut disk = DISK.lock();
+    if disk.desc.is_null() {
+        return Err("virtio disk: not set up");
+    }
+
+    // The spec's Section 5.2 says that legacy block operations use three
+    // descriptors: one for type/reserved/sector, one for the data, one for
+    // a 1-byte status result
+    let idx = loop {
+        if let Some(idx) = disk.alloc3_desc() {
+            break idx;
+        }
+        let chan = addr_of!(disk.free) as usize;
+        disk = sleep(chan, disk);
+    };
+
+    disk.ops[idx[0]] = BlkReq {
+        kind: if write {
+            VIRTIO_BLK_T_OUT
+        } else {
+            VIRTIO_BLK_T_IN
+        },
+        reserved: 0,
+        sector: sector as u64,
+    };
+    disk.info[idx[0]] = Info {
+        status: 0xff, // Device writes 0 on success
+        done: false,
+    };
+    let header = addr_of!(disk.ops[idx[0]]) as u64;
+    let status = addr_of_mut!(disk.info[idx[0]].status) as u64;
+    unsafe {
+        *disk.desc.add(idx[0]) = VirtqDesc {
+            addr: header,
+            len: size_of::<BlkReq>() as u32,
+            flags: VRING_DESC_F_NEXT,
+            next: idx[1] as u16,
+        };
+        *disk.desc.add(idx[1]) = VirtqDesc {
+            addr: buf as u64,
+            len: len as u32,
+            // The device reads the buffer for a write, and writes it for a read
+            flags: VRING_DESC_F_NEXT | if write { 0 } else { VRING_DESC_F_WRITE },
+            next: idx[2] as u16,
+        };
+        *disk.desc.add(idx[2]) = VirtqDesc {
+            addr: status,
+            len: 1,
+            flags: VRING_DESC_F_WRITE, // Device writes the status
+            next: 0,
+        };
+
+        // Tell the device the first index in our chain of descriptors
+        let avail = disk.avail;
+        let slot = read_volatile(addr_of!((*avail).idx)) as usize % NUM;
+        write_volatile(addr_of_mut!((*avail).ring[slot]), idx[0] as u16);
+        fence(Ordering::SeqCst);
+        // Tell the device another avail ring entry is available
+        let next = read_volatile(addr_of!((*avail).idx)).wrapping_add(1);
+        write_volatile(addr_of_mut!((*avail).idx), next);
+        fence(Ordering::SeqCst);
+    }
+    write_reg(MMIO_QUEUE_NOTIFY, 0); // Value is queue number
+
+    // Wait for virtio_disk_intr() to say the request has finished
+    while !disk.info[idx[0]].done {
+        let chan = addr_of!(disk.info[idx[0]]) as usize;
+        disk = sleep(chan, disk);
+    }
+    let status = unsafe { read_volatile(addr_of!(disk.info[idx[0]].status)) };
+    disk.free_chain(idx[0]);
+    match status {
+        0 => Ok(()),
+        _ => Err("virtio disk: I/O error"),
+    }
+}
+
+// Disk interrupt: mark finished requests done and wake their callers
+pub fn virtio_disk_intr() {
+    let mut disk = DISK.lock();
+
+    // The device won't raise another interrupt until we tell it we've seen
+    // this one, which the following line does. This may race with the device
+    // writing new entries to the used ring, in which case we may process the
+    // new completion entries in this interrupt, and have nothing to do in the
+    // next interrupt, which is harmless
+    write_reg(MMIO_INTERRUPT_ACK, read_reg(MMIO_INTERRUPT_STATUS) & 0x3);
+    fence(Ordering::SeqCst);
+
+    // The device increments used.idx when it adds an entry to the used ring
+    let used = disk.used;
+    while disk.used_idx != unsafe { read_volatile(addr_of!((*used).idx)) } {
+        fence(Ordering::SeqCst);
+        let slot = disk.used_idx as usize % NUM;
+        let id = unsafe { read_volatile(addr_of!((*used).ring[slot].id)) } as usize;
+        disk.info[id].done = true;
+        wakeup(addr_of!(disk.info[id]) as usize);
+        disk.used_idx = disk.used_idx.wrapping_add(1);
+    }
+}
+
+// The disk, as the swap area's block device
+// Buffers are addressed through the kernel's direct map, so their virtual
+// addresses are the physical ones the device needs
+pub struct VirtioDisk;
+
+impl BlockDevice for VirtioDisk {
+    fn read(&self, sector: usize, buf: &mut [u8]) -> Result<(), &'static str> {
+        disk_rw(sector, buf.as_mut_ptr() as usize, buf.len(), false)
+    }
+
+    fn write(&self, sector: usize, buf: &[u8]) -> Result<(), &'static str> {
+        disk_rw(sector, buf.as_ptr() as usize, buf.len(), true)
+    }
+}
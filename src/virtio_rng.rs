@@ -0,0 +1,146 @@
+// Driver for QEMU's virtio entropy device, a source of random bytes for the
+// kernel entropy pool
+// QEMU attaches one with
+//   -device virtio-rng-device,bus=virtio-mmio-bus.1
+// The kernel only draws from it once, at boot, so the driver polls a single
+// descriptor instead of taking interrupts, then resets the device and frees
+// the queue
+
+use crate::kalloc::{kfree, kzalloc, PagePurpose};
+use crate::memset::VIRTIO1;
+use crate::println;
+use crate::random::add_entropy_bytes;
+use crate::virtio::{self, *};
+use core::hint::spin_loop;
+use core::ptr::{addr_of, addr_of_mut, read_volatile, write_volatile};
+use core::sync::atomic::{fence, Ordering};
+
+const VIRTIO_DEVICE_ENTROPY: u32 = 4;
+
+// One descriptor is all a single request needs
+const NUM: usize = 1;
+
+// Bytes asked for at boot
+const SEED_BYTES: usize = 64;
+
+// The queue and the buffer the device fills share one page
+#[repr(C, align(16))]
+struct Queue {
+    desc: [VirtqDesc; NUM],
+    avail: virtio::VirtqAvail<NUM>,
+    used: virtio::VirtqUsed<NUM>,
+    buf: [u8; SEED_BYTES],
+}
+
+fn read_reg(reg: usize) -> u32 {
+    virtio::read_reg(VIRTIO1, reg)
+}
+
+fn write_reg(reg: usize, val: u32) {
+    virtio::write_reg(VIRTIO1, reg, val)
+}
+
+fn write_addr(low: usize, high: usize, addr: usize) {
+    virtio::write_addr(VIRTIO1, low, high, addr)
+}
+
+// Mix SEED_BYTES from the device into the entropy pool
+// Called by hart 0 at boot, before any process runs
+pub fn virtio_rng_init() {
+    if read_reg(MMIO_MAGIC_VALUE) != VIRTIO_MAGIC
+        || read_reg(MMIO_VERSION) != 2
+        || read_reg(MMIO_DEVICE_ID) != VIRTIO_DEVICE_ENTROPY
+        || read_reg(MMIO_VENDOR_ID) != VIRTIO_VENDOR_QEMU
+    {
+        println!("no virtio rng, entropy from timing jitter and rng-seed only");
+        return;
+    }
+
+    // Reset the device, then set ACKNOWLEDGE and DRIVER status bits
+    let mut status = 0;
+    write_reg(MMIO_STATUS, status);
+    status |= VIRTIO_CONFIG_S_ACKNOWLEDGE;
+    write_reg(MMIO_STATUS, status);
+    status |= VIRTIO_CONFIG_S_DRIVER;
+    write_reg(MMIO_STATUS, status);
+
+    // The entropy device has no features of its own; turn down the ring ones
+    let features = read_reg(MMIO_DEVICE_FEATURES)
+        & !(1 << VIRTIO_F_ANY_LAYOUT)
+        & !(1 << VIRTIO_RING_F_EVENT_IDX)
+        & !(1 << VIRTIO_RING_F_INDIRECT_DESC);
+    write_reg(MMIO_DRIVER_FEATURES, features);
+    status |= VIRTIO_CONFIG_S_FEATURES_OK;
+    write_reg(MMIO_STATUS, status);
+    if read_reg(MMIO_STATUS) & VIRTIO_CONFIG_S_FEATURES_OK == 0 {
+        println!("virtio rng: FEATURES_OK unset");
+        write_reg(MMIO_STATUS, 0);
+        return;
+    }
+
+    // Initialise queue 0
+    write_reg(MMIO_QUEUE_SEL, 0);
+    if read_reg(MMIO_QUEUE_READY) != 0 || read_reg(MMIO_QUEUE_NUM_MAX) == 0 {
+        println!("virtio rng: no usable queue 0");
+        write_reg(MMIO_STATUS, 0);
+        return;
+    }
+    let Some(page) = kzalloc(PagePurpose::Buffer) else {
+        println!("virtio rng: out of memory");
+        write_reg(MMIO_STATUS, 0);
+        return;
+    };
+    let q = page as *mut Queue;
+    write_reg(MMIO_QUEUE_NUM, NUM as u32);
+    unsafe {
+        write_addr(
+            MMIO_QUEUE_DESC_LOW,
+            MMIO_QUEUE_DESC_HIGH,
+            addr_of!((*q).desc) as usize,
+        );
+        write_addr(
+            MMIO_DRIVER_DESC_LOW,
+            MMIO_DRIVER_DESC_HIGH,
+            addr_of!((*q).avail) as usize,
+        );
+        write_addr(
+            MMIO_DEVICE_DESC_LOW,
+            MMIO_DEVICE_DESC_HIGH,
+            addr_of!((*q).used) as usize,
+        );
+    }
+    write_reg(MMIO_QUEUE_READY, 1);
+    status |= VIRTIO_CONFIG_S_DRIVER_OK;
+    write_reg(MMIO_STATUS, status);
+
+    // One device-writable descriptor for the whole buffer
+    unsafe {
+        (*q).desc[0] = VirtqDesc {
+            addr: addr_of!((*q).buf) as u64,
+            len: SEED_BYTES as u32,
+            flags: VRING_DESC_F_WRITE,
+            next: 0,
+        };
+        (*q).avail.ring[0] = 0;
+        fence(Ordering::SeqCst);
+        write_volatile(addr_of_mut!((*q).avail.idx), 1);
+        fence(Ordering::SeqCst);
+    }
+    write_reg(MMIO_QUEUE_NOTIFY, 0);
+
+    // Wait for the device to hand the descriptor back
+    while unsafe { read_volatile(addr_of!((*q).used.idx)) } == 0 {
+        spin_loop();
+    }
+    fence(Ordering::SeqCst);
+    write_reg(MMIO_INTERRUPT_ACK, read_reg(MMIO_INTERRUPT_STATUS) & 0x3);
+
+    let len = unsafe { read_volatile(addr_of!((*q).used.ring[0].len)) } as usize;
+    let len = len.min(SEED_BYTES);
+    add_entropy_bytes(unsafe { &(&(*q).buf)[..len] });
+    println!("virtio rng: {} bytes of entropy", len);
+
+    // Reset the device so it forgets the queue before the page is reused
+    write_reg(MMIO_STATUS, 0);
+    kfree(page);
+}
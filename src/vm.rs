@@ -0,0 +1,2717 @@
+// Sv39 virtual memory
+// Three levels of 512-entry page-table pages translate a 39-bit virtual address:
+//   38..30 -- 9 bits of level-2 index
+//   29..21 -- 9 bits of level-1 index
+//   20..12 -- 9 bits of level-0 index
+//   11..0  -- 12 bits of byte offset within the page
+
+use crate::arch::{
+    flush_tlb, flush_tlb_asid, flush_tlb_page, make_satp, read_hpmcounter, read_satp,
+    read_threadptr, write_satp, SatpMode, SATP_ASID_MASK, SATP_ASID_SHIFT,
+};
+use crate::ipi::send_ipi;
+use crate::kalloc::{
+    kalloc, kalloc_pages, kfree, kfree_pages, ksplit_pages, kzalloc, page_ref, page_refcount,
+    PagePurpose,
+};
+use crate::memset::{
+    pg_round_down, pg_round_up, CLINT, CLINT_SIZE, KERNEL_BASE_ADDRESS, MMAP_BASE, PGSHIFT, PGSIZE,
+    PHYSICAL_MEMORY_LIMIT, PIE_BASE, PLIC, PLIC_SIZE, TRAMPOLINE, TRAPFRAME, UART0, USTACK_TOP,
+    VIRTIO0, VIRTIO1, VIRT_TEST,
+};
+use crate::println;
+use crate::proc::{is_myproc, proc_mapstacks, with_idle_aspace, NCPU, NPROC};
+use crate::random::random_bits;
+use crate::sleeplock::SleepLock;
+use crate::spinlock::{pop_off, push_off, Spinlock};
+use crate::swap::{swap_alloc, swap_dup, swap_enabled, swap_free, swap_read, swap_write};
+use crate::trampoline::trampoline;
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::fmt::{self, Write};
+use core::hint::spin_loop;
+use core::marker::PhantomData;
+use core::mem::{size_of, MaybeUninit};
+use core::ops::{BitAnd, BitOr, BitOrAssign, Not};
+use core::ptr::{addr_of, copy_nonoverlapping, write_bytes};
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+extern "C" {
+    // End of kernel code, defined by kernel.ld
+    static etext: u8;
+}
+
+// One beyond the highest possible virtual address
+// MAXVA is actually one bit less than the max allowed by Sv39, to avoid
+// having to sign-extend virtual addresses that have the high bit set
+pub const MAXVA: usize = 1 << (9 + 9 + 9 + PGSHIFT - 1);
+
+const PTES_PER_PAGE: usize = 512;
+
+// Page table entry flags
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Default)]
+pub struct PteFlags(usize);
+
+impl PteFlags {
+    pub const V: PteFlags = PteFlags(1 << 0); // Valid
+    pub const R: PteFlags = PteFlags(1 << 1); // Readable
+    pub const W: PteFlags = PteFlags(1 << 2); // Writable
+    pub const X: PteFlags = PteFlags(1 << 3); // Executable
+    pub const U: PteFlags = PteFlags(1 << 4); // User-accessible
+    pub const G: PteFlags = PteFlags(1 << 5); // Global mapping
+    pub const A: PteFlags = PteFlags(1 << 6); // Accessed
+    pub const D: PteFlags = PteFlags(1 << 7); // Dirty
+
+    // Software bits (RSW), ignored by hardware
+    pub const COW: PteFlags = PteFlags(1 << 8); // Copy-on-write: shared and read-only until written
+    pub const SWAP: PteFlags = PteFlags(1 << 9); // Swapped out: not valid, and the PPN is a swap slot
+
+    const MASK: usize = 0x3FF; // Low ten bits, including the two RSW software bits
+
+    pub const fn empty() -> Self {
+        PteFlags(0)
+    }
+
+    pub const fn bits(self) -> usize {
+        self.0
+    }
+
+    pub const fn from_bits_truncate(bits: usize) -> Self {
+        PteFlags(bits & Self::MASK)
+    }
+
+    pub const fn contains(self, other: PteFlags) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    pub const fn intersects(self, other: PteFlags) -> bool {
+        self.0 & other.0 != 0
+    }
+
+    pub const fn is_empty(self) -> bool {
+        self.0 == 0
+    }
+}
+
+impl BitOr for PteFlags {
+    type Output = PteFlags;
+
+    fn bitor(self, rhs: PteFlags) -> PteFlags {
+        PteFlags(self.0 | rhs.0)
+    }
+}
+
+impl BitOrAssign for PteFlags {
+    fn bitor_assign(&mut self, rhs: PteFlags) {
+        self.0 |= rhs.0;
+    }
+}
+
+impl BitAnd for PteFlags {
+    type Output = PteFlags;
+
+    fn bitand(self, rhs: PteFlags) -> PteFlags {
+        PteFlags(self.0 & rhs.0)
+    }
+}
+
+impl Not for PteFlags {
+    type Output = PteFlags;
+
+    fn not(self) -> PteFlags {
+        PteFlags(!self.0 & Self::MASK)
+    }
+}
+
+// One letter per hardware bit, '-' where clear, as in "rw-u-ad"; then " cow" if set
+impl fmt::Display for PteFlags {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let bits = [
+            (PteFlags::R, 'r'),
+            (PteFlags::W, 'w'),
+            (PteFlags::X, 'x'),
+            (PteFlags::U, 'u'),
+            (PteFlags::G, 'g'),
+            (PteFlags::A, 'a'),
+            (PteFlags::D, 'd'),
+        ];
+        for (flag, c) in bits {
+            f.write_char(if self.contains(flag) { c } else { '-' })?;
+        }
+        if self.contains(PteFlags::COW) {
+            f.write_str(" cow")?;
+        }
+        Ok(())
+    }
+}
+
+// Page table entry: physical page number in bits 53..10, flags in 9..0
+#[repr(transparent)]
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Default)]
+pub struct Pte(usize);
+
+impl Pte {
+    pub const fn new(pa: usize, flags: PteFlags) -> Self {
+        Pte(((pa >> PGSHIFT) << 10) | flags.bits())
+    }
+
+    pub const fn pa(self) -> usize {
+        (self.0 >> 10) << PGSHIFT
+    }
+
+    pub const fn flags(self) -> PteFlags {
+        PteFlags::from_bits_truncate(self.0)
+    }
+
+    pub const fn is_valid(self) -> bool {
+        self.flags().contains(PteFlags::V)
+    }
+
+    // A valid PTE with any of R/W/X set maps memory; otherwise it points at the next level
+    pub const fn is_leaf(self) -> bool {
+        self.is_valid()
+            && self
+                .flags()
+                .intersects(PteFlags(PteFlags::R.0 | PteFlags::W.0 | PteFlags::X.0))
+    }
+
+    // A swap entry for a page evicted to slot, which kept flags while it was mapped
+    pub const fn swapped(slot: usize, flags: PteFlags) -> Self {
+        let flags = PteFlags((flags.bits() & !PteFlags::V.0) | PteFlags::SWAP.0);
+        Pte((slot << 10) | flags.bits())
+    }
+
+    pub const fn is_swapped(self) -> bool {
+        !self.is_valid() && self.flags().contains(PteFlags::SWAP)
+    }
+
+    // An entry for the page at pa, made inaccessible by mprotect(PROT_NONE)
+    // V is clear so that every access faults; the hardware ignores the other
+    // bits of an invalid entry, so U stays set to mark the page as still here
+    pub const fn inaccessible(pa: usize, flags: PteFlags) -> Self {
+        let rwx = PteFlags::V.0 | PteFlags::R.0 | PteFlags::W.0 | PteFlags::X.0;
+        let flags = PteFlags((flags.bits() & !rwx & !PteFlags::COW.0) | PteFlags::U.0);
+        Pte::new(pa, flags)
+    }
+
+    pub const fn is_inaccessible(self) -> bool {
+        !self.is_valid()
+            && !self.flags().contains(PteFlags::SWAP)
+            && self.flags().contains(PteFlags::U)
+    }
+
+    // Swap slot of a swap entry
+    pub const fn slot(self) -> usize {
+        self.0 >> 10
+    }
+
+    pub fn set_flags(&mut self, flags: PteFlags) {
+        self.0 = (self.0 & !PteFlags::MASK) | flags.bits();
+    }
+
+    pub fn clear(&mut self) {
+        self.0 = 0;
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum VmError {
+    OutOfMemory,          // No page available for a page-table page or data
+    Misaligned(usize),    // Address is not page aligned
+    BadAddress(usize),    // Address is at or above MAXVA
+    AlreadyMapped(usize), // Virtual page already has a valid mapping
+    NotMapped(usize),     // Virtual page has no valid mapping
+    Protection(usize),    // Mapping does not permit the access
+    Io,                   // Reading or writing a mapped file or swap failed
+    WriteExec(usize),     // User mapping would be both writable and executable
+    StackOverflow(usize), // Stack would grow past its rlimit or into its guard gap
+}
+
+// Index into the page-table page at level for va
+const fn px(level: usize, va: usize) -> usize {
+    (va >> (PGSHIFT + 9 * level)) & (PTES_PER_PAGE - 1)
+}
+
+// Bytes mapped by a leaf PTE at level: 4 KiB, 2 MiB or 1 GiB
+pub const fn level_size(level: usize) -> usize {
+    PGSIZE << (9 * level)
+}
+
+// Huge user mappings are made of 2 MiB superpages, each one kalloc_pages() block
+pub const HUGE_PGSIZE: usize = level_size(1);
+const HUGE_ORDER: usize = 9;
+
+// The 512 PTEs held in the page-table page at physical address pa
+// Physical memory is direct-mapped, so pa is also a usable pointer
+fn ptes<'a>(pa: usize) -> &'a mut [Pte; PTES_PER_PAGE] {
+    unsafe { &mut *(pa as *mut [Pte; PTES_PER_PAGE]) }
+}
+
+// Replace the superpage leaf pte at level with a page-table page of 512 leaves
+// one level down that map the same memory with the same permissions
+// User superpages come from kalloc_pages(), so their pages become individually freeable
+fn split(pte: &mut Pte, level: usize) -> Result<(), VmError> {
+    let table = kzalloc(PagePurpose::PageTable).ok_or(VmError::OutOfMemory)?;
+    let (pa, flags) = (pte.pa(), pte.flags());
+    for (i, sub) in ptes(table).iter_mut().enumerate() {
+        *sub = Pte::new(pa + i * level_size(level - 1), flags);
+    }
+    if flags.contains(PteFlags::U) {
+        ksplit_pages(pa, 9 * level);
+    }
+    *pte = Pte::new(table, PteFlags::V);
+    Ok(())
+}
+
+pub struct PageTable {
+    root: usize, // Physical address of the level-2 page-table page
+}
+
+impl PageTable {
+    // Create an empty page table
+    pub fn new() -> Result<Self, VmError> {
+        let root = kzalloc(PagePurpose::PageTable).ok_or(VmError::OutOfMemory)?;
+        Ok(PageTable { root })
+    }
+
+    // Physical address of the root page, for make_satp()
+    pub fn root(&self) -> usize {
+        self.root
+    }
+
+    // Return the level-0 PTE for va, creating any required page-table pages if alloc is set
+    // A superpage covering va is split, since the caller wants a single 4 KiB page of it
+    pub fn walk(&mut self, va: usize, alloc: bool) -> Result<&mut Pte, VmError> {
+        self.walk_level(va, 0, alloc)
+    }
+
+    // Return the PTE for va at level, splitting any superpage above it
+    fn walk_level(&mut self, va: usize, level: usize, alloc: bool) -> Result<&mut Pte, VmError> {
+        if va >= MAXVA {
+            return Err(VmError::BadAddress(va));
+        }
+        let mut table = self.root;
+        for l in (level + 1..=2).rev() {
+            let pte = &mut ptes(table)[px(l, va)];
+            if pte.is_leaf() {
+                split(pte, l)?;
+            } else if !pte.is_valid() {
+                if !alloc {
+                    return Err(VmError::NotMapped(va));
+                }
+                let page = kzalloc(PagePurpose::PageTable).ok_or(VmError::OutOfMemory)?;
+                *pte = Pte::new(page, PteFlags::V);
+            }
+            table = pte.pa();
+        }
+        Ok(&mut ptes(table)[px(level, va)])
+    }
+
+    // The leaf PTE that maps va and its level, if va is mapped
+    pub fn lookup_leaf(&self, va: usize) -> Option<(Pte, usize)> {
+        if va >= MAXVA {
+            return None;
+        }
+        let mut table = self.root;
+        for level in (0..=2).rev() {
+            let pte = ptes(table)[px(level, va)];
+            if pte.is_leaf() {
+                return Some((pte, level));
+            }
+            if !pte.is_valid() {
+                break;
+            }
+            table = pte.pa();
+        }
+        None
+    }
+
+    // The level-0 PTE for va, valid or not, if there is a page-table page for it
+    // Unlike walk(), never splits a superpage or allocates
+    pub fn lookup_entry(&self, va: usize) -> Option<Pte> {
+        if va >= MAXVA {
+            return None;
+        }
+        let mut table = self.root;
+        for level in (1..=2).rev() {
+            let pte = ptes(table)[px(level, va)];
+            if !pte.is_valid() || pte.is_leaf() {
+                return None;
+            }
+            table = pte.pa();
+        }
+        Some(ptes(table)[px(0, va)])
+    }
+
+    // The PTE for the 4 KiB page holding va, if va is mapped
+    // Inside a superpage this describes that page's piece of it
+    pub fn lookup(&self, va: usize) -> Option<Pte> {
+        let (pte, level) = self.lookup_leaf(va)?;
+        let offset = va & (level_size(level) - 1) & !(PGSIZE - 1);
+        Some(Pte::new(pte.pa() + offset, pte.flags()))
+    }
+
+    // Physical address that va maps to, or None if it is unmapped
+    pub fn translate(&self, va: usize) -> Option<usize> {
+        let pte = self.lookup(va)?;
+        Some(pte.pa() + (va & (PGSIZE - 1)))
+    }
+
+    // Create PTEs for virtual addresses starting at va that refer to physical
+    // addresses starting at pa. va, pa and size must be page aligned
+    // Superpages are used wherever va, pa and the remaining size allow, so a
+    // user range mapped in one call must be a single kalloc_pages() block
+    // Fails without leaving any new mapping behind if a page is already mapped
+    pub fn map_pages(
+        &mut self,
+        va: usize,
+        size: usize,
+        pa: usize,
+        perm: PteFlags,
+    ) -> Result<(), VmError> {
+        if !va.is_multiple_of(PGSIZE) {
+            return Err(VmError::Misaligned(va));
+        }
+        if !pa.is_multiple_of(PGSIZE) {
+            return Err(VmError::Misaligned(pa));
+        }
+        if size == 0 || !size.is_multiple_of(PGSIZE) {
+            return Err(VmError::Misaligned(size));
+        }
+        if va.checked_add(size).is_none_or(|last| last > MAXVA) {
+            return Err(VmError::BadAddress(va));
+        }
+
+        let mut offset = 0;
+        while offset < size {
+            match self.map_one(va + offset, pa + offset, size - offset, perm) {
+                Ok(n) => offset += n,
+                Err(e) => {
+                    if offset > 0 {
+                        self.unmap(va, offset / PGSIZE, false)
+                            .expect("map_pages: rollback");
+                    }
+                    return Err(e);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // Map the largest page that fits at va: a superpage if va, pa and len allow
+    // and no smaller pages are mapped there already, otherwise a 4 KiB page
+    // Returns the number of bytes mapped
+    fn map_one(
+        &mut self,
+        va: usize,
+        pa: usize,
+        len: usize,
+        perm: PteFlags,
+    ) -> Result<usize, VmError> {
+        for level in (0..=2).rev() {
+            let size = level_size(level);
+            if !va.is_multiple_of(size) || !pa.is_multiple_of(size) || len < size {
+                continue;
+            }
+            let pte = self.walk_level(va, level, true)?;
+            if pte.is_valid() && !pte.is_leaf() {
+                // A page-table page of smaller mappings is in the way
+                continue;
+            }
+            if pte.is_valid() || pte.is_swapped() || pte.is_inaccessible() {
+                return Err(VmError::AlreadyMapped(va));
+            }
+            *pte = Pte::new(pa, perm | PteFlags::V);
+            return Ok(size);
+        }
+        Err(VmError::Misaligned(va))
+    }
+
+    // Remove npages of mappings starting from va, which must be page aligned
+    // A superpage inside the range goes in one step; one only partly inside is split first
+    // Optionally drop a reference to the physical memory behind each
+    pub fn unmap(&mut self, va: usize, npages: usize, do_free: bool) -> Result<(), VmError> {
+        if !va.is_multiple_of(PGSIZE) {
+            return Err(VmError::Misaligned(va));
+        }
+        let end = va + npages * PGSIZE;
+        let mut a = va;
+        while a < end {
+            let (_, level) = self.lookup_leaf(a).ok_or(VmError::NotMapped(a))?;
+            let level = if a.is_multiple_of(level_size(level)) && end - a >= level_size(level) {
+                level
+            } else {
+                0
+            };
+            let pte = self.walk_level(a, level, false)?;
+            if do_free {
+                match level {
+                    0 => kfree(pte.pa()),
+                    _ => kfree_pages(pte.pa(), 9 * level),
+                }
+            }
+            pte.clear();
+            a += level_size(level);
+        }
+        Ok(())
+    }
+
+    // Print the page table on the console, one line per page-table page and
+    // one per run of leaves at a level that map contiguous memory with the same flags
+    // Superpages are shown as such rather than expanded into 4 KiB pages
+    pub fn vmprint(&self) {
+        println!("page table {:#x}", self.root);
+        vmprint_table(self.root, 2, 0);
+    }
+
+    // Recursively free page-table pages
+    // All leaf mappings must already have been removed
+    pub fn free(self) {
+        free_walk(self.root);
+    }
+}
+
+// Print the entries of the page-table page at level, which maps virtual addresses from base
+fn vmprint_table(table: usize, level: usize, base: usize) {
+    let entries = ptes(table);
+    let indent = 3 - level;
+    let size = level_size(level);
+    let mut i = 0;
+    while i < PTES_PER_PAGE {
+        let pte = entries[i];
+        let va = base + i * size;
+        if !pte.is_valid() {
+            i += 1;
+            continue;
+        }
+        if !pte.is_leaf() {
+            println!("{:>w$}{}: table {:#x}", "", i, pte.pa(), w = 3 * indent);
+            vmprint_table(pte.pa(), level - 1, va);
+            i += 1;
+            continue;
+        }
+
+        // Extend over following leaves that continue the same mapping
+        let mut n = 1;
+        while i + n < PTES_PER_PAGE {
+            let next = entries[i + n];
+            if !next.is_leaf() || next.flags() != pte.flags() || next.pa() != pte.pa() + n * size {
+                break;
+            }
+            n += 1;
+        }
+        let page = match level {
+            0 => "4K",
+            1 => "2M",
+            _ => "1G",
+        };
+        println!(
+            "{:>w$}{}: va {:#x}-{:#x} pa {:#x} {} {} x {}",
+            "",
+            IndexRange(i, i + n - 1),
+            va,
+            va + n * size,
+            pte.pa(),
+            pte.flags(),
+            n,
+            page,
+            w = 3 * indent
+        );
+        i += n;
+    }
+}
+
+// Displays as "i" or "first-last", for vmprint_table()
+struct IndexRange(usize, usize);
+
+impl fmt::Display for IndexRange {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.0 == self.1 {
+            write!(f, "{}", self.0)
+        } else {
+            write!(f, "{}-{}", self.0, self.1)
+        }
+    }
+}
+
+fn free_walk(table: usize) {
+    for pte in ptes(table).iter_mut() {
+        if !pte.is_valid() {
+            continue;
+        }
+        if pte.is_leaf() {
+            panic!("free_walk: leaf {:#x}", pte.pa());
+        }
+        free_walk(pte.pa());
+        pte.clear();
+    }
+    kfree(table);
+}
+
+// The kernel's page table, shared by every hart
+static KERNEL_PAGETABLE: Spinlock<Option<PageTable>> = Spinlock::new(None, "kvm");
+
+// Add a mapping to the kernel page table
+// Only used when booting, so a failure is fatal
+fn kvmmap(kpgtbl: &mut PageTable, va: usize, pa: usize, size: usize, perm: PteFlags) {
+    if let Err(e) = kpgtbl.map_pages(va, size, pa, perm) {
+        panic!("kvmmap {:#x}: {:?}", va, e);
+    }
+}
+
+// Make a direct-map page table for the kernel
+fn kvmmake() -> PageTable {
+    let mut kpgtbl = PageTable::new().expect("kvmmake: out of memory");
+    let text_end = addr_of!(etext) as usize;
+
+    // QEMU test finisher, so the kernel can power off
+    kvmmap(
+        &mut kpgtbl,
+        VIRT_TEST,
+        VIRT_TEST,
+        PGSIZE,
+        PteFlags::R | PteFlags::W,
+    );
+
+    // uart registers
+    kvmmap(&mut kpgtbl, UART0, UART0, PGSIZE, PteFlags::R | PteFlags::W);
+
+    // virtio mmio disk interface
+    kvmmap(
+        &mut kpgtbl,
+        VIRTIO0,
+        VIRTIO0,
+        PGSIZE,
+        PteFlags::R | PteFlags::W,
+    );
+
+    // virtio mmio entropy source
+    kvmmap(
+        &mut kpgtbl,
+        VIRTIO1,
+        VIRTIO1,
+        PGSIZE,
+        PteFlags::R | PteFlags::W,
+    );
+
+    // CLINT, for timer and software interrupts
+    kvmmap(
+        &mut kpgtbl,
+        CLINT,
+        CLINT,
+        CLINT_SIZE,
+        PteFlags::R | PteFlags::W,
+    );
+
+    // PLIC
+    kvmmap(
+        &mut kpgtbl,
+        PLIC,
+        PLIC,
+        PLIC_SIZE,
+        PteFlags::R | PteFlags::W,
+    );
+
+    // Map kernel text executable and read-only
+    kvmmap(
+        &mut kpgtbl,
+        KERNEL_BASE_ADDRESS,
+        KERNEL_BASE_ADDRESS,
+        text_end - KERNEL_BASE_ADDRESS,
+        PteFlags::R | PteFlags::X,
+    );
+
+    // Map kernel data and the physical RAM we'll make use of
+    // map_pages() covers the aligned bulk of RAM with superpages
+    kvmmap(
+        &mut kpgtbl,
+        text_end,
+        text_end,
+        PHYSICAL_MEMORY_LIMIT - text_end,
+        PteFlags::R | PteFlags::W,
+    );
+
+    // Map the trampoline for trap entry/exit to the highest virtual address in the kernel
+    let tramp = addr_of!(trampoline) as usize;
+    kvmmap(
+        &mut kpgtbl,
+        TRAMPOLINE,
+        tramp,
+        PGSIZE,
+        PteFlags::R | PteFlags::X,
+    );
+
+    // Allocate and map a kernel stack for each process
+    proc_mapstacks(&mut kpgtbl);
+
+    kpgtbl
+}
+
+// Create the kernel page table, once, on the boot hart
+pub fn kvminit() {
+    *KERNEL_PAGETABLE.lock() = Some(kvmmake());
+}
+
+// Switch this hart's page table register to the kernel's page table and enable paging
+// Called by every hart after kvminit()
+pub fn kvminithart() {
+    let root = KERNEL_PAGETABLE
+        .lock()
+        .as_ref()
+        .expect("kvminithart: no kernel page table")
+        .root();
+
+    // Wait for any previous writes to the page table memory to finish
+    flush_tlb();
+
+    // Find out how many ASID bits this hart implements: the rest read back as zero
+    write_satp(make_satp(root, SatpMode::Sv39, SATP_ASID_MASK));
+    let implemented = (read_satp() >> SATP_ASID_SHIFT) & SATP_ASID_MASK;
+    let mut asids = ASIDS.lock();
+    asids.bits = asids.bits.min(implemented.count_ones() as usize);
+    drop(asids);
+
+    write_satp(make_satp(root, SatpMode::Sv39, KERNEL_ASID));
+
+    // Flush stale entries from the TLB
+    flush_tlb();
+
+    // From now on this hart takes part in TLB shootdowns
+    HARTS_ONLINE.fetch_or(1 << read_threadptr(), Ordering::Release);
+}
+
+// Print the kernel page table on the console
+pub fn kvmprint() {
+    KERNEL_PAGETABLE
+        .lock()
+        .as_ref()
+        .expect("kvmprint: no kernel page table")
+        .vmprint();
+}
+
+// The satp value that selects the kernel page table, for returning from user traps
+pub fn kernel_satp() -> usize {
+    let root = KERNEL_PAGETABLE
+        .lock()
+        .as_ref()
+        .expect("kernel_satp: no kernel page table")
+        .root();
+    make_satp(root, SatpMode::Sv39, KERNEL_ASID)
+}
+
+// Address-space identifiers tag TLB entries, so that switching between
+// address spaces does not have to flush the TLB
+// ASID 0 is the kernel's. The others are handed out in order; once they run
+// out a new generation starts, every hart flushes its whole TLB before using
+// an ASID from it, and each address space takes a new ASID when it next runs
+// Without hardware ASIDs everything uses ASID 0 and the trampoline flushes instead
+const KERNEL_ASID: usize = 0;
+
+// An address space's ASID and the generation it was handed out in
+// Generation 0 means none has been assigned yet
+#[derive(Copy, Clone, Default, Debug)]
+pub struct Asid {
+    generation: usize,
+    id: usize,
+}
+
+struct AsidAllocator {
+    bits: usize,         // ASID bits implemented by every hart
+    generation: usize,   // Current generation, from 1
+    next: usize,         // Next unused ASID in the current generation
+    seen: [usize; NCPU], // Generation each hart last flushed its TLB for
+}
+
+static ASIDS: Spinlock<AsidAllocator> = Spinlock::new(
+    AsidAllocator {
+        bits: SATP_ASID_MASK.count_ones() as usize,
+        generation: 1,
+        next: KERNEL_ASID + 1,
+        seen: [0; NCPU],
+    },
+    "asid",
+);
+
+impl AsidAllocator {
+    // Make asid valid in the current generation, assigning a new ID if needed
+    // Returns true if hart must flush its whole TLB before using it
+    fn activate(&mut self, asid: &mut Asid, hart: usize) -> bool {
+        if self.bits == 0 {
+            *asid = Asid {
+                generation: self.generation,
+                id: KERNEL_ASID,
+            };
+            return false;
+        }
+        if asid.generation != self.generation {
+            if self.next >= 1 << self.bits {
+                self.generation += 1;
+                self.next = KERNEL_ASID + 1;
+            }
+            *asid = Asid {
+                generation: self.generation,
+                id: self.next,
+            };
+            self.next += 1;
+        }
+        let stale = self.seen[hart] != self.generation;
+        self.seen[hart] = self.generation;
+        stale
+    }
+}
+
+// Use only bits of ASID from now on, returning how many were used before
+// Starts a new generation, so running address spaces switch over as they
+// next return to user space. For measuring what ASIDs save
+#[cfg(test)]
+pub fn set_asid_bits(bits: usize) -> usize {
+    let mut asids = ASIDS.lock();
+    let old = asids.bits;
+    asids.bits = bits;
+    asids.generation += 1;
+    asids.next = KERNEL_ASID + 1;
+    old
+}
+
+// TLB statistics, to compare runs with and without ASIDs
+// Misses are counted by hardware performance counters that start() sets up,
+// on harts that have them, and only those taken while running user code
+pub const TLB_MISS_COUNTERS: [usize; 3] = [3, 4, 5];
+
+static TLB_FULL_FLUSHES: AtomicUsize = AtomicUsize::new(0);
+static TLB_ASID_FLUSHES: AtomicUsize = AtomicUsize::new(0);
+static TLB_PAGE_FLUSHES: AtomicUsize = AtomicUsize::new(0);
+static TLB_USER_MISSES: AtomicUsize = AtomicUsize::new(0);
+
+// This hart's miss count when it last entered user space, for leave()
+static TLB_MISS_MARK: [AtomicUsize; NCPU] = [const { AtomicUsize::new(0) }; NCPU];
+
+#[derive(Copy, Clone, Default, Debug)]
+pub struct TlbStats {
+    pub full_flushes: usize, // Whole TLB, on one hart
+    pub asid_flushes: usize, // One address space
+    pub page_flushes: usize, // One page of one address space
+    pub user_misses: usize,  // Misses while in user mode
+}
+
+impl TlbStats {
+    // What happened between before and self
+    pub fn since(self, before: TlbStats) -> TlbStats {
+        TlbStats {
+            full_flushes: self.full_flushes - before.full_flushes,
+            asid_flushes: self.asid_flushes - before.asid_flushes,
+            page_flushes: self.page_flushes - before.page_flushes,
+            user_misses: self.user_misses - before.user_misses,
+        }
+    }
+}
+
+pub fn tlb_stats() -> TlbStats {
+    TlbStats {
+        full_flushes: TLB_FULL_FLUSHES.load(Ordering::Relaxed),
+        asid_flushes: TLB_ASID_FLUSHES.load(Ordering::Relaxed),
+        page_flushes: TLB_PAGE_FLUSHES.load(Ordering::Relaxed),
+        user_misses: TLB_USER_MISSES.load(Ordering::Relaxed),
+    }
+}
+
+// TLB misses on this hart since boot
+fn tlb_misses() -> usize {
+    TLB_MISS_COUNTERS.into_iter().map(read_hpmcounter).sum()
+}
+
+// Address space layout randomization
+// Each randomized address moves by a random number of pages below 2^bits
+#[derive(Copy, Clone)]
+struct Aslr {
+    enabled: bool,
+    load_bits: usize,  // PIE load base, up from PIE_BASE
+    brk_bits: usize,   // Heap start, up from the end of the program
+    mmap_bits: usize,  // mmap base, down from MMAP_BASE
+    stack_bits: usize, // Stack top, down from USTACK_TOP
+}
+
+// Largest accepted entropy, which keeps each address well inside its area
+const ASLR_BITS_MAX: usize = 24;
+
+static ASLR: Spinlock<Aslr> = Spinlock::new(
+    Aslr {
+        enabled: true,
+        load_bits: 16,
+        brk_bits: 12,
+        mmap_bits: 18,
+        stack_bits: 16,
+    },
+    "aslr",
+);
+
+// Apply ASLR options from the kernel command line, once at boot
+//   norandmaps                          turn ASLR off, for reproducible debugging
+//   aslr.{load,brk,mmap,stack}_bits=N   entropy of one address
+pub fn aslr_bootargs(args: &str) {
+    let mut aslr = ASLR.lock();
+    for arg in args.split_whitespace() {
+        if arg == "norandmaps" {
+            aslr.enabled = false;
+            continue;
+        }
+        let Some((name, value)) = arg.split_once('=') else {
+            continue;
+        };
+        let Ok(bits) = value.parse::<usize>() else {
+            continue;
+        };
+        let bits = bits.min(ASLR_BITS_MAX);
+        match name {
+            "aslr.load_bits" => aslr.load_bits = bits,
+            "aslr.brk_bits" => aslr.brk_bits = bits,
+            "aslr.mmap_bits" => aslr.mmap_bits = bits,
+            "aslr.stack_bits" => aslr.stack_bits = bits,
+            _ => {}
+        }
+    }
+}
+
+// TLB shootdown
+// Changing a PTE only affects this hart's TLB; other harts may still cache the
+// old translation. The changing hart posts a request, interrupts those harts,
+// and spins until each has flushed its own TLB
+// A target serves the request from kerneltrap() or usertrap() once it has
+// interrupts on, or from Spinlock::lock() while it spins with them off, so
+// the initiator may hold a lock that a target is waiting for
+// One shootdown is in flight at a time. A hart waiting for its turn serves
+// requests aimed at itself, so two initiators cannot deadlock each other
+
+// Harts that have turned on paging, and so can be asked to flush
+static HARTS_ONLINE: AtomicUsize = AtomicUsize::new(0);
+// Beyond this many pages, flushing the whole ASID is cheaper than page by page
+const FLUSH_PAGES_MAX: usize = 64;
+
+static SHOOTDOWN_BUSY: AtomicBool = AtomicBool::new(false);
+static SHOOTDOWN_PENDING: AtomicUsize = AtomicUsize::new(0); // Harts yet to flush
+static SHOOTDOWN_ASID: AtomicUsize = AtomicUsize::new(0);
+static SHOOTDOWN_START: AtomicUsize = AtomicUsize::new(0);
+static SHOOTDOWN_END: AtomicUsize = AtomicUsize::new(0);
+
+// Flush [start, end) of address space asid from this hart's TLB
+fn flush_local(asid: usize, start: usize, end: usize) {
+    if end.saturating_sub(start) > FLUSH_PAGES_MAX * PGSIZE {
+        TLB_ASID_FLUSHES.fetch_add(1, Ordering::Relaxed);
+        flush_tlb_asid(asid);
+        return;
+    }
+    for va in (pg_round_down(start)..end).step_by(PGSIZE) {
+        TLB_PAGE_FLUSHES.fetch_add(1, Ordering::Relaxed);
+        flush_tlb_page(va, asid);
+    }
+}
+
+// Flush [start, end) of address space asid here and on the other harts in harts
+fn shootdown(asid: usize, start: usize, end: usize, harts: usize) {
+    push_off();
+    let me = read_threadptr();
+    flush_local(asid, start, end);
+
+    let others = harts & HARTS_ONLINE.load(Ordering::Acquire) & !(1 << me);
+    if others != 0 {
+        while SHOOTDOWN_BUSY
+            .compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            shootdown_intr();
+            spin_loop();
+        }
+        SHOOTDOWN_ASID.store(asid, Ordering::Relaxed);
+        SHOOTDOWN_START.store(start, Ordering::Relaxed);
+        SHOOTDOWN_END.store(end, Ordering::Relaxed);
+        SHOOTDOWN_PENDING.store(others, Ordering::Release);
+        for hart in (0..NCPU).filter(|hart| others & (1 << hart) != 0) {
+            send_ipi(hart);
+        }
+        while SHOOTDOWN_PENDING.load(Ordering::Acquire) != 0 {
+            spin_loop();
+        }
+        SHOOTDOWN_BUSY.store(false, Ordering::Release);
+    }
+    pop_off();
+}
+
+// Serve the shootdown request aimed at this hart, if there is one
+// Called from ipiintr(), and by Spinlock::lock() while it waits
+pub fn shootdown_intr() {
+    let me = 1 << read_threadptr();
+    if SHOOTDOWN_PENDING.load(Ordering::Acquire) & me == 0 {
+        return;
+    }
+    flush_local(
+        SHOOTDOWN_ASID.load(Ordering::Relaxed),
+        SHOOTDOWN_START.load(Ordering::Relaxed),
+        SHOOTDOWN_END.load(Ordering::Relaxed),
+    );
+    SHOOTDOWN_PENDING.fetch_and(!me, Ordering::AcqRel);
+}
+
+// Remove size bytes of kernel mappings at va, such as a kernel stack that is
+// no longer needed, and make sure no hart keeps a stale translation for them
+pub fn kvmunmap(va: usize, size: usize, do_free: bool) {
+    if let Err(e) = KERNEL_PAGETABLE
+        .lock()
+        .as_mut()
+        .expect("kvmunmap: no kernel page table")
+        .unmap(va, size / PGSIZE, do_free)
+    {
+        panic!("kvmunmap {:#x}: {:?}", va, e);
+    }
+    shootdown(KERNEL_ASID, va, va + size, usize::MAX);
+}
+
+// A process's user page table and the memory mapped through it
+// User memory runs from the load base up to size; the trampoline and the
+// process's trapframe page are mapped at the top
+// Dropping the address space unmaps and frees everything it allocated,
+// so a partially built one can simply be dropped on an error path
+pub struct UserAddressSpace {
+    pagetable: PageTable,
+    asid: Asid,
+    harts: usize,        // Bitmask of harts running us, between satp() and leave()
+    stale: usize,        // Bitmask of harts that must flush asid before running us again
+    clock: usize,        // Where the page reclaimer's clock hand is, as a virtual address
+    reclaiming: bool,    // Sleeping in reclaim() partway through a fault; see alloc_page()
+    base: usize,         // User memory starts here, at the program's load base
+    size: usize,         // User memory ends here; [base, size) holds program and heap
+    stack_top: usize,    // The user stack grows down from here
+    stack_rlimit: usize, // ... by at most this many bytes
+    vmas: Vec<Vma>,      // mmap regions and the stack, sorted by start address
+    mmap_base: usize,    // mmap regions are placed below this address
+}
+
+impl UserAddressSpace {
+    // Create an address space with no user memory, but with the trampoline
+    // and the trapframe page at physical address trapframe mapped
+    pub fn new(trapframe: usize) -> Result<Self, VmError> {
+        let mut pagetable = PageTable::new()?;
+
+        // The trampoline code (for system call return) at the highest user virtual address
+        // Only the supervisor uses it, on the way to/from user space, so not PteFlags::U
+        let tramp = addr_of!(trampoline) as usize;
+        if let Err(e) = pagetable.map_pages(TRAMPOLINE, PGSIZE, tramp, PteFlags::R | PteFlags::X) {
+            pagetable.free();
+            return Err(e);
+        }
+
+        // The trapframe page just below the trampoline page, for trampoline.rs
+        if let Err(e) = pagetable.map_pages(TRAPFRAME, PGSIZE, trapframe, PteFlags::R | PteFlags::W)
+        {
+            pagetable
+                .unmap(TRAMPOLINE, 1, false)
+                .expect("uvm: trampoline");
+            pagetable.free();
+            return Err(e);
+        }
+
+        Ok(UserAddressSpace {
+            pagetable,
+            asid: Asid::default(),
+            harts: 0,
+            stale: 0,
+            clock: 0,
+            reclaiming: false,
+            base: 0,
+            size: 0,
+            stack_top: USTACK_TOP,
+            stack_rlimit: STACK_RLIMIT,
+            vmas: Vec::new(),
+            mmap_base: MMAP_BASE,
+        })
+    }
+
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    // Where a position-independent program should be loaded
+    pub fn load_base(&self) -> usize {
+        self.base
+    }
+
+    pub fn stack_top(&self) -> usize {
+        self.stack_top
+    }
+
+    // Is va in the program's memory or heap, [base, size)?
+    fn in_user_memory(&self, va: usize) -> bool {
+        self.base <= va && va < self.size
+    }
+
+    // Pick random places for the stack top and mmap base of a new program, and
+    // for its load base if it is position-independent, unless ASLR is off
+    // Call on an empty address space, before loading
+    pub fn randomize(&mut self, pie: bool) {
+        let aslr = *ASLR.lock();
+        if !aslr.enabled || self.size != 0 {
+            return;
+        }
+        if pie {
+            self.base = PIE_BASE + random_bits(aslr.load_bits) * PGSIZE;
+            self.size = self.base;
+        }
+        self.stack_top = USTACK_TOP - random_bits(aslr.stack_bits) * PGSIZE;
+        self.mmap_base = MMAP_BASE - random_bits(aslr.mmap_bits) * PGSIZE;
+    }
+
+    // Start the heap a random number of pages past the loaded program, unless
+    // ASLR is off. The skipped pages are demand-zero heap like the rest, so they
+    // cost nothing unless touched
+    pub fn randomize_brk(&mut self) {
+        let aslr = *ASLR.lock();
+        if aslr.enabled {
+            self.size = pg_round_up(self.size) + random_bits(aslr.brk_bits) * PGSIZE;
+        }
+    }
+
+    pub fn pagetable(&self) -> &PageTable {
+        &self.pagetable
+    }
+
+    pub fn pagetable_mut(&mut self) -> &mut PageTable {
+        &mut self.pagetable
+    }
+
+    // The satp value that switches this hart to this address space
+    // Call with interrupts off, just before returning to user space
+    // Flushes whatever this hart's TLB might still hold from an older ASID generation
+    pub fn satp(&mut self) -> usize {
+        let hart = read_threadptr();
+        let generation = self.asid.generation;
+        if ASIDS.lock().activate(&mut self.asid, hart) {
+            TLB_FULL_FLUSHES.fetch_add(1, Ordering::Relaxed);
+            flush_tlb();
+        }
+        if self.asid.generation != generation {
+            // No hart has cached anything for a new ASID, but page-table
+            // writes made before now still need ordering on this one
+            self.stale = 0;
+            TLB_ASID_FLUSHES.fetch_add(1, Ordering::Relaxed);
+            flush_tlb_asid(self.asid.id);
+        } else if self.stale & (1 << hart) != 0 {
+            // Pages were evicted while we were not running (see defer_flush())
+            self.stale &= !(1 << hart);
+            TLB_ASID_FLUSHES.fetch_add(1, Ordering::Relaxed);
+            flush_tlb_asid(self.asid.id);
+        }
+        self.harts |= 1 << hart;
+        TLB_MISS_MARK[hart].store(tlb_misses(), Ordering::Relaxed);
+        make_satp(self.pagetable.root(), SatpMode::Sv39, self.asid.id)
+    }
+
+    // This hart has switched from us to the kernel page table, on a trap
+    // Our entries can stay in its TLB under our ASID, but from now on a
+    // shootdown need not interrupt it (see flush_range())
+    // Also counts the TLB misses taken in user space since satp(), and the
+    // flushes the trampoline made on the way there and back without ASIDs
+    pub fn leave(&mut self, trampoline_flushes: usize) {
+        push_off();
+        let hart = read_threadptr();
+        self.harts &= !(1 << hart);
+        let misses = tlb_misses().wrapping_sub(TLB_MISS_MARK[hart].load(Ordering::Relaxed));
+        TLB_USER_MISSES.fetch_add(misses, Ordering::Relaxed);
+        TLB_FULL_FLUSHES.fetch_add(trampoline_flushes, Ordering::Relaxed);
+        pop_off();
+    }
+
+    // Flush the TLB entry for the page at va after changing its PTE
+    fn flush_page(&mut self, va: usize) {
+        self.flush_range(va, va + PGSIZE);
+    }
+
+    // Flush TLB entries for [start, end) after changing their PTEs
+    // This hart flushes them at once and harts running us are interrupted to
+    // do the same. Any other hart may still hold entries from when it last
+    // ran us, so it flushes our whole ASID before it runs us again, in satp()
+    fn flush_range(&mut self, start: usize, end: usize) {
+        push_off();
+        let me = 1 << read_threadptr();
+        self.stale |= !self.harts & !me;
+        shootdown(self.asid.id, start, end, self.harts);
+        pop_off();
+    }
+
+    // Like flush_range() over all of user memory, for an address space that is
+    // not running anywhere, without even a flush here: each hart that may hold
+    // stale entries flushes them itself before it next runs us, in satp()
+    fn defer_flush(&mut self) {
+        self.stale = usize::MAX;
+    }
+
+    // Allocate a page for user memory, zeroed if zero is set
+    // When memory runs out, pages are evicted to swap to make room. That may
+    // sleep while our caller is partway through a fault, so other processes'
+    // reclaim() leaves us alone meanwhile; our own may still evict our pages,
+    // so callers must look at their PTE again afterwards
+    fn alloc_page(&mut self, zero: bool) -> Result<usize, VmError> {
+        loop {
+            let pa = if zero {
+                kzalloc(PagePurpose::User)
+            } else {
+                kalloc(PagePurpose::User)
+            };
+            if let Some(pa) = pa {
+                return Ok(pa);
+            }
+            self.reclaiming = true;
+            let reclaimed = reclaim(self);
+            self.reclaiming = false;
+            if !reclaimed {
+                return Err(VmError::OutOfMemory);
+            }
+        }
+    }
+
+    // Grow user memory to newsz bytes without allocating anything
+    // The new pages are demand-zero: the first access to each faults and
+    // lazy_fault() maps a zeroed page there
+    pub fn grow(&mut self, newsz: usize) -> Result<usize, VmError> {
+        if newsz > self.heap_limit() {
+            return Err(VmError::BadAddress(newsz));
+        }
+        if newsz > self.size {
+            self.size = newsz;
+        }
+        Ok(self.size)
+    }
+
+    // Allocate zeroed pages and map them right away to grow user memory to newsz bytes,
+    // for memory the kernel fills in itself such as program segments
+    // The new pages get exactly perm, some of R, W and X, as from elf_perm()
+    // On failure nothing new stays allocated and the size is unchanged
+    pub fn grow_eager(&mut self, newsz: usize, perm: PteFlags) -> Result<usize, VmError> {
+        if newsz <= self.size {
+            return Ok(self.size);
+        }
+        if newsz > self.heap_limit() {
+            return Err(VmError::BadAddress(newsz));
+        }
+        let perm = perm & (PteFlags::R | PteFlags::W | PteFlags::X);
+        check_wx(self.size, perm, false)?;
+
+        let oldsz = self.size;
+        let mut a = pg_round_up(oldsz);
+        while a < newsz {
+            let result = kalloc(PagePurpose::User)
+                .ok_or(VmError::OutOfMemory)
+                .and_then(|pa| {
+                    unsafe { write_bytes(pa as *mut u8, 0, PGSIZE) };
+                    self.pagetable
+                        .map_pages(a, PGSIZE, pa, perm | PteFlags::U)
+                        .inspect_err(|_| kfree(pa))
+                });
+            if let Err(e) = result {
+                self.shrink(oldsz);
+                return Err(e);
+            }
+            a += PGSIZE;
+            self.size = a.min(newsz);
+        }
+        self.size = newsz;
+        self.flush_range(pg_round_up(oldsz), newsz);
+        Ok(newsz)
+    }
+
+    // Copy src into user memory at va, through the direct map so that pages
+    // the user cannot write, such as program text, can still be filled in
+    // The pages must already be mapped, as by grow_eager()
+    pub fn load(&mut self, va: usize, src: &[u8]) -> Result<(), VmError> {
+        let mut done = 0;
+        while done < src.len() {
+            let a = va + done;
+            let pte = self
+                .pagetable
+                .lookup(a)
+                .filter(|pte| pte.flags().contains(PteFlags::U))
+                .ok_or(VmError::NotMapped(a))?;
+            let n = (PGSIZE - a % PGSIZE).min(src.len() - done);
+            let dst = (pte.pa() + a % PGSIZE) as *mut u8;
+            unsafe { copy_nonoverlapping(src[done..].as_ptr(), dst, n) };
+            done += n;
+        }
+        Ok(())
+    }
+
+    // User memory may grow up to the lowest mmap region, and never into
+    // the area kept for the stack
+    fn heap_limit(&self) -> usize {
+        self.vmas
+            .first()
+            .map_or(TRAPFRAME, |vma| vma.start)
+            .min(self.stack_floor())
+    }
+
+    // Unmap and free user pages to bring the size down to newsz bytes,
+    // but never below the load base
+    // Returns the new size
+    pub fn shrink(&mut self, newsz: usize) -> usize {
+        if newsz >= self.size {
+            return self.size;
+        }
+        let newsz = newsz.max(self.base);
+        self.unmap_present(pg_round_up(newsz), pg_round_up(self.size));
+        self.size = newsz;
+        newsz
+    }
+
+    // Unmap and drop a reference to every page mapped in [start, end), mapped
+    // but inaccessible, or swapped out, in which case it is the swap slot
+    // Demand-zero and not yet faulted pages have nothing to free
+    fn unmap_present(&mut self, start: usize, end: usize) {
+        let mut va = start;
+        while va < end {
+            let step = match self.pagetable.lookup_leaf(va) {
+                // unmap() splits a superpage that is only partly in range
+                Some((_, level)) if va.is_multiple_of(level_size(level)) => {
+                    level_size(level).min(end - va)
+                }
+                Some(_) => PGSIZE,
+                None => {
+                    if let Ok(pte) = self.pagetable.walk(va, false) {
+                        if pte.is_swapped() {
+                            swap_free(pte.slot());
+                            pte.clear();
+                        } else if pte.is_inaccessible() {
+                            kfree(pte.pa());
+                            pte.clear();
+                        }
+                    }
+                    va += PGSIZE;
+                    continue;
+                }
+            };
+            self.pagetable
+                .unmap(va, step / PGSIZE, true)
+                .expect("uvm unmap");
+            va += step;
+        }
+        self.flush_range(start, end);
+    }
+
+    // Copy of this address space's user memory, for fork
+    // Pages are shared rather than copied: writable pages become read-only and
+    // PteFlags::COW in both address spaces, and the first write to one takes a
+    // private copy (see cow_fault()). The copy maps its own trapframe page
+    pub fn copy(&mut self, trapframe: usize) -> Result<Self, VmError> {
+        let mut new = UserAddressSpace::new(trapframe)?;
+        new.base = self.base;
+        new.size = self.base;
+        new.stack_top = self.stack_top;
+        new.stack_rlimit = self.stack_rlimit;
+        new.mmap_base = self.mmap_base;
+        // Dropping new on an error path releases every page shared so far
+        for va in (pg_round_down(self.base)..self.size).step_by(PGSIZE) {
+            self.share_page(&mut new, va, true)?;
+            new.size = (va + PGSIZE).min(self.size);
+        }
+        new.size = self.size;
+
+        // mmap regions are inherited: MAP_SHARED pages stay shared and
+        // writable, private ones become copy-on-write like the heap
+        for vma in self.vmas.clone() {
+            let cow = matches!(vma.kind, VmaKind::Private { .. });
+            let (start, end, huge) = (vma.start, vma.end, vma.huge);
+            new.vmas.push(vma);
+            if huge {
+                self.copy_huge(&mut new, start, end)?;
+                continue;
+            }
+            for va in (start..end).step_by(PGSIZE) {
+                self.share_page(&mut new, va, cow)?;
+            }
+        }
+
+        // Our own writable mappings just became read-only
+        self.flush_range(0, TRAPFRAME);
+        Ok(new)
+    }
+
+    // Map the page at va into new as well, taking a reference to it
+    // With cow a writable page becomes read-only and PteFlags::COW in both
+    // A swapped-out page stays swapped out in both, sharing its slot; each
+    // address space faults in a private copy of it (see swap_in())
+    // An inaccessible page stays so in both; mprotect() makes it copy-on-write
+    // again if it is made writable while still shared
+    fn share_page(&mut self, new: &mut Self, va: usize, cow: bool) -> Result<(), VmError> {
+        let pte = match self.pagetable.walk(va, false) {
+            Ok(pte) if pte.is_valid() => pte,
+            Ok(pte) if pte.is_swapped() => {
+                let entry = *pte;
+                *new.pagetable.walk(va, true)? = entry;
+                swap_dup(entry.slot());
+                return Ok(());
+            }
+            Ok(pte) if pte.is_inaccessible() => {
+                let entry = *pte;
+                *new.pagetable.walk(va, true)? = entry;
+                page_ref(entry.pa());
+                return Ok(());
+            }
+            // Never touched, so still demand-zero in the copy too
+            _ => return Ok(()),
+        };
+        if cow && pte.flags().contains(PteFlags::W) {
+            pte.set_flags((pte.flags() & !PteFlags::W) | PteFlags::COW);
+        }
+        let (pa, flags) = (pte.pa(), pte.flags());
+        new.pagetable.map_pages(va, PGSIZE, pa, flags)?;
+        page_ref(pa);
+        Ok(())
+    }
+
+    // Give new its own copy of the huge mapping [start, end)
+    // Superpages are not shared copy-on-write, so fork copies them right away
+    fn copy_huge(&self, new: &mut Self, start: usize, end: usize) -> Result<(), VmError> {
+        let mut va = start;
+        while va < end {
+            // mprotect(PROT_NONE) leaves inaccessible 4 KiB pages behind
+            let (pte, level) = match self.pagetable.lookup_leaf(va) {
+                Some(leaf) => leaf,
+                None => match self.pagetable.lookup_entry(va) {
+                    Some(pte) if pte.is_inaccessible() => (pte, 0),
+                    _ => return Err(VmError::NotMapped(va)),
+                },
+            };
+            // Whatever is left of a partly unmapped superpage is in 4 KiB pages
+            let (size, pa) = match level {
+                0 => (PGSIZE, kalloc(PagePurpose::User)),
+                _ => (HUGE_PGSIZE, kalloc_pages(HUGE_ORDER, PagePurpose::User)),
+            };
+            let pa = pa.ok_or(VmError::OutOfMemory)?;
+            unsafe { copy_nonoverlapping(pte.pa() as *const u8, pa as *mut u8, size) };
+            if pte.is_inaccessible() {
+                *new.pagetable.walk(va, true).inspect_err(|_| kfree(pa))? =
+                    Pte::inaccessible(pa, pte.flags());
+                va += size;
+                continue;
+            }
+            new.pagetable
+                .map_pages(va, size, pa, pte.flags())
+                .inspect_err(|_| match level {
+                    0 => kfree(pa),
+                    _ => kfree_pages(pa, HUGE_ORDER),
+                })?;
+            va += size;
+        }
+        Ok(())
+    }
+
+    // Is va in user memory or an mmap region, on a page that has not been faulted
+    // in yet or has been swapped out since?
+    pub fn is_lazy(&self, va: usize) -> bool {
+        (self.in_user_memory(va) || self.find_vma(va).is_some())
+            && self.pagetable.lookup(va).is_none()
+            && !self
+                .pagetable
+                .lookup_entry(va)
+                .is_some_and(|pte| pte.is_inaccessible())
+    }
+
+    // Fault in the page holding va on first access, or after it was swapped out
+    // Heap pages are demand-zero; mmap pages come from their region's backing
+    // Fails if va is not a lazy page or the region does not permit access
+    pub fn lazy_fault(&mut self, va: usize, access: PteFlags) -> Result<(), VmError> {
+        if !self.is_lazy(va) {
+            return Err(VmError::BadAddress(va));
+        }
+        let va = pg_round_down(va);
+        if self.is_swapped(va) {
+            self.swap_in(va)?;
+        } else if let Some(i) = self.find_vma(va) {
+            self.vma_fault(i, va, access)?;
+        } else {
+            let pa = self.alloc_page(true)?;
+            let perm = PteFlags::R | PteFlags::W | PteFlags::U;
+            self.pagetable
+                .map_pages(va, PGSIZE, pa, perm)
+                .inspect_err(|_| kfree(pa))?;
+        }
+        // Order the new PTE before the retried access
+        self.flush_page(va);
+        Ok(())
+    }
+
+    // Is the page holding va a copy-on-write page?
+    pub fn is_cow(&self, va: usize) -> bool {
+        self.pagetable
+            .lookup(va)
+            .is_some_and(|pte| pte.is_valid() && pte.flags().contains(PteFlags::COW))
+    }
+
+    // Handle a write to the copy-on-write page holding va by giving this
+    // address space its own writable copy. The last sharer keeps the original
+    // Fails if va is not a copy-on-write user page, in which case the write was a real fault
+    // If the page was swapped out while allocating the copy, nothing changes
+    // and the retried write faults it back in
+    pub fn cow_fault(&mut self, va: usize) -> Result<(), VmError> {
+        let va = pg_round_down(va);
+        if !self.in_user_memory(va) && self.find_vma(va).is_none() {
+            return Err(VmError::BadAddress(va));
+        }
+        let pte = self.pagetable.walk(va, false)?;
+        if !pte.is_valid() || !pte.flags().contains(PteFlags::U | PteFlags::COW) {
+            return Err(VmError::Protection(va));
+        }
+
+        let old = pte.pa();
+        let flags = (pte.flags() & !PteFlags::COW) | PteFlags::W;
+        if page_refcount(old) == 1 {
+            pte.set_flags(flags);
+        } else {
+            let new = self.alloc_page(false)?;
+            let pte = self.pagetable.walk(va, false)?;
+            if !pte.is_valid() || pte.pa() != old || !pte.flags().contains(PteFlags::COW) {
+                kfree(new);
+                return Ok(());
+            }
+            if page_refcount(old) == 1 {
+                // The other sharers went away meanwhile
+                kfree(new);
+                pte.set_flags(flags);
+            } else {
+                unsafe { copy_nonoverlapping(old as *const u8, new as *mut u8, PGSIZE) };
+                *pte = Pte::new(new, flags);
+                kfree(old);
+            }
+        }
+        self.flush_page(va);
+        Ok(())
+    }
+}
+
+// W^X: a user page is never writable and executable at once, so injected
+// code cannot be run and running code cannot be rewritten. Regions mapped
+// with the jit option are the only exception
+fn check_wx(va: usize, perm: PteFlags, jit: bool) -> Result<(), VmError> {
+    if perm.contains(PteFlags::W | PteFlags::X) && !jit {
+        return Err(VmError::WriteExec(va));
+    }
+    Ok(())
+}
+
+// Give the leaf pte, which may be inaccessible, the permissions prot
+// With none at all the page stays but every access faults (see Pte::inaccessible())
+// A page shared copy-on-write stays read-only when prot has W, so that the
+// first write still takes a private copy. Pages of a MAP_SHARED region are
+// shared on purpose and get W directly
+fn protect(pte: &mut Pte, prot: PteFlags, shared: bool) {
+    if prot.is_empty() {
+        *pte = Pte::inaccessible(pte.pa(), pte.flags());
+        return;
+    }
+    let rwx = PteFlags::R | PteFlags::W | PteFlags::X;
+    let mut flags = (pte.flags() & !rwx & !PteFlags::COW) | prot | PteFlags::V;
+    let cow = pte.flags().contains(PteFlags::COW) || page_refcount(pte.pa()) > 1;
+    if prot.contains(PteFlags::W) && cow && !shared {
+        flags = (flags & !PteFlags::W) | PteFlags::COW;
+    }
+    pte.set_flags(flags);
+}
+
+// ELF program header p_flags bits
+pub const PF_X: u32 = 1;
+pub const PF_W: u32 = 2;
+pub const PF_R: u32 = 4;
+
+// The page permissions an ELF segment declares, for grow_eager()
+// W without R is a reserved PTE encoding, so PF_W implies PF_R
+pub fn elf_perm(p_flags: u32) -> PteFlags {
+    let mut perm = PteFlags::empty();
+    if p_flags & (PF_R | PF_W) != 0 {
+        perm |= PteFlags::R;
+    }
+    if p_flags & PF_W != 0 {
+        perm |= PteFlags::W;
+    }
+    if p_flags & PF_X != 0 {
+        perm |= PteFlags::X;
+    }
+    perm
+}
+
+// Memory-mapped regions
+impl UserAddressSpace {
+    // Index of the mmap region containing va
+    pub fn find_vma(&self, va: usize) -> Option<usize> {
+        self.vmas
+            .iter()
+            .position(|vma| vma.start <= va && va < vma.end)
+    }
+
+    pub fn vmas(&self) -> &[Vma] {
+        &self.vmas
+    }
+
+    // Add an mmap region of len bytes with the given backing
+    // Placed exactly at fixed if given, otherwise in the highest free gap below mmap_base
+    // Only a jit region may ever be writable and executable at once
+    // Nothing is mapped until the pages are touched
+    pub fn mmap(
+        &mut self,
+        fixed: Option<usize>,
+        len: usize,
+        prot: PteFlags,
+        kind: VmaKind,
+        jit: bool,
+    ) -> Result<usize, VmError> {
+        let prot = prot & (PteFlags::R | PteFlags::W | PteFlags::X);
+        check_wx(fixed.unwrap_or(0), prot, jit)?;
+        let len = pg_round_up(len);
+        let start = self.place(fixed, len, PGSIZE)?;
+        self.insert_vma(Vma {
+            start,
+            end: start + len,
+            prot,
+            kind,
+            huge: false,
+            jit,
+            stack: false,
+        });
+        Ok(start)
+    }
+
+    // Add an anonymous private region of 2 MiB superpages, allocated and zeroed now
+    // len is rounded up to a whole number of superpages
+    pub fn mmap_huge(
+        &mut self,
+        fixed: Option<usize>,
+        len: usize,
+        prot: PteFlags,
+    ) -> Result<usize, VmError> {
+        let len = len
+            .checked_next_multiple_of(HUGE_PGSIZE)
+            .ok_or(VmError::BadAddress(len))?;
+        let prot = prot & (PteFlags::R | PteFlags::W | PteFlags::X);
+        check_wx(fixed.unwrap_or(0), prot, false)?;
+        let start = self.place(fixed, len, HUGE_PGSIZE)?;
+        self.insert_vma(Vma {
+            start,
+            end: start + len,
+            prot,
+            kind: VmaKind::Private { file: None },
+            huge: true,
+            jit: false,
+            stack: false,
+        });
+        for va in (start..start + len).step_by(HUGE_PGSIZE) {
+            let result = kalloc_pages(HUGE_ORDER, PagePurpose::User)
+                .ok_or(VmError::OutOfMemory)
+                .and_then(|pa| {
+                    unsafe { write_bytes(pa as *mut u8, 0, HUGE_PGSIZE) };
+                    self.pagetable
+                        .map_pages(va, HUGE_PGSIZE, pa, prot | PteFlags::U)
+                        .inspect_err(|_| kfree_pages(pa, HUGE_ORDER))
+                });
+            if let Err(e) = result {
+                let _ = self.munmap(start, len);
+                return Err(e);
+            }
+        }
+        self.flush_range(start, start + len);
+        Ok(start)
+    }
+
+    // Start address for a new region of len bytes aligned to align
+    // Either exactly fixed, which must not overlap anything, or the highest free gap
+    fn place(&self, fixed: Option<usize>, len: usize, align: usize) -> Result<usize, VmError> {
+        if len == 0 {
+            return Err(VmError::Misaligned(len));
+        }
+        let Some(addr) = fixed else {
+            return self.find_gap(len, align);
+        };
+        if addr % align != 0 {
+            return Err(VmError::Misaligned(addr));
+        }
+        let end = addr.checked_add(len).ok_or(VmError::BadAddress(addr))?;
+        if addr < pg_round_up(self.size) || end > TRAPFRAME {
+            return Err(VmError::BadAddress(addr));
+        }
+        if addr < self.stack_top && self.stack_floor() < end {
+            return Err(VmError::AlreadyMapped(addr));
+        }
+        if self
+            .vmas
+            .iter()
+            .any(|vma| vma.start < end && addr < vma.end)
+        {
+            return Err(VmError::AlreadyMapped(addr));
+        }
+        Ok(addr)
+    }
+
+    // Record a region, keeping vmas sorted
+    fn insert_vma(&mut self, vma: Vma) {
+        let at = self.vmas.partition_point(|v| v.start < vma.start);
+        self.vmas.insert(at, vma);
+    }
+
+    // Highest free range of len bytes aligned to align, below mmap_base and above user memory
+    fn find_gap(&self, len: usize, align: usize) -> Result<usize, VmError> {
+        let fit = |top: usize, floor: usize| {
+            top.checked_sub(len)
+                .map(|start| start & !(align - 1))
+                .filter(|&start| start >= floor)
+        };
+        let mut top = self.mmap_base;
+        for vma in self.vmas.iter().rev() {
+            if vma.start >= top {
+                continue;
+            }
+            if vma.end <= top {
+                if let Some(start) = fit(top, vma.end) {
+                    return Ok(start);
+                }
+            }
+            top = vma.start;
+        }
+        fit(top, pg_round_up(self.size)).ok_or(VmError::OutOfMemory)
+    }
+
+    // Change the permissions of [addr, addr + len) to prot, some of R, W and X
+    // Every page must be user memory or inside an mmap region; regions are split
+    // at the ends of the range. Lazy user memory pages are faulted in so they keep prot
+    // With no permissions, pages already touched are kept but made invalid,
+    // since a valid PTE without R, W or X would point to a page-table page
+    pub fn mprotect(&mut self, addr: usize, len: usize, prot: PteFlags) -> Result<(), VmError> {
+        if !addr.is_multiple_of(PGSIZE) {
+            return Err(VmError::Misaligned(addr));
+        }
+        let prot = prot & (PteFlags::R | PteFlags::W | PteFlags::X);
+        let end = addr
+            .checked_add(pg_round_up(len))
+            .ok_or(VmError::BadAddress(addr))?;
+        if end > TRAPFRAME {
+            return Err(VmError::BadAddress(addr));
+        }
+
+        // Check the whole range before changing any of it
+        let mut va = addr;
+        while va < end {
+            if self.in_user_memory(va) {
+                check_wx(va, prot, false)?;
+                va += PGSIZE;
+                continue;
+            }
+            let i = self.find_vma(va).ok_or(VmError::NotMapped(va))?;
+            check_wx(va, prot, self.vmas[i].jit)?;
+            va = self.vmas[i].end;
+        }
+
+        self.split_vma(addr);
+        self.split_vma(end);
+        for vma in self.vmas.iter_mut() {
+            if addr <= vma.start && vma.end <= end {
+                vma.prot = prot;
+            }
+        }
+
+        let mut va = addr;
+        while va < end {
+            if self.is_lazy(va) {
+                if self.find_vma(va).is_some() && !self.is_swapped(va) {
+                    // Faulted in with the region's new prot when first touched
+                    va += PGSIZE;
+                    continue;
+                }
+                self.lazy_fault(va, PteFlags::empty())?;
+            }
+            let shared = self
+                .find_vma(va)
+                .is_some_and(|i| matches!(self.vmas[i].kind, VmaKind::Shared { .. }));
+            // Inaccessible pages are always 4 KiB, so a superpage made
+            // PROT_NONE is split
+            let level = match self.pagetable.lookup_leaf(va) {
+                Some((_, level))
+                    if !prot.is_empty()
+                        && va.is_multiple_of(level_size(level))
+                        && end - va >= level_size(level) =>
+                {
+                    level
+                }
+                _ => 0,
+            };
+            let pte = self.pagetable.walk_level(va, level, false)?;
+            protect(pte, prot, shared);
+            va += level_size(level);
+        }
+        self.flush_range(addr, end);
+        Ok(())
+    }
+
+    // Split the region containing addr in two at addr, unless it already starts there
+    fn split_vma(&mut self, addr: usize) {
+        let Some(i) = self.find_vma(addr) else {
+            return;
+        };
+        if self.vmas[i].start == addr {
+            return;
+        }
+        let vma = &self.vmas[i];
+        let (low, high) = (vma.slice(vma.start, addr), vma.slice(addr, vma.end));
+        self.vmas[i] = low;
+        self.vmas.insert(i + 1, high);
+    }
+
+    // Remove mappings in [addr, addr + len), writing MAP_SHARED file pages back first
+    // Regions partly inside the range are trimmed or split
+    pub fn munmap(&mut self, addr: usize, len: usize) -> Result<(), VmError> {
+        if !addr.is_multiple_of(PGSIZE) {
+            return Err(VmError::Misaligned(addr));
+        }
+        let end = addr
+            .checked_add(pg_round_up(len))
+            .ok_or(VmError::BadAddress(addr))?;
+        if addr < pg_round_up(self.size) || end > TRAPFRAME {
+            return Err(VmError::BadAddress(addr));
+        }
+
+        // Like a page cache, a failed writeback does not keep the mapping alive
+        let _ = self.msync(addr, end - addr);
+
+        let mut kept = Vec::with_capacity(self.vmas.len() + 1);
+        for vma in self.vmas.drain(..) {
+            if vma.end <= addr || end <= vma.start {
+                kept.push(vma);
+                continue;
+            }
+            if vma.start < addr {
+                kept.push(vma.slice(vma.start, addr));
+            }
+            if end < vma.end {
+                kept.push(vma.slice(end, vma.end));
+            }
+        }
+        self.vmas = kept;
+        self.unmap_present(addr, end);
+        Ok(())
+    }
+
+    // Write MAP_SHARED file pages in [addr, addr + len) back to their files
+    // The range must be page-aligned and lie below the trapframe
+    pub fn msync(&mut self, addr: usize, len: usize) -> Result<(), VmError> {
+        if !addr.is_multiple_of(PGSIZE) {
+            return Err(VmError::Misaligned(addr));
+        }
+        let end = addr
+            .checked_add(len)
+            .filter(|&end| end <= TRAPFRAME)
+            .ok_or(VmError::BadAddress(addr))?;
+        for vma in self.vmas.iter() {
+            if vma.end <= addr || end <= vma.start {
+                continue;
+            }
+            if let VmaKind::Shared { object, pgoff } = &vma.kind {
+                let from = pg_round_down(addr.max(vma.start));
+                let to = pg_round_up(end.min(vma.end));
+                let first = pgoff + (from - vma.start) / PGSIZE;
+                let last = pgoff + (to - vma.start) / PGSIZE;
+                object.writeback(first, last)?;
+            }
+        }
+        Ok(())
+    }
+
+    // Fault in page va of mmap region i, if the region permits access
+    fn vma_fault(&mut self, i: usize, va: usize, access: PteFlags) -> Result<(), VmError> {
+        // Cloned, since allocating the page borrows all of self
+        let vma = self.vmas[i].clone();
+        if !vma.prot.contains(access) {
+            return Err(VmError::Protection(va));
+        }
+        let pa = match &vma.kind {
+            VmaKind::Private { file } => {
+                let pa = self.alloc_page(true)?;
+                if let Some((file, offset)) = file {
+                    if file
+                        .read_page(offset + (va - vma.start), page_bytes(pa))
+                        .is_err()
+                    {
+                        kfree(pa);
+                        return Err(VmError::Io);
+                    }
+                }
+                pa
+            }
+            VmaKind::Shared { object, pgoff } => object.page(pgoff + (va - vma.start) / PGSIZE)?,
+        };
+        self.pagetable
+            .map_pages(va, PGSIZE, pa, vma.prot | PteFlags::U)
+            .inspect_err(|_| kfree(pa))
+    }
+}
+
+// The user stack is an anonymous region that starts as one page just below
+// stack_top and grows down on demand, when a fault lands below it, up to the
+// stack rlimit. Under that, STACK_GUARD bytes are never mapped, so a runaway
+// stack faults there instead of running into whatever is below
+pub const STACK_RLIMIT: usize = 8 * 1024 * 1024; // Default rlimit
+pub const STACK_RLIMIT_MAX: usize = 256 * 1024 * 1024;
+const STACK_GUARD: usize = 256 * PGSIZE;
+
+impl UserAddressSpace {
+    // Map a new program's stack, returning the initial stack pointer
+    pub fn setup_stack(&mut self) -> Result<usize, VmError> {
+        let start = self.stack_top - PGSIZE;
+        if self.stack_vma().is_some() || self.find_vma(start).is_some() {
+            return Err(VmError::AlreadyMapped(start));
+        }
+        self.insert_vma(Vma {
+            start,
+            end: self.stack_top,
+            prot: PteFlags::R | PteFlags::W,
+            kind: VmaKind::Private { file: None },
+            huge: false,
+            jit: false,
+            stack: true,
+        });
+        Ok(self.stack_top)
+    }
+
+    pub fn stack_rlimit(&self) -> usize {
+        self.stack_rlimit
+    }
+
+    // Let the stack grow to limit bytes, which may be less than it already has
+    // and at most STACK_RLIMIT_MAX
+    // Fails if the stack and its guard gap could then reach down into the heap
+    // or another region, since below_stack() would take faults there for the stack
+    pub fn set_stack_rlimit(&mut self, limit: usize) -> Result<(), VmError> {
+        let limit = pg_round_up(limit.min(STACK_RLIMIT_MAX));
+        let floor = self.stack_top.saturating_sub(limit + STACK_GUARD);
+        let overlaps = floor < pg_round_up(self.size)
+            || self
+                .vmas
+                .iter()
+                .any(|vma| !vma.stack && vma.end > floor && vma.start < self.stack_top);
+        if overlaps {
+            return Err(VmError::AlreadyMapped(floor));
+        }
+        self.stack_rlimit = limit;
+        Ok(())
+    }
+
+    // Index of the stack region, or of its lowest piece if mprotect() split it
+    fn stack_vma(&self) -> Option<usize> {
+        self.vmas.iter().position(|vma| vma.stack)
+    }
+
+    // Bottom of the area kept for the stack and its guard gap
+    fn stack_floor(&self) -> usize {
+        self.stack_top
+            .saturating_sub(self.stack_rlimit + STACK_GUARD)
+    }
+
+    // Would a fault at va land below the stack, where it grows into or overflows?
+    // Not if va is in another region, which is handled like any other fault
+    pub fn below_stack(&self, va: usize) -> bool {
+        self.find_vma(va).is_none()
+            && self
+                .stack_vma()
+                .is_some_and(|i| self.stack_floor() <= va && va < self.vmas[i].start)
+    }
+
+    // Extend the stack down over va, after a fault below it
+    // Fails with StackOverflow if that would take it past its rlimit or
+    // leave less than STACK_GUARD between it and the memory below
+    pub fn grow_stack(&mut self, va: usize) -> Result<(), VmError> {
+        let i = self.stack_vma().ok_or(VmError::NotMapped(va))?;
+        let start = pg_round_down(va);
+        let below = match i {
+            0 => pg_round_up(self.size),
+            _ => self.vmas[i - 1].end,
+        };
+        if self.stack_top - start > self.stack_rlimit || start < below + STACK_GUARD {
+            return Err(VmError::StackOverflow(va));
+        }
+        self.vmas[i].start = start;
+        Ok(())
+    }
+}
+
+// Swapping
+// When user memory runs out, reclaim() evicts anonymous pages to swap (see swap.rs)
+// Victims are chosen by a clock sweep: the hand moves over each address space's
+// pages in turn, and a page whose accessed bit is set has it cleared and gets
+// a second chance, so only pages untouched for a whole sweep are evicted
+impl UserAddressSpace {
+    // Has the page holding va been swapped out?
+    pub fn is_swapped(&self, va: usize) -> bool {
+        self.pagetable
+            .lookup_entry(va)
+            .is_some_and(|pte| pte.is_swapped())
+    }
+
+    // Read the swapped-out page at va back into a new page and map it as it was
+    // The slot is freed once no other address space shares it after fork;
+    // each sharer gets its own copy, so copy-on-write state needs no tracking
+    fn swap_in(&mut self, va: usize) -> Result<(), VmError> {
+        let pa = self.alloc_page(false)?;
+        // Allocating may have slept, so look at the entry only now
+        let entry = *self.pagetable.walk(va, false)?;
+        if !entry.is_swapped() {
+            kfree(pa);
+            return Ok(());
+        }
+        if let Err(e) = swap_read(entry.slot(), pa) {
+            println!("swap_in {:#x}: {}", va, e);
+            kfree(pa);
+            return Err(VmError::Io);
+        }
+        let flags = (entry.flags() & !PteFlags::SWAP) | PteFlags::V;
+        *self.pagetable.walk(va, false)? = Pte::new(pa, flags);
+        swap_free(entry.slot());
+        Ok(())
+    }
+
+    // The first page at or after va that may be swapped out: in user memory
+    // or a private mmap region, but not a huge one
+    fn next_swappable(&self, va: usize) -> Option<usize> {
+        let va = pg_round_up(va);
+        if va < self.size {
+            return Some(va.max(pg_round_down(self.base)));
+        }
+        self.vmas
+            .iter()
+            .filter(|vma| matches!(vma.kind, VmaKind::Private { .. }) && !vma.huge)
+            .find(|vma| va < vma.end)
+            .map(|vma| va.max(vma.start))
+    }
+
+    // Move the clock hand over up to budget pages, evicting those not accessed
+    // since the last sweep until victims is full. Only pages mapped here alone
+    // are taken; shared ones would need every sharer's PTE changed
+    // Each victim's PTE becomes a swap entry before its contents are written out,
+    // so the caller must flush this address space's TLB entries and then call
+    // swap_write() on each of the slots added to victims
+    fn swap_scan(&mut self, budget: usize, victims: &mut Victims) {
+        for _ in 0..budget {
+            if victims.len == victims.slots.len() {
+                return;
+            }
+            let va = match self.next_swappable(self.clock) {
+                Some(va) => va,
+                None => match self.next_swappable(0) {
+                    Some(va) => va,
+                    None => return,
+                },
+            };
+            self.clock = va + PGSIZE;
+
+            let pte = match self.pagetable.lookup_leaf(va) {
+                Some((_, 0)) => self.pagetable.walk(va, false).expect("swap_scan"),
+                _ => continue,
+            };
+            if !pte.flags().contains(PteFlags::U) || page_refcount(pte.pa()) != 1 {
+                continue;
+            }
+            if pte.flags().contains(PteFlags::A) {
+                pte.set_flags(pte.flags() & !PteFlags::A);
+                continue;
+            }
+            let Some(slot) = swap_alloc(pte.pa()) else {
+                return;
+            };
+            *pte = Pte::swapped(slot, pte.flags());
+            victims.slots[victims.len] = slot;
+            victims.len += 1;
+        }
+    }
+}
+
+// Pages freed per reclaim(), so each call makes a useful amount of room
+const RECLAIM_BATCH: usize = 32;
+// Pages the clock hand moves over in one address space before going on to the next
+const SCAN_BUDGET: usize = 256;
+
+// Slots being written out by reclaim(); a fixed array, as the heap may be out of memory too
+struct Victims {
+    slots: [usize; RECLAIM_BATCH],
+    len: usize,
+}
+
+// Process slot the clock hand is in
+static CLOCK_PROC: AtomicUsize = AtomicUsize::new(0);
+
+// Free some user memory by evicting pages to swap, from own, the caller's
+// address space, and from processes that are not running
+// Returns false if nothing could be evicted
+// May sleep, so must be called from process context with no spinlock held
+fn reclaim(own: &mut UserAddressSpace) -> bool {
+    if !swap_enabled() {
+        return false;
+    }
+    let mut victims = Victims {
+        slots: [0; RECLAIM_BATCH],
+        len: 0,
+    };
+    // Two laps, so pages given a second chance on the first can go on the second
+    for _ in 0..2 * NPROC {
+        let i = CLOCK_PROC.fetch_add(1, Ordering::Relaxed) % NPROC;
+        if is_myproc(i) {
+            own.swap_scan(SCAN_BUDGET, &mut victims);
+            own.flush_range(0, TRAPFRAME);
+        } else {
+            with_idle_aspace(i, |aspace| {
+                if !aspace.reclaiming {
+                    aspace.swap_scan(SCAN_BUDGET, &mut victims);
+                    aspace.defer_flush();
+                }
+            });
+        }
+        if victims.len == RECLAIM_BATCH {
+            break;
+        }
+    }
+    for &slot in &victims.slots[..victims.len] {
+        swap_write(slot);
+    }
+    victims.len > 0
+}
+
+// Address-space inspection
+impl UserAddressSpace {
+    // Print user memory, the mmap regions and then the page table on the console
+    pub fn vmprint(&self) {
+        println!(
+            "address space: user memory {:#x}-{:#x}, stack top {:#x}, mmap base {:#x}, asid {}",
+            self.base, self.size, self.stack_top, self.mmap_base, self.asid.id
+        );
+        for vma in self.vmas.iter() {
+            println!("  {}", vma);
+        }
+        self.pagetable.vmprint();
+    }
+}
+
+impl Drop for UserAddressSpace {
+    // Write back shared file mappings, then free user memory pages, then page-table pages
+    fn drop(&mut self) {
+        let _ = self.msync(0, TRAPFRAME);
+        for vma in core::mem::take(&mut self.vmas) {
+            self.unmap_present(vma.start, vma.end);
+        }
+        self.shrink(0);
+        self.pagetable
+            .unmap(TRAMPOLINE, 1, false)
+            .expect("uvm free: trampoline");
+        self.pagetable
+            .unmap(TRAPFRAME, 1, false)
+            .expect("uvm free: trapframe");
+        free_walk(self.pagetable.root);
+    }
+}
+
+// The contents of a whole page of memory at physical address pa
+fn page_bytes<'a>(pa: usize) -> &'a mut [u8] {
+    unsafe { core::slice::from_raw_parts_mut(pa as *mut u8, PGSIZE) }
+}
+
+// File operations a mapping needs, implemented by the file layer
+pub trait MappedFile: Send + Sync {
+    // Fill buf with the file's contents at offset, zero-filling past the end of the file
+    fn read_page(&self, offset: usize, buf: &mut [u8]) -> Result<(), &'static str>;
+    // Write buf to the file at offset
+    fn write_page(&self, offset: usize, buf: &[u8]) -> Result<(), &'static str>;
+}
+
+// Pages seen by every process that maps them, such as MAP_SHARED memory
+// Each page is filled on first use, from the file if there is one, otherwise with zeros
+// The object holds one reference to each page and every mapping holds another,
+// so the pages are freed once the object is dropped and the last mapping is gone
+pub struct SharedObject {
+    pages: SleepLock<BTreeMap<usize, usize>>, // Page index to physical address
+    file: Option<(Arc<dyn MappedFile>, usize)>, // Backing file and offset of page 0
+}
+
+impl SharedObject {
+    pub fn new(file: Option<(Arc<dyn MappedFile>, usize)>) -> Self {
+        SharedObject {
+            pages: SleepLock::new(BTreeMap::new(), "shared object"),
+            file,
+        }
+    }
+
+    // Physical address of page index, with a new reference taken for the caller's mapping
+    pub fn page(&self, index: usize) -> Result<usize, VmError> {
+        let mut pages = self.pages.lock();
+        if let Some(&pa) = pages.get(&index) {
+            page_ref(pa);
+            return Ok(pa);
+        }
+        let pa = kzalloc(PagePurpose::User).ok_or(VmError::OutOfMemory)?;
+        if let Some((file, offset)) = &self.file {
+            if file
+                .read_page(offset + index * PGSIZE, page_bytes(pa))
+                .is_err()
+            {
+                kfree(pa);
+                return Err(VmError::Io);
+            }
+        }
+        pages.insert(index, pa);
+        page_ref(pa);
+        Ok(pa)
+    }
+
+    // Write pages first..last that have been faulted in back to the file
+    pub fn writeback(&self, first: usize, last: usize) -> Result<(), VmError> {
+        let Some((file, offset)) = &self.file else {
+            return Ok(());
+        };
+        let pages = self.pages.lock();
+        for (&index, &pa) in pages.range(first..last) {
+            file.write_page(offset + index * PGSIZE, page_bytes(pa))
+                .map_err(|_| VmError::Io)?;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for SharedObject {
+    fn drop(&mut self) {
+        for &pa in self.pages.lock().values() {
+            kfree(pa);
+        }
+    }
+}
+
+// Where the pages of an mmap region come from
+#[derive(Clone)]
+pub enum VmaKind {
+    // Private to this address space, zero-filled or read from file at the given
+    // offset on first touch, and copy-on-write after fork
+    Private {
+        file: Option<(Arc<dyn MappedFile>, usize)>,
+    },
+    // Pages of object starting at page pgoff, shared with every other mapping of it
+    Shared {
+        object: Arc<SharedObject>,
+        pgoff: usize,
+    },
+}
+
+// A memory-mapped region [start, end) of a user address space
+#[derive(Clone)]
+pub struct Vma {
+    pub start: usize,
+    pub end: usize,
+    pub prot: PteFlags, // Some of R, W and X
+    pub kind: VmaKind,
+    pub huge: bool,  // Backed by 2 MiB superpages, allocated up front
+    pub jit: bool,   // Opted out of W^X, so prot may include both W and X
+    pub stack: bool, // The user stack, which grows down on faults below it
+}
+
+// As in "0x3fffe00000-0x3fffe02000 rw----- anon private"
+impl fmt::Display for Vma {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:#x}-{:#x} {} ", self.start, self.end, self.prot)?;
+        match &self.kind {
+            VmaKind::Private { file: None } => write!(f, "anon private")?,
+            VmaKind::Private {
+                file: Some((_, offset)),
+            } => write!(f, "file private offset {:#x}", offset)?,
+            VmaKind::Shared { object, pgoff } => write!(
+                f,
+                "shared{} object {:p} page {}",
+                if object.file.is_some() { " file" } else { "" },
+                Arc::as_ptr(object),
+                pgoff
+            )?,
+        }
+        if self.huge {
+            write!(f, " huge")?;
+        }
+        if self.jit {
+            write!(f, " jit")?;
+        }
+        if self.stack {
+            write!(f, " stack")?;
+        }
+        Ok(())
+    }
+}
+
+impl Vma {
+    // The part of this region covering [start, end), keeping file offsets lined up
+    fn slice(&self, start: usize, end: usize) -> Vma {
+        let skip = start - self.start;
+        let kind = match &self.kind {
+            VmaKind::Private { file } => VmaKind::Private {
+                file: file
+                    .as_ref()
+                    .map(|(file, offset)| (file.clone(), offset + skip)),
+            },
+            VmaKind::Shared { object, pgoff } => VmaKind::Shared {
+                object: object.clone(),
+                pgoff: pgoff + skip / PGSIZE,
+            },
+        };
+        Vma {
+            start,
+            end,
+            prot: self.prot,
+            kind,
+            huge: self.huge,
+            jit: self.jit,
+            stack: self.stack,
+        }
+    }
+}
+
+// The kernel never dereferences a user virtual address directly. Every access
+// is translated through the process's page table and checked for PteFlags::U
+// and the needed permission first, so a bad pointer from user space becomes
+// an error (EFAULT to the caller) instead of a kernel page fault
+
+// Physical address of the start of the user page holding va,
+// checking it is mapped for user access with the permissions in need
+fn user_page(pagetable: &PageTable, va: usize, need: PteFlags) -> Result<usize, VmError> {
+    if va >= MAXVA {
+        return Err(VmError::BadAddress(va));
+    }
+    let pte = match pagetable.lookup(va) {
+        Some(pte) if pte.is_valid() => pte,
+        _ => return Err(VmError::NotMapped(va)),
+    };
+    if !pte.flags().contains(PteFlags::U | need) {
+        return Err(VmError::Protection(va));
+    }
+    Ok(pte.pa())
+}
+
+// Copy len bytes between kernel memory and the user page behind va, whose
+// physical address is pa, through the kernel's direct map of that page
+fn copy_chunk(kernel: *mut u8, va: usize, pa: usize, len: usize, to_user: bool) {
+    let user = (pa + (va % PGSIZE)) as *mut u8;
+    unsafe {
+        if to_user {
+            copy_nonoverlapping(kernel as *const u8, user, len);
+        } else {
+            copy_nonoverlapping(user as *const u8, kernel, len);
+        }
+    }
+}
+
+// Copy from kernel to user
+// Copy len bytes from src to virtual address dstva in the given address space
+pub fn copyout(aspace: &mut UserAddressSpace, dstva: usize, src: &[u8]) -> Result<(), VmError> {
+    let mut done = 0;
+    while done < src.len() {
+        let va = dstva + done;
+        if aspace.below_stack(va) {
+            aspace.grow_stack(va)?;
+        }
+        // A fault may leave the page swapped out again, if reclaiming
+        // memory for it evicted it; then go round and fault it back in
+        let pa = loop {
+            if aspace.is_lazy(va) {
+                aspace.lazy_fault(va, PteFlags::W)?;
+            } else if aspace.is_cow(va) {
+                aspace.cow_fault(va)?;
+            } else {
+                break user_page(&aspace.pagetable, va, PteFlags::W)?;
+            }
+        };
+        let n = (PGSIZE - va % PGSIZE).min(src.len() - done);
+        copy_chunk(src[done..].as_ptr() as *mut u8, va, pa, n, true);
+        done += n;
+    }
+    Ok(())
+}
+
+// Physical page behind va for the kernel to read, faulting in mmap pages
+// None means an untouched demand-zero heap page, which reads as zeros
+fn readable_page(aspace: &mut UserAddressSpace, va: usize) -> Result<Option<usize>, VmError> {
+    if aspace.below_stack(va) {
+        aspace.grow_stack(va)?;
+    }
+    if aspace.is_lazy(va) {
+        if aspace.find_vma(va).is_none() && !aspace.is_swapped(va) {
+            return Ok(None);
+        }
+        aspace.lazy_fault(va, PteFlags::R)?;
+    }
+    user_page(&aspace.pagetable, va, PteFlags::R).map(Some)
+}
+
+// Copy from user to kernel
+// Copy dst.len() bytes to dst from virtual address srcva in the given address space
+pub fn copyin(aspace: &mut UserAddressSpace, dst: &mut [u8], srcva: usize) -> Result<(), VmError> {
+    let mut done = 0;
+    while done < dst.len() {
+        let va = srcva + done;
+        let n = (PGSIZE - va % PGSIZE).min(dst.len() - done);
+        match readable_page(aspace, va)? {
+            Some(pa) => copy_chunk(dst[done..].as_mut_ptr(), va, pa, n, false),
+            // No need to allocate a demand-zero page just to read zeros
+            None => dst[done..done + n].fill(0),
+        }
+        done += n;
+    }
+    Ok(())
+}
+
+// Copy a null-terminated string from user to kernel
+// Copy bytes to dst from virtual address srcva until a '\0' or dst is full
+// Returns the length of the string, not counting the '\0'
+pub fn copyinstr(
+    aspace: &mut UserAddressSpace,
+    dst: &mut [u8],
+    srcva: usize,
+) -> Result<usize, VmError> {
+    for (i, byte) in dst.iter_mut().enumerate() {
+        let va = srcva + i;
+        *byte = match readable_page(aspace, va)? {
+            Some(pa) => unsafe { *((pa + va % PGSIZE) as *const u8) },
+            None => 0,
+        };
+        if *byte == 0 {
+            return Ok(i);
+        }
+    }
+    Err(VmError::BadAddress(srcva))
+}
+
+// Plain data that can be copied to and from user memory
+// Safety: every bit pattern must be a valid value, and there must be no padding,
+// so that read() never makes an invalid T and write() never leaks kernel bytes
+#[allow(clippy::missing_safety_doc)]
+pub unsafe trait Pod: Copy {}
+
+unsafe impl Pod for u8 {}
+unsafe impl Pod for u16 {}
+unsafe impl Pod for u32 {}
+unsafe impl Pod for u64 {}
+unsafe impl Pod for usize {}
+unsafe impl Pod for i8 {}
+unsafe impl Pod for i16 {}
+unsafe impl Pod for i32 {}
+unsafe impl Pod for i64 {}
+unsafe impl Pod for isize {}
+unsafe impl<T: Pod, const N: usize> Pod for [T; N] {}
+
+// Address of a T in user memory
+pub struct UserPtr<T> {
+    addr: usize,
+    _marker: PhantomData<*mut T>,
+}
+
+impl<T> Clone for UserPtr<T> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<T> Copy for UserPtr<T> {}
+
+impl<T: Pod> UserPtr<T> {
+    pub fn new(addr: usize) -> Self {
+        UserPtr {
+            addr,
+            _marker: PhantomData,
+        }
+    }
+
+    pub fn addr(self) -> usize {
+        self.addr
+    }
+
+    pub fn is_null(self) -> bool {
+        self.addr == 0
+    }
+
+    pub fn read(self, aspace: &mut UserAddressSpace) -> Result<T, VmError> {
+        let mut val = MaybeUninit::<T>::uninit();
+        let bytes =
+            unsafe { core::slice::from_raw_parts_mut(val.as_mut_ptr() as *mut u8, size_of::<T>()) };
+        copyin(aspace, bytes, self.addr)?;
+        Ok(unsafe { val.assume_init() })
+    }
+
+    pub fn write(self, aspace: &mut UserAddressSpace, val: &T) -> Result<(), VmError> {
+        let bytes =
+            unsafe { core::slice::from_raw_parts(val as *const T as *const u8, size_of::<T>()) };
+        copyout(aspace, self.addr, bytes)
+    }
+}
+
+// Buffer of len bytes in user memory
+#[derive(Copy, Clone)]
+pub struct UserSlice {
+    addr: usize,
+    len: usize,
+}
+
+impl UserSlice {
+    // Fails if the range wraps around or reaches past MAXVA
+    pub fn new(addr: usize, len: usize) -> Result<Self, VmError> {
+        match addr.checked_add(len) {
+            Some(end) if end <= MAXVA => Ok(UserSlice { addr, len }),
+            _ => Err(VmError::BadAddress(addr)),
+        }
+    }
+
+    pub fn addr(self) -> usize {
+        self.addr
+    }
+
+    pub fn len(self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(self) -> bool {
+        self.len == 0
+    }
+
+    // Copy the start of the buffer into dst, returning the number of bytes copied
+    pub fn read(self, aspace: &mut UserAddressSpace, dst: &mut [u8]) -> Result<usize, VmError> {
+        let n = dst.len().min(self.len);
+        copyin(aspace, &mut dst[..n], self.addr)?;
+        Ok(n)
+    }
+
+    // Copy src into the start of the buffer, returning the number of bytes copied
+    pub fn write(self, aspace: &mut UserAddressSpace, src: &[u8]) -> Result<usize, VmError> {
+        let n = src.len().min(self.len);
+        copyout(aspace, self.addr, &src[..n])?;
+        Ok(n)
+    }
+}
+
+// Null-terminated string in user memory
+#[derive(Copy, Clone)]
+pub struct UserCStr {
+    addr: usize,
+}
+
+impl UserCStr {
+    pub fn new(addr: usize) -> Self {
+        UserCStr { addr }
+    }
+
+    // Copy the string into buf, returning it without the '\0'
+    // Fails if it does not fit or is not valid UTF-8
+    pub fn read<'a>(
+        self,
+        aspace: &mut UserAddressSpace,
+        buf: &'a mut [u8],
+    ) -> Result<&'a str, VmError> {
+        let len = copyinstr(aspace, buf, self.addr)?;
+        core::str::from_utf8(&buf[..len]).map_err(|_| VmError::BadAddress(self.addr))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::file::MemFile;
+    use crate::proc::{spawn, wait_pid};
+    use crate::programs::program;
+
+    // A two-page file whose second page starts with "page 1" and whose end is
+    // halfway into a third
+    fn test_file() -> Arc<MemFile> {
+        let file = MemFile::new(2 * PGSIZE + PGSIZE / 2).unwrap();
+        file.write_at(PGSIZE, b"page 1");
+        Arc::new(file)
+    }
+
+    // Map file from offset as a three-page region of kind shared or private
+    fn map_file(aspace: &mut UserAddressSpace, file: &Arc<MemFile>, shared: bool) -> usize {
+        let file: Option<(Arc<dyn MappedFile>, usize)> = Some((file.clone(), PGSIZE));
+        let kind = if shared {
+            VmaKind::Shared {
+                object: Arc::new(SharedObject::new(file)),
+                pgoff: 0,
+            }
+        } else {
+            VmaKind::Private { file }
+        };
+        let rw = PteFlags::R | PteFlags::W;
+        aspace.mmap(None, 3 * PGSIZE, rw, kind, false).unwrap()
+    }
+
+    // Private file pages are read in on first touch, zero past the end of
+    // the file, and writes to them never reach it
+    #[test_case]
+    fn file_private() {
+        let tf = kalloc(PagePurpose::Other).unwrap();
+        let mut aspace = UserAddressSpace::new(tf).unwrap();
+        let file = test_file();
+        let va = map_file(&mut aspace, &file, false);
+
+        let mut buf = [0u8; 6];
+        copyin(&mut aspace, &mut buf, va).unwrap();
+        assert_eq!(&buf, b"page 1");
+        copyin(&mut aspace, &mut buf, va + 2 * PGSIZE - 3).unwrap();
+        assert_eq!(buf, [0; 6]);
+
+        copyout(&mut aspace, va, b"acorn").unwrap();
+        aspace.msync(va, 3 * PGSIZE).unwrap();
+        aspace.munmap(va, 3 * PGSIZE).unwrap();
+        file.read_at(PGSIZE, &mut buf);
+        assert_eq!(&buf, b"page 1");
+        drop(aspace);
+        kfree(tf);
+    }
+
+    // Shared file pages are read in on first touch and written back by msync,
+    // which refuses ranges that are misaligned or run past user memory
+    #[test_case]
+    fn file_msync() {
+        let tf = kalloc(PagePurpose::Other).unwrap();
+        let mut aspace = UserAddressSpace::new(tf).unwrap();
+        let file = test_file();
+        let va = map_file(&mut aspace, &file, true);
+
+        let mut buf = [0u8; 6];
+        copyin(&mut aspace, &mut buf, va).unwrap();
+        assert_eq!(&buf, b"page 1");
+        copyout(&mut aspace, va, b"acorn!").unwrap();
+        copyout(&mut aspace, va + PGSIZE + PGSIZE / 2, b"EOF").unwrap();
+        file.read_at(PGSIZE, &mut buf);
+        assert_eq!(&buf, b"page 1");
+
+        assert!(aspace.msync(va + 1, PGSIZE).is_err());
+        assert!(aspace.msync(va, usize::MAX).is_err());
+        assert!(aspace.msync(va, TRAPFRAME).is_err());
+        aspace.msync(va, PGSIZE).unwrap();
+        file.read_at(PGSIZE, &mut buf);
+        assert_eq!(&buf, b"acorn!");
+
+        // Bytes past the end of the file are dropped, not appended
+        aspace.msync(va, 3 * PGSIZE).unwrap();
+        assert_eq!(file.read_at(2 * PGSIZE + PGSIZE / 2, &mut buf), 0);
+        drop(aspace);
+        kfree(tf);
+    }
+
+    // Shared file pages are written back when they are unmapped, and when
+    // the address space goes away at exit
+    #[test_case]
+    fn file_writeback() {
+        let tf = kalloc(PagePurpose::Other).unwrap();
+        let mut aspace = UserAddressSpace::new(tf).unwrap();
+        let file = test_file();
+        let mut buf = [0u8; 6];
+
+        let va = map_file(&mut aspace, &file, true);
+        copyout(&mut aspace, va, b"munmap").unwrap();
+        aspace.munmap(va, 3 * PGSIZE).unwrap();
+        file.read_at(PGSIZE, &mut buf);
+        assert_eq!(&buf, b"munmap");
+
+        let va = map_file(&mut aspace, &file, true);
+        copyout(&mut aspace, va + PGSIZE, b"exited").unwrap();
+        drop(aspace);
+        file.read_at(2 * PGSIZE, &mut buf);
+        assert_eq!(&buf, b"exited");
+        kfree(tf);
+    }
+
+    // A touched page made PROT_NONE keeps its contents but refuses every
+    // access, in a fork as well, until its permissions are given back
+    #[test_case]
+    fn prot_none() {
+        let tf = kalloc(PagePurpose::Other).unwrap();
+        let child_tf = kalloc(PagePurpose::Other).unwrap();
+        let mut aspace = UserAddressSpace::new(tf).unwrap();
+        let rw = PteFlags::R | PteFlags::W;
+        let va = aspace
+            .mmap(None, 2 * PGSIZE, rw, VmaKind::Private { file: None }, false)
+            .unwrap();
+        copyout(&mut aspace, va, b"acorn").unwrap();
+
+        aspace.mprotect(va, 2 * PGSIZE, PteFlags::empty()).unwrap();
+        let mut buf = [0u8; 5];
+        assert!(copyin(&mut aspace, &mut buf, va).is_err());
+        assert!(copyin(&mut aspace, &mut buf, va + PGSIZE).is_err());
+
+        let mut child = aspace.copy(child_tf).unwrap();
+        for aspace in [&mut aspace, &mut child] {
+            aspace.mprotect(va, PGSIZE, PteFlags::R).unwrap();
+            copyin(aspace, &mut buf, va).unwrap();
+            assert_eq!(&buf, b"acorn");
+        }
+        drop(child);
+        drop(aspace);
+        kfree(child_tf);
+        kfree(tf);
+    }
+
+    // Once a region sits below the stack, the stack's rlimit cannot be
+    // raised to reach it
+    #[test_case]
+    fn stack_floor() {
+        let tf = kalloc(PagePurpose::Other).unwrap();
+        let mut aspace = UserAddressSpace::new(tf).unwrap();
+        let top = aspace.setup_stack().unwrap();
+        aspace.set_stack_rlimit(16 * PGSIZE).unwrap();
+        let rw = PteFlags::R | PteFlags::W;
+        let va = aspace
+            .mmap(
+                Some(top - STACK_RLIMIT),
+                PGSIZE,
+                rw,
+                VmaKind::Private { file: None },
+                false,
+            )
+            .unwrap();
+
+        assert!(aspace.set_stack_rlimit(STACK_RLIMIT).is_err());
+        assert_eq!(aspace.stack_rlimit(), 16 * PGSIZE);
+        assert!(!aspace.below_stack(va));
+        assert!(aspace.below_stack(top - 2 * PGSIZE));
+        drop(aspace);
+        kfree(tf);
+    }
+
+    // Benchmark: more processes than harts, each touching its pages and then
+    // yielding, so harts keep switching between address spaces. Without ASIDs
+    // every switch flushes the whole TLB, and each process misses on all its
+    // pages again; with them, entries survive until the process runs again
+    // QEMU drops its own TLB on every satp write anyway, and has no miss
+    // counters, so there only the flush counts are compared. Where the
+    // counters count, ASIDs must save misses as well
+    #[test_case]
+    fn asid_benchmark() {
+        const SWITCHERS: usize = 2 * NCPU;
+        let run = |bits| {
+            let old = set_asid_bits(bits);
+            let before = tlb_stats();
+            let pids: Vec<usize> = (0..SWITCHERS)
+                .map(|_| spawn("switch", program("switch").unwrap()).unwrap())
+                .collect();
+            for pid in pids {
+                assert_eq!(wait_pid(pid), Ok(0));
+            }
+            set_asid_bits(old);
+            tlb_stats().since(before)
+        };
+
+        let bits = ASIDS.lock().bits;
+        let with = run(bits);
+        let without = run(0);
+        println!();
+        println!("  {} ASID bits: {:?}", bits, with);
+        println!("  no ASIDs:    {:?}", without);
+        println!(
+            "  user TLB misses: {} with ASIDs, {} without",
+            with.user_misses, without.user_misses
+        );
+        if bits > 0 {
+            assert!(with.full_flushes < without.full_flushes);
+            if without.user_misses > 0 {
+                assert!(with.user_misses < without.user_misses);
+            }
+        }
+    }
+}
@@ -0,0 +1,255 @@
+use crate::proc::{myproc, sleep, sleep_until, wakeup, wakeup_one};
+use crate::spinlock::SpinlockGuard;
+use crate::trap::ticks;
+
+// Why a wait ended without its condition becoming true
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum WaitError {
+    Interrupted, // Process was killed while waiting
+    TimedOut,    // Deadline passed
+}
+
+// Queue of processes waiting for some condition
+// Every wait takes the guard of the spinlock protecting the condition, and the
+// condition is only ever tested with that lock held. sleep() releases the lock
+// only after the waiter is marked Sleeping, so a waker that changes the state
+// under the same lock and then calls wake_*() can never slip in between the
+// test and the sleep
+pub struct WaitQueue {
+    _private: u8, // Non-zero size so every queue has a distinct address
+}
+
+impl WaitQueue {
+    pub const fn new() -> Self {
+        WaitQueue { _private: 0 }
+    }
+
+    // Sleepers wait on the queue's own address
+    fn chan(&self) -> usize {
+        self as *const Self as usize
+    }
+
+    // Sleep until cond returns true, checking it with the lock held
+    // Fails if the process is killed first; the lock is released on failure
+    pub fn wait_event<'a, T, F>(
+        &self,
+        guard: SpinlockGuard<'a, T>,
+        cond: F,
+    ) -> Result<SpinlockGuard<'a, T>, WaitError>
+    where
+        F: FnMut(&mut T) -> bool,
+    {
+        self.wait(guard, None, cond)
+    }
+
+    // As wait_event(), but gives up after timeout clock ticks
+    pub fn wait_event_timeout<'a, T, F>(
+        &self,
+        guard: SpinlockGuard<'a, T>,
+        timeout: usize,
+        cond: F,
+    ) -> Result<SpinlockGuard<'a, T>, WaitError>
+    where
+        F: FnMut(&mut T) -> bool,
+    {
+        self.wait(guard, Some(ticks() + timeout), cond)
+    }
+
+    fn wait<'a, T, F>(
+        &self,
+        mut guard: SpinlockGuard<'a, T>,
+        deadline: Option<usize>,
+        mut cond: F,
+    ) -> Result<SpinlockGuard<'a, T>, WaitError>
+    where
+        F: FnMut(&mut T) -> bool,
+    {
+        let p = myproc().expect("wait_event: no process");
+        while !cond(&mut guard) {
+            if p.is_killed() {
+                return Err(WaitError::Interrupted);
+            }
+            if deadline.is_some_and(|d| ticks() >= d) {
+                return Err(WaitError::TimedOut);
+            }
+            guard = sleep_until(self.chan(), guard, deadline);
+        }
+        Ok(guard)
+    }
+
+    // Wake a single waiter, returning false if the queue was empty
+    pub fn wake_one(&self) -> bool {
+        wakeup_one(self.chan())
+    }
+
+    // Wake every waiter
+    pub fn wake_all(&self) {
+        wakeup(self.chan());
+    }
+}
+
+impl Default for WaitQueue {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// Condition variable paired with a spinlock-protected value
+// wait() may return spuriously; callers loop on their own predicate
+pub struct Condvar {
+    queue: WaitQueue,
+}
+
+impl Condvar {
+    pub const fn new() -> Self {
+        Condvar {
+            queue: WaitQueue::new(),
+        }
+    }
+
+    // Atomically release the lock and sleep until notified
+    pub fn wait<'a, T>(&self, guard: SpinlockGuard<'a, T>) -> SpinlockGuard<'a, T> {
+        sleep(self.queue.chan(), guard)
+    }
+
+    pub fn notify_one(&self) {
+        self.queue.wake_one();
+    }
+
+    pub fn notify_all(&self) {
+        self.queue.wake_all();
+    }
+}
+
+impl Default for Condvar {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::proc::{kill, kthread, kthread_wait, yield_};
+    use crate::spinlock::Spinlock;
+    use core::sync::atomic::{AtomicUsize, Ordering};
+
+    struct Tokens {
+        count: usize,  // Tokens left for waiters to take
+        sleeps: usize, // Times a waiter found none and went to sleep
+    }
+
+    static TOKENS: Spinlock<Tokens> = Spinlock::new(
+        Tokens {
+            count: 0,
+            sleeps: 0,
+        },
+        "tokens",
+    );
+    static QUEUE: WaitQueue = WaitQueue::new();
+    static TAKEN: AtomicUsize = AtomicUsize::new(0);
+    static INTERRUPTED: AtomicUsize = AtomicUsize::new(0);
+
+    // Wait for a token and take it
+    fn take(_: usize) {
+        let found = |t: &mut Tokens| {
+            if t.count == 0 {
+                t.sleeps += 1;
+            }
+            t.count > 0
+        };
+        match QUEUE.wait_event(TOKENS.lock(), found) {
+            Ok(mut tokens) => {
+                tokens.count -= 1;
+                TAKEN.fetch_add(1, Ordering::Relaxed);
+            }
+            Err(e) => {
+                assert_eq!(e, WaitError::Interrupted);
+                INTERRUPTED.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+
+    // Once n sleeps are counted, sleep() has released the lock they were
+    // counted under, so the waiters are asleep
+    fn wait_for_sleeps(n: usize) {
+        while TOKENS.lock().sleeps < n {
+            yield_();
+        }
+    }
+
+    // Give the hart up until f() holds
+    fn yield_until(f: impl Fn() -> bool) {
+        while !f() {
+            yield_();
+        }
+    }
+
+    // Sleep for n clock ticks, on a queue no one wakes
+    fn pause(n: usize) {
+        let lock = Spinlock::new((), "pause");
+        let queue = WaitQueue::new();
+        assert!(matches!(
+            queue.wait_event_timeout(lock.lock(), n, |_| false),
+            Err(WaitError::TimedOut)
+        ));
+    }
+
+    // wake_one() wakes a single waiter and wake_all() the rest; either way a
+    // waiter only returns once its condition holds
+    #[test_case]
+    fn wake_one_vs_all() {
+        TAKEN.store(0, Ordering::Relaxed);
+        TOKENS.lock().sleeps = 0;
+        let pids: [usize; 3] = core::array::from_fn(|_| kthread("take", take, 0).unwrap());
+        wait_for_sleeps(3);
+
+        // Woken with nothing to take, they all go back to sleep
+        QUEUE.wake_all();
+        wait_for_sleeps(6);
+        assert_eq!(TAKEN.load(Ordering::Relaxed), 0);
+
+        TOKENS.lock().count = 3;
+        assert!(QUEUE.wake_one());
+        yield_until(|| TAKEN.load(Ordering::Relaxed) == 1);
+        pause(2);
+        assert_eq!(TAKEN.load(Ordering::Relaxed), 1);
+
+        QUEUE.wake_all();
+        for pid in pids {
+            kthread_wait(pid);
+        }
+        assert_eq!(TAKEN.load(Ordering::Relaxed), 3);
+        assert!(!QUEUE.wake_one());
+    }
+
+    // A wait with a timeout gives up once that many ticks have passed, and
+    // returns at once if its condition already holds
+    #[test_case]
+    fn timeout() {
+        let lock = Spinlock::new(false, "timeout");
+        let queue = WaitQueue::new();
+        let start = ticks();
+        let result = queue.wait_event_timeout(lock.lock(), 3, |ready| *ready);
+        assert!(matches!(result, Err(WaitError::TimedOut)));
+        assert!(ticks() >= start + 3);
+
+        *lock.lock() = true;
+        assert!(queue
+            .wait_event_timeout(lock.lock(), 3, |ready| *ready)
+            .is_ok());
+    }
+
+    // Killing a sleeping waiter wakes it, and its wait fails with Interrupted
+    #[test_case]
+    fn interrupted_by_kill() {
+        INTERRUPTED.store(0, Ordering::Relaxed);
+        TOKENS.lock().sleeps = 0;
+        let pid = kthread("take", take, 0).unwrap();
+        wait_for_sleeps(1);
+        kill(pid).unwrap();
+        kthread_wait(pid);
+        assert_eq!(INTERRUPTED.load(Ordering::Relaxed), 1);
+        assert_eq!(TOKENS.lock().count, 0);
+    }
+}